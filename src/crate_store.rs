@@ -0,0 +1,420 @@
+use std::{
+    env, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use thiserror::Error;
+
+use crate::crates::{find_crate_file, get_crate_path, link_crate_dir};
+use crate::download::{append_to_path, long_path};
+use crate::mirror::ConfigCrates;
+use crate::s3::{S3Bucket, S3Credentials, S3Error};
+
+#[derive(Error, Debug)]
+pub enum CrateStoreError {
+    #[error("Unknown crate storage \"{0}\", expected \"filesystem\" or \"s3\"")]
+    UnknownStorage(String),
+    #[error("{0} must be set for S3 storage")]
+    MissingConfig(&'static str),
+    #[error("{0} is only supported with filesystem storage")]
+    Unsupported(&'static str),
+    #[error("S3 error: {0}")]
+    S3(#[from] S3Error),
+}
+
+/// A crate file found in a `CrateStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoredCrate {
+    /// The crate is a file on the local disk.
+    File(PathBuf),
+    /// The crate can be downloaded from a URL, which clients should be redirected to.
+    Redirect(String),
+    /// The crate can be downloaded from a URL, to be streamed back to clients.
+    Remote { url: String, len: u64 },
+}
+
+/// Storage for the crate files of a mirror.
+///
+/// Sync downloads each crate to `download_path` and then hands it to `store`,
+/// and serve looks crates up with `locate`. The index always stays on local disk.
+pub trait CrateStore: Send + Sync {
+    /// Local path a crate is downloaded to, or `None` if the crate name is invalid.
+    fn download_path(&self, name: &str, version: &str) -> Option<PathBuf>;
+
+    /// Store a crate that was downloaded to `file_path`.
+    fn store<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+        file_path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), io::Error>>;
+
+    /// Find a stored crate, or `None` if it isn't in the store.
+    fn locate<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<Option<StoredCrate>, io::Error>>;
+}
+
+/// The default store, keeping crates under the mirror's `crates` directory.
+#[derive(Debug, Clone)]
+pub struct FsCrateStore {
+    mirror_path: PathBuf,
+}
+
+impl FsCrateStore {
+    pub fn new(mirror_path: &Path) -> Self {
+        Self {
+            mirror_path: mirror_path.to_path_buf(),
+        }
+    }
+}
+
+impl CrateStore for FsCrateStore {
+    fn download_path(&self, name: &str, version: &str) -> Option<PathBuf> {
+        get_crate_path(&self.mirror_path, name, version).map(|p| long_path(&p))
+    }
+
+    fn store<'a>(
+        &'a self,
+        name: &'a str,
+        _version: &'a str,
+        _file_path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), io::Error>> {
+        // Crates are downloaded straight into place, and only need a link if mixed-case.
+        Box::pin(async move { link_crate_dir(&self.mirror_path, name) })
+    }
+
+    fn locate<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<Option<StoredCrate>, io::Error>> {
+        // This also finds crates whatever the casing of `name`.
+        Box::pin(async move {
+            Ok(find_crate_file(&self.mirror_path, name, version).map(StoredCrate::File))
+        })
+    }
+}
+
+/// A store uploading crates to an S3-compatible bucket.
+///
+/// Crates are downloaded to the mirror's `crates` directory first, and removed
+/// from it once uploaded. Objects use the same layout, under `crates/`.
+#[derive(Debug)]
+pub struct S3CrateStore {
+    mirror_path: PathBuf,
+    bucket: S3Bucket,
+    prefix: String,
+    redirect: bool,
+    presign_expiry: Duration,
+}
+
+impl S3CrateStore {
+    fn from_config(mirror_path: &Path, crates: &ConfigCrates) -> Result<Self, CrateStoreError> {
+        let endpoint = crates
+            .s3_endpoint
+            .as_deref()
+            .ok_or(CrateStoreError::MissingConfig("s3_endpoint"))?;
+        let bucket = crates
+            .s3_bucket
+            .as_deref()
+            .ok_or(CrateStoreError::MissingConfig("s3_bucket"))?;
+
+        // Credentials fall back to the usual AWS environment variables.
+        let access_key_id = crates
+            .s3_access_key_id
+            .clone()
+            .or_else(|| env::var("AWS_ACCESS_KEY_ID").ok())
+            .ok_or(CrateStoreError::MissingConfig("s3_access_key_id"))?;
+        let secret_access_key = crates
+            .s3_secret_access_key
+            .clone()
+            .or_else(|| env::var("AWS_SECRET_ACCESS_KEY").ok())
+            .ok_or(CrateStoreError::MissingConfig("s3_secret_access_key"))?;
+        let credentials = S3Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        };
+
+        Ok(Self {
+            mirror_path: mirror_path.to_path_buf(),
+            bucket: S3Bucket::new(
+                endpoint,
+                bucket,
+                crates.s3_region.as_deref().unwrap_or("us-east-1"),
+                credentials,
+            )?,
+            prefix: crates
+                .s3_prefix
+                .as_deref()
+                .map(|p| format!("{}/", p.trim_matches('/')))
+                .filter(|p| p != "/")
+                .unwrap_or_default(),
+            redirect: crates.s3_redirect.unwrap_or(false),
+            presign_expiry: Duration::from_secs(crates.s3_presign_expiry.unwrap_or(300)),
+        })
+    }
+
+    /// Key of a crate's object in the bucket.
+    fn key(&self, name: &str, version: &str) -> Option<String> {
+        let path = get_crate_path(Path::new(""), name, version)?;
+        let parts = path
+            .components()
+            .map(|c| match c {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(format!("{}{}", self.prefix, parts.join("/")))
+    }
+}
+
+impl CrateStore for S3CrateStore {
+    fn download_path(&self, name: &str, version: &str) -> Option<PathBuf> {
+        get_crate_path(&self.mirror_path, name, version).map(|p| long_path(&p))
+    }
+
+    fn store<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+        file_path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), io::Error>> {
+        Box::pin(async move {
+            let key = self
+                .key(name, version)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad crate name"))?;
+
+            // Upload the .sha256 file along with its crate, if there is one.
+            let sha256_path = append_to_path(file_path, ".sha256");
+            if let Ok(sha256) = tokio::fs::read(&sha256_path).await {
+                self.bucket
+                    .put(&format!("{key}.sha256"), sha256)
+                    .await
+                    .map_err(io::Error::other)?;
+                tokio::fs::remove_file(&sha256_path).await?;
+            }
+
+            let body = tokio::fs::read(file_path).await?;
+            self.bucket
+                .put(&key, body)
+                .await
+                .map_err(io::Error::other)?;
+            tokio::fs::remove_file(file_path).await
+        })
+    }
+
+    fn locate<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<Option<StoredCrate>, io::Error>> {
+        Box::pin(async move {
+            let Some(key) = self.key(name, version) else {
+                return Ok(None);
+            };
+            let Some(len) = self.bucket.head(&key).await.map_err(io::Error::other)? else {
+                return Ok(None);
+            };
+
+            let url = self
+                .bucket
+                .presign_get(&key, self.presign_expiry)
+                .to_string();
+            Ok(Some(if self.redirect {
+                StoredCrate::Redirect(url)
+            } else {
+                StoredCrate::Remote { url, len }
+            }))
+        })
+    }
+}
+
+/// Wraps another store, redirecting clients to `base` for crates it has on disk.
+pub struct RedirectCrateStore {
+    inner: Arc<dyn CrateStore>,
+    mirror_path: PathBuf,
+    base: String,
+}
+
+impl RedirectCrateStore {
+    pub fn new(inner: Arc<dyn CrateStore>, mirror_path: &Path, base: &str) -> Self {
+        Self {
+            inner,
+            mirror_path: mirror_path.to_path_buf(),
+            base: base.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// URL of a crate file under `base`, by its path relative to the mirror.
+    fn redirect_url(&self, file_path: &Path) -> Option<String> {
+        let parts = file_path
+            .strip_prefix(&self.mirror_path)
+            .ok()?
+            .components()
+            .map(|c| match c {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(format!("{}/{}", self.base, parts.join("/")))
+    }
+}
+
+impl CrateStore for RedirectCrateStore {
+    fn download_path(&self, name: &str, version: &str) -> Option<PathBuf> {
+        self.inner.download_path(name, version)
+    }
+
+    fn store<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+        file_path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), io::Error>> {
+        self.inner.store(name, version, file_path)
+    }
+
+    fn locate<'a>(
+        &'a self,
+        name: &'a str,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<Option<StoredCrate>, io::Error>> {
+        Box::pin(async move {
+            Ok(match self.inner.locate(name, version).await? {
+                Some(StoredCrate::File(file_path)) => Some(
+                    self.redirect_url(&file_path)
+                        .map_or(StoredCrate::File(file_path), StoredCrate::Redirect),
+                ),
+                other => other,
+            })
+        })
+    }
+}
+
+/// Create the crate store selected by `[crates] storage`.
+pub fn crate_store(
+    mirror_path: &Path,
+    crates: Option<&ConfigCrates>,
+) -> Result<Arc<dyn CrateStore>, CrateStoreError> {
+    let Some(crates) = crates else {
+        return Ok(Arc::new(FsCrateStore::new(mirror_path)));
+    };
+    match crates.storage.as_deref() {
+        None | Some("filesystem") => Ok(Arc::new(FsCrateStore::new(mirror_path))),
+        // Pruning walks the crates directory, so it can't see crates stored in S3.
+        Some("s3") if crates.max_total_bytes.is_some() => {
+            Err(CrateStoreError::Unsupported("max_total_bytes"))
+        }
+        Some("s3") => Ok(Arc::new(S3CrateStore::from_config(mirror_path, crates)?)),
+        Some(other) => Err(CrateStoreError::UnknownStorage(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        crate_store, CrateStore, CrateStoreError, RedirectCrateStore, S3CrateStore, StoredCrate,
+    };
+    use crate::mirror::ConfigCrates;
+
+    #[tokio::test]
+    async fn filesystem_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+
+        let store = crate_store(path, None).unwrap();
+        let file_path = store.download_path("Inflector", "0.11.4").unwrap();
+        assert_eq!(
+            file_path,
+            path.join("crates/in/fl/inflector/0.11.4/Inflector-0.11.4.crate")
+        );
+        assert_eq!(store.locate("Inflector", "0.11.4").await.unwrap(), None);
+
+        std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        std::fs::write(&file_path, []).unwrap();
+        store
+            .store("Inflector", "0.11.4", &file_path)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.locate("inflector", "0.11.4").await.unwrap(),
+            Some(StoredCrate::File(file_path))
+        );
+
+        let redirect = RedirectCrateStore::new(store, path, "https://cdn.example.com/mirror/");
+        assert_eq!(
+            redirect.locate("inflector", "0.11.4").await.unwrap(),
+            Some(StoredCrate::Redirect(
+                "https://cdn.example.com/mirror/crates/in/fl/inflector/0.11.4/Inflector-0.11.4.crate"
+                    .to_string()
+            ))
+        );
+        assert_eq!(redirect.locate("inflector", "0.1.0").await.unwrap(), None);
+    }
+
+    #[test]
+    fn unknown_storage() {
+        let crates: ConfigCrates = toml_edit::easy::from_str(include_str!("mirror.default.toml"))
+            .map(|c: crate::mirror::Config| c.crates.unwrap())
+            .unwrap();
+        assert!(crate_store(std::path::Path::new("."), Some(&crates)).is_ok());
+
+        let crates = ConfigCrates {
+            storage: Some("oci".to_string()),
+            ..crates
+        };
+        assert!(matches!(
+            crate_store(std::path::Path::new("."), Some(&crates)),
+            Err(CrateStoreError::UnknownStorage(_))
+        ));
+
+        let crates = ConfigCrates {
+            storage: Some("s3".to_string()),
+            s3_endpoint: Some("http://minio.internal:9000".to_string()),
+            ..crates
+        };
+        assert!(matches!(
+            crate_store(std::path::Path::new("."), Some(&crates)),
+            Err(CrateStoreError::MissingConfig("s3_bucket"))
+        ));
+
+        let crates = ConfigCrates {
+            max_total_bytes: Some(1 << 30),
+            ..crates
+        };
+        assert!(matches!(
+            crate_store(std::path::Path::new("."), Some(&crates)),
+            Err(CrateStoreError::Unsupported("max_total_bytes"))
+        ));
+    }
+
+    #[test]
+    fn s3_keys() {
+        let crates = ConfigCrates {
+            s3_endpoint: Some("http://minio.internal:9000".to_string()),
+            s3_bucket: Some("mirror".to_string()),
+            s3_access_key_id: Some("access".to_string()),
+            s3_secret_access_key: Some("secret".to_string()),
+            s3_prefix: Some("/panamax/".to_string()),
+            ..toml_edit::easy::from_str::<crate::mirror::Config>(include_str!(
+                "mirror.default.toml"
+            ))
+            .unwrap()
+            .crates
+            .unwrap()
+        };
+        let store = S3CrateStore::from_config(std::path::Path::new("."), &crates).unwrap();
+        assert_eq!(
+            store.key("Inflector", "0.11.4").as_deref(),
+            Some("panamax/crates/in/fl/inflector/0.11.4/Inflector-0.11.4.crate")
+        );
+        assert_eq!(store.key("", "0.1.0"), None);
+    }
+}
@@ -1,20 +1,29 @@
-use crate::crates_index::{fast_forward, IndexSyncError};
-use crate::download::{download, DownloadError};
-use crate::mirror::{ConfigCrates, ConfigMirror};
-use crate::progress_bar::padded_prefix_message;
+use crate::crate_store::{crate_store, CrateStore, CrateStoreError, FsCrateStore};
+use crate::crates_index::{
+    fast_forward, fetch_for_dry_run, IndexSyncError, DRY_RUN_REF, INDEX_LOCK, UPSTREAM_REF,
+};
+use crate::download::{
+    append_to_path, download, long_path, sha256_file, write_file_create_dir, DownloadError,
+    DownloadOptions, DownloadStatus, DryRunSummary,
+};
+use crate::mirror::ConfigCrates;
+use crate::progress_bar::{progress_bar, progress_spinner, DownloadProgress, PhaseEvents};
 use futures::StreamExt;
-use git2::Repository;
+use git2::{ObjectType, Repository, Tree, TreeWalkMode, TreeWalkResult};
 use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use rayon::prelude::*;
 use reqwest::header::HeaderValue;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::read_dir;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{
     fs,
-    io::{self, BufRead, Cursor},
+    io::{self, BufRead, Cursor, Write},
 };
 use thiserror::Error;
 
@@ -34,18 +43,55 @@ pub enum SyncError {
 
     #[error("Index syncing error: {0}")]
     IndexSync(#[from] IndexSyncError),
+
+    #[error("Invalid crate pin \"{0}\"")]
+    BadPin(String),
+
+    #[error("Invalid crate set entry \"{0}\"")]
+    BadCrateSetEntry(String),
+
+    #[error("Invalid minimum version \"{0}\"")]
+    BadMinVersion(String),
+
+    #[error("Invalid popularity file entry \"{0}\"")]
+    BadPopularityEntry(String),
+
+    #[error("Crate storage error: {0}")]
+    Store(#[from] CrateStoreError),
+
+    #[error("Could not start the index parsing threads: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+
+    #[error("Interrupted")]
+    Interrupted,
 }
 /// One entry found in a crates.io-index file.
 /// These files are formatted as lines of JSON.
+///
+/// Fields panamax doesn't use are kept in `extra`, so an entry serializes back to the
+/// same JSON it was read from, whatever fields the index format adds.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CrateEntry {
     name: String,
     vers: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     cksum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     yanked: Option<bool>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl CrateEntry {
+    /// Drop the fields in `extra`. They aren't needed to download a crate, and keeping them
+    /// for every crate version in the index would take a lot of memory.
+    fn without_extra(self) -> Self {
+        Self {
+            extra: serde_json::Map::new(),
+            ..self
+        }
+    }
+
     pub(crate) fn get_name(&self) -> &str {
         self.name.as_str()
     }
@@ -53,113 +99,392 @@ impl CrateEntry {
     pub(crate) fn get_vers(&self) -> &str {
         self.vers.as_str()
     }
+
+    pub(crate) fn get_cksum(&self) -> Option<&str> {
+        self.cksum.as_deref()
+    }
 }
 
-/// Download one single crate file.
-pub async fn sync_one_crate_entry(
-    client: &Client,
-    path: &Path,
-    source: Option<&str>,
-    retries: usize,
-    crate_entry: &CrateEntry,
-    user_agent: &HeaderValue,
-) -> Result<(), DownloadError> {
-    // If source is "https://crates.io/api/v1/crates" (the default, and thus a None here)
-    // download straight from the static.crates.io CDN, to avoid bogging down crates.io itself
+/// A crate version to always download and keep, from `[crates] pinned` or a crate set.
+#[derive(Debug, Clone)]
+pub struct CratePin {
+    name: String,
+    version: Option<PinVersion>,
+}
+
+/// The versions matched by a `CratePin`.
+#[derive(Debug, Clone)]
+enum PinVersion {
+    Glob(glob::Pattern),
+    Req(semver::VersionReq),
+}
+
+impl CratePin {
+    /// Parse a pin in the form `name@version`, where version can be a glob such as `1.*`,
+    /// or just `name` for all versions.
+    fn parse(spec: &str) -> Result<Self, SyncError> {
+        let (name, version) = match spec.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (spec, None),
+        };
+        if name.is_empty() {
+            return Err(SyncError::BadPin(spec.to_string()));
+        }
+        let version = version
+            .map(glob::Pattern::new)
+            .transpose()
+            .map_err(|_| SyncError::BadPin(spec.to_string()))?
+            .map(PinVersion::Glob);
+        Ok(Self {
+            name: name.to_lowercase(),
+            version,
+        })
+    }
+
+    /// Parse a crate set entry: `name@version`, `name@requirement` or `name requirement`,
+    /// where requirement is a semver range such as `>=1.2, <2`, or just `name` for all versions.
+    fn parse_set_entry(spec: &str) -> Result<Self, SyncError> {
+        let (name, version) = match spec.split_once(['@', ' ', '\t']) {
+            Some((name, version)) => (name, Some(version.trim())),
+            None => (spec, None),
+        };
+        if name.is_empty() {
+            return Err(SyncError::BadCrateSetEntry(spec.to_string()));
+        }
+        // A plain version means exactly that version, rather than a caret requirement.
+        let version = version
+            .map(|v| match semver::Version::parse(v) {
+                Ok(v) => semver::VersionReq::parse(&format!("={v}")),
+                Err(_) => semver::VersionReq::parse(v),
+            })
+            .transpose()
+            .map_err(|_| SyncError::BadCrateSetEntry(spec.to_string()))?
+            .map(PinVersion::Req);
+        Ok(Self {
+            name: name.to_lowercase(),
+            version,
+        })
+    }
+
+    fn matches(&self, name: &str, version: &str) -> bool {
+        self.name == name.to_lowercase()
+            && match &self.version {
+                None => true,
+                Some(PinVersion::Glob(pattern)) => pattern.matches(version),
+                Some(PinVersion::Req(req)) => {
+                    semver::Version::parse(version).is_ok_and(|v| req.matches(&v))
+                }
+            }
+    }
+}
+
+/// Parse the `[crates] pinned` crate versions.
+pub fn crate_pins(crates: &ConfigCrates) -> Result<Vec<CratePin>, SyncError> {
+    crates
+        .pinned
+        .iter()
+        .flatten()
+        .map(|spec| CratePin::parse(spec))
+        .collect()
+}
+
+/// Minimum versions of crates to sync, from `[crates] min_version` and `min_versions`.
+#[derive(Debug, Default, Clone)]
+pub struct VersionFloor {
+    global: Option<semver::VersionReq>,
+    per_crate: HashMap<String, semver::VersionReq>,
+}
+
+impl VersionFloor {
+    pub fn from_config(crates: &ConfigCrates) -> Result<Self, SyncError> {
+        Ok(Self {
+            global: crates.min_version.as_deref().map(parse_floor).transpose()?,
+            per_crate: crates
+                .min_versions
+                .iter()
+                .flatten()
+                .map(|(name, floor)| Ok((name.to_lowercase(), parse_floor(floor)?)))
+                .collect::<Result<_, SyncError>>()?,
+        })
+    }
+
+    /// Whether a crate version is below its floor.
+    ///
+    /// Versions that aren't valid semver are always kept. Pre-releases are compared
+    /// as their release, so `1.0.0-beta` is kept with a floor of `>=1.0`.
+    pub fn excludes(&self, name: &str, version: &str) -> bool {
+        let Some(req) = self
+            .per_crate
+            .get(&name.to_lowercase())
+            .or(self.global.as_ref())
+        else {
+            return false;
+        };
+        let Ok(mut version) = semver::Version::parse(version) else {
+            return false;
+        };
+        version.pre = semver::Prerelease::EMPTY;
+        !req.matches(&version)
+    }
+}
+
+/// Parse a version floor: a requirement such as `>=1.0`, or a plain version meaning at least it.
+fn parse_floor(floor: &str) -> Result<semver::VersionReq, SyncError> {
+    let floor = floor.trim();
+    let req = if floor.starts_with(|c: char| c.is_ascii_digit()) {
+        semver::VersionReq::parse(&format!(">={floor}"))
+    } else {
+        semver::VersionReq::parse(floor)
+    };
+    req.map_err(|_| SyncError::BadMinVersion(floor.to_string()))
+}
+
+/// Read a popularity file, listing one crate per line as `name rank` or `name,rank`, where a
+/// lower rank is more popular. A line with a name alone is ranked by its line number.
+///
+/// Empty lines and comments starting with `#` are ignored.
+pub fn read_popularity_file(path: &Path) -> Result<HashMap<String, u64>, SyncError> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            let mut fields = line.split([',', ' ', '\t']).filter(|f| !f.is_empty());
+            let name = fields.next().unwrap_or_default().to_lowercase();
+            let rank = match (fields.next(), fields.next()) {
+                (None, _) => i as u64 + 1,
+                (Some(rank), None) => rank
+                    .parse()
+                    .map_err(|_| SyncError::BadPopularityEntry(line.to_string()))?,
+                _ => return Err(SyncError::BadPopularityEntry(line.to_string())),
+            };
+            Ok((name, rank))
+        })
+        .collect()
+}
+
+/// Sort crates by popularity, most popular first. Unranked crates come last, in their
+/// original order.
+fn order_by_popularity(crates: &mut [CrateEntry], ranks: &HashMap<String, u64>) {
+    crates.sort_by_cached_key(|c| {
+        ranks
+            .get(&c.name.to_lowercase())
+            .copied()
+            .unwrap_or(u64::MAX)
+    });
+}
+
+/// Read a crate set file, listing one crate per line in the format of `CratePin::parse_set_entry`.
+///
+/// Empty lines and comments starting with `#` are ignored.
+pub fn read_crate_set(path: &Path) -> Result<Vec<CratePin>, SyncError> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(CratePin::parse_set_entry)
+        .collect()
+}
+
+/// URL to download a crate from, given the URL template from `crates_source`.
+fn crate_url(source: Option<&str>, crate_entry: &CrateEntry) -> String {
+    // If source is "https://crates.io/api/v1/crates" (the default, and thus a None here,
+    // unless use_cdn is false) download straight from the static.crates.io CDN, to avoid bogging down crates.io itself
     // or affecting its statistics, and avoiding an extra redirect for each crate.
-    let url = if let Some(source) = source {
-        format!(
-            "{}/{}/{}/download",
-            source, crate_entry.name, crate_entry.vers
-        )
+    if let Some(source) = source {
+        let prefix = crate_prefix(&crate_entry.name)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        source
+            .replace("{name}", &crate_entry.name)
+            .replace("{version}", &crate_entry.vers)
+            .replace("{prefix}", &prefix)
     } else {
         format!(
             "https://static.crates.io/crates/{}/{}-{}.crate",
             crate_entry.name, crate_entry.name, crate_entry.vers
         )
-    };
+    }
+}
 
-    let file_path = get_crate_path(path, &crate_entry.name, &crate_entry.vers)
+/// Download one single crate file.
+pub async fn sync_one_crate_entry(
+    client: &Client,
+    store: &dyn CrateStore,
+    source: Option<&str>,
+    options: &DownloadOptions,
+    crate_entry: &CrateEntry,
+    write_sha256: bool,
+    user_agent: &HeaderValue,
+) -> Result<DownloadStatus, DownloadError> {
+    let url = crate_url(source, crate_entry);
+    let file_path = store
+        .download_path(&crate_entry.name, &crate_entry.vers)
         .ok_or_else(|| DownloadError::BadCrate(crate_entry.name.clone()))?;
 
-    download(
+    let status = download(
         client,
         &url[..],
         &file_path,
         crate_entry.cksum.as_deref(),
-        retries,
+        options,
         false,
         user_agent,
     )
-    .await
+    .await?;
+
+    if let Some(cksum) = crate_entry.cksum.as_deref().filter(|_| write_sha256) {
+        if read_crate_sha256(&file_path).as_deref() != Some(cksum) {
+            let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+            write_file_create_dir(
+                &append_to_path(&file_path, ".sha256"),
+                &format!("{cksum}  {file_name}\n"),
+                options,
+            )?;
+        }
+    }
+
+    store
+        .store(&crate_entry.name, &crate_entry.vers, &file_path)
+        .await?;
+
+    Ok(status)
 }
 
-/// Synchronize the crate files themselves, using the index for a list of files.
-// TODO: There are still many unwraps in the foreach sections. This needs to be fixed.
-pub async fn sync_crates_files(
-    path: &Path,
-    vendor_path: Option<PathBuf>,
-    cargo_lock_filepath: Option<PathBuf>,
-    mirror: &ConfigMirror,
-    crates: &ConfigCrates,
-    user_agent: &HeaderValue,
-) -> Result<(), SyncError> {
-    let is_crate_whitelist_only = vendor_path.is_some() || cargo_lock_filepath.is_some();
+/// Read the hash from a crate's `.sha256` file, if it exists and is not older than the crate.
+pub fn read_crate_sha256(crate_path: &Path) -> Option<String> {
+    let sha256_path = append_to_path(crate_path, ".sha256");
+    let crate_modified = fs::metadata(crate_path).and_then(|m| m.modified()).ok()?;
+    let sha256_modified = fs::metadata(&sha256_path).and_then(|m| m.modified()).ok()?;
+    if sha256_modified < crate_modified {
+        return None;
+    }
+    let contents = fs::read_to_string(sha256_path).ok()?;
+    contents.split_whitespace().next().map(str::to_string)
+}
 
-    // if a vendor_path, parse the filepath for Cargo.toml files for each crate, filling vendors
-    let mut mirror_entries = vec![];
-    vendor_path_to_mirror_entries(&mut mirror_entries, vendor_path.as_ref());
-    // gather crates from Cargo.lock if supplied
-    cargo_lock_to_mirror_entries(&mut mirror_entries, cargo_lock_filepath.as_ref());
+/// Check a crate file against its checksum from the index.
+///
+/// The crate's `.sha256` file is trusted if it is up to date, otherwise the crate is re-hashed.
+pub fn crate_checksum_matches(crate_path: &Path, cksum: &str) -> Result<bool, io::Error> {
+    if let Some(hash) = read_crate_sha256(crate_path) {
+        return Ok(hash == cksum);
+    }
+    Ok(sha256_file(crate_path)? == cksum)
+}
 
-    let prefix = padded_prefix_message(2, 3, "Syncing crates files");
+/// Crate versions to sync, and index files of removed crates, between two versions of the index.
+struct IndexChanges {
+    changed: Vec<CrateEntry>,
+    removed: Vec<PathBuf>,
+    skipped: Vec<SkippedCrate>,
+}
 
-    // For now, assume successful crates.io-index download
-    let repo_path = path.join("crates.io-index");
-    let repo = Repository::open(&repo_path)?;
+/// A crate version that a sync intentionally didn't download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedCrate {
+    pub name: String,
+    pub vers: String,
+    pub reason: SkipReason,
+}
 
-    // Set the crates.io URL, or None if default
-    let crates_source = if crates.source == "https://crates.io/api/v1/crates" {
-        None
-    } else {
-        Some(crates.source.as_str())
-    };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Below the configured `min_version` or `min_versions`.
+    BelowMinVersion,
+    /// Known to be forbidden by crates.io, see `verify::is_known_403`.
+    Known403,
+    /// The server returned this status, 403 or 404.
+    NotFound(u16),
+    /// The crate name doesn't belong in the index file it was found in.
+    IndexPathMismatch,
+}
 
-    // Find Reference for origin/master
-    let origin_master = repo.find_reference("refs/remotes/origin/master")?;
-    let origin_master_tree = origin_master.peel_to_tree()?;
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::BelowMinVersion => f.write_str("below minimum version"),
+            SkipReason::Known403 => f.write_str("known 403"),
+            SkipReason::NotFound(status) => write!(f, "not found ({status})"),
+            SkipReason::IndexPathMismatch => f.write_str("name doesn't match its index file"),
+        }
+    }
+}
 
-    let master = repo.find_reference("refs/heads/master").ok();
-    let master_tree = master.as_ref().and_then(|m| m.peel_to_tree().ok());
+/// Log the crates a sync skipped, and append them to `report` if given,
+/// one tab-separated `date  name@version  reason` line each.
+fn log_skipped_crates(skipped: &[SkippedCrate], report: Option<&Path>) -> Result<(), io::Error> {
+    for s in skipped {
+        log::debug!("Skipped crate {}@{}: {}", s.name, s.vers, s.reason);
+    }
+    let Some(report) = report.filter(|_| !skipped.is_empty()) else {
+        return Ok(());
+    };
+    let date = httpdate::fmt_http_date(std::time::SystemTime::now());
+    let mut f = io::BufWriter::new(
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(report)?,
+    );
+    for s in skipped {
+        writeln!(f, "{date}\t{}@{}\t{}", s.name, s.vers, s.reason)?;
+    }
+    f.flush()
+}
 
-    // Diff between master and origin/master (i.e. everything since the last fetch)
-    let diff = repo.diff_tree_to_tree(master_tree.as_ref(), Some(&origin_master_tree), None)?;
+/// The index tree that crates were last synced from: the upstream commit master was last
+/// fast-forwarded to, or `from_commit` if given.
+///
+/// Master itself has panamax's own commits on top, rewriting config.json and removing pruned
+/// versions, which aren't changes to sync. Mirrors synced before the upstream commit was
+/// recorded use master.
+fn synced_tree<'r>(
+    repo: &'r Repository,
+    from_commit: Option<&str>,
+) -> Result<Option<Tree<'r>>, SyncError> {
+    // Diff from the given commit instead of master, to re-sync everything changed since then.
+    Ok(match from_commit {
+        Some(from_commit) => Some(repo.revparse_single(from_commit)?.peel_to_tree()?),
+        None => repo
+            .find_reference(UPSTREAM_REF)
+            .or_else(|_| repo.find_reference("refs/heads/master"))
+            .ok()
+            .and_then(|m| m.peel_to_tree().ok()),
+    })
+}
 
-    let mut changed_crates = Vec::new();
-    let mut removed_crates = Vec::new();
+/// Find the crate versions changed between `old_tree` and `new_tree`.
+///
+/// With a `whitelist`, only the listed and pinned crate versions are kept. Versions below their
+/// `floor` are skipped unless pinned. Pinned versions that are missing from `store` are always
+/// included, even if they didn't change. Changed index files are parsed on `parse_threads`
+/// threads, or one per CPU if it is 0.
+#[allow(clippy::too_many_arguments)]
+async fn index_changes(
+    repo: &Repository,
+    old_tree: Option<&Tree<'_>>,
+    new_tree: &Tree<'_>,
+    whitelist: Option<&[CrateEntry]>,
+    pins: &[CratePin],
+    floor: &VersionFloor,
+    store: &dyn CrateStore,
+    parse_threads: usize,
+    pb: &ProgressBar,
+) -> Result<IndexChanges, SyncError> {
+    let diff = repo.diff_tree_to_tree(old_tree, Some(new_tree), None)?;
 
-    let pb = ProgressBar::new_spinner()
-        .with_style(
-            ProgressStyle::default_bar()
-                .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
-                .expect("template is correct")
-                .progress_chars("  "),
-        )
-        .with_finish(ProgressFinish::AndLeave)
-        .with_prefix(prefix.clone());
-    pb.enable_steady_tick(Duration::from_millis(10));
+    let mut changed_files = Vec::new();
+    let mut removed_crates = Vec::new();
 
     // Figure out which crates we need to update/remove.
     diff.foreach(
         &mut |delta, _| {
             let df = delta.new_file();
-            let p = df.path().unwrap();
-            if p == Path::new("config.json") {
-                return true;
-            }
-            if p.starts_with(".github/") {
+            let Some(p) = df.path().filter(|p| is_crate_index_path(p)) else {
                 return true;
-            }
+            };
 
             // DEV: if dev_reduced_crates is enabled, only download crates that start with z.
             // Keep this code in here, because it's helpful for development and debugging.
@@ -176,202 +501,1335 @@ pub async fn sync_crates_files(
                 }
             }
 
-            // Get the data for this crate file
-            let oid = df.id();
-            if oid.is_zero() {
+            if df.id().is_zero() {
                 // The crate was removed, continue to next crate.
                 // Note that this does not include yanked crates.
                 removed_crates.push(p.to_path_buf());
-                return true;
-            }
-            let blob = repo.find_blob(oid).unwrap();
-            let data = blob.content();
-
-            // Download one crate for each of the versions in the crate file
-            for line in Cursor::new(data).lines() {
-                let line = line.unwrap();
-                let c = match serde_json::from_str::<CrateEntry>(&line) {
-                    Ok(c) => {
-                        // if vendor_path, check for matching crate name/version
-                        if is_crate_whitelist_only {
-                            if mirror_entries
-                                .iter()
-                                .any(|a| a.name == c.name && a.vers == c.vers)
-                            {
-                                c
-                            } else {
-                                continue;
-                            }
-                        } else {
-                            c
-                        }
-                    }
-                    Err(_) => {
-                        continue;
-                    }
-                };
-
-                changed_crates.push(c);
+            } else {
+                changed_files.push((p.to_path_buf(), df.id()));
             }
-
             true
         },
         None,
         None,
         None,
-    )
-    .unwrap();
+    )?;
 
-    pb.finish_and_clear();
-    let pb = ProgressBar::new(changed_crates.len() as u64)
-        .with_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
+    // Parsing is CPU-bound, unlike downloading, so it has its own threads, waited on from a
+    // blocking thread. A Repository can't be shared between threads, so each opens its own.
+    let repo_path = repo.path().to_path_buf();
+    let whitelist = whitelist.map(<[CrateEntry]>::to_vec);
+    let (thread_pins, floor) = (pins.to_vec(), floor.clone());
+    let parsed = tokio::task::spawn_blocking(move || {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(parse_threads)
+            .build()?;
+        let parsed = pool.install(|| {
+            changed_files
+                .par_iter()
+                .map_init(
+                    || Repository::open(&repo_path),
+                    |thread_repo, (p, oid)| {
+                        let thread_repo = thread_repo
+                            .as_ref()
+                            .map_err(|e| git2::Error::new(e.code(), e.class(), e.message()))?;
+                        let blob = thread_repo.find_blob(*oid)?;
+                        Ok(parse_index_file(
+                            p,
+                            blob.content(),
+                            whitelist.as_deref(),
+                            &thread_pins,
+                            &floor,
+                        ))
+                    },
                 )
-                .expect("template is correct")
-                .progress_chars("█▉▊▋▌▍▎▏  "),
-        )
-        .with_finish(ProgressFinish::AndLeave)
-        .with_prefix(prefix);
-    pb.enable_steady_tick(Duration::from_millis(10));
-
-    let client = Client::new();
+                .collect::<Result<Vec<_>, git2::Error>>()
+        })?;
+        Ok::<_, SyncError>(parsed)
+    })
+    .await
+    .map_err(io::Error::other)??;
 
-    // Dirty hack:
-    // Since we can't rely on diff tree because these crates are manually set
-    // we force them to always update.
-    if is_crate_whitelist_only {
-        changed_crates.append(&mut mirror_entries);
+    let mut changed_crates = Vec::new();
+    let mut skipped_crates = Vec::new();
+    let mut malformed_entries = 0usize;
+    let mut mismatched_entries = 0usize;
+    for file in parsed {
+        changed_crates.extend(file.changed);
+        skipped_crates.extend(file.skipped);
+        malformed_entries += file.malformed;
+        mismatched_entries += file.mismatched;
     }
 
-    let tasks = futures::stream::iter(changed_crates.into_iter())
-        .map(|c| {
-            let client = client.clone();
-            // Duplicate variables used in the async closure.
-            let path = path.to_owned();
-            let mirror_retries = mirror.retries;
-            let crates_source = crates_source.map(|s| s.to_string());
-            let user_agent = user_agent.to_owned();
-            let pb = pb.clone();
-
-            tokio::spawn(async move {
-                let out = sync_one_crate_entry(
-                    &client,
-                    &path,
-                    crates_source.as_deref(),
-                    mirror_retries,
-                    &c,
-                    &user_agent,
-                )
-                .await;
+    if malformed_entries > 0 {
+        pb.suspend(|| {
+            eprintln!("Skipped {malformed_entries} malformed crates.io-index entries. Set RUST_LOG=debug to list them.");
+        });
+    }
+    if mismatched_entries > 0 {
+        pb.suspend(|| {
+            eprintln!("Skipped {mismatched_entries} crates.io-index entries whose name doesn't match their index file. Set RUST_LOG=debug to list them.");
+        });
+    }
 
-                pb.inc(1);
+    // Pinned versions are always downloaded if they're missing, even if they didn't change.
+    for pin in pins {
+        let Some(index_path) = crate_prefix(&pin.name).map(|p| p.join(&pin.name)) else {
+            continue;
+        };
+        let Ok(entry) = new_tree.get_path(&index_path) else {
+            continue;
+        };
+        let blob = repo.find_blob(entry.id())?;
+        for line in Cursor::new(blob.content()).lines() {
+            let Ok(c) = serde_json::from_str::<CrateEntry>(&line?) else {
+                continue;
+            };
+            if pin.matches(&c.name, &c.vers)
+                && !changed_crates
+                    .iter()
+                    .any(|a| a.name == c.name && a.vers == c.vers)
+                && store.locate(&c.name, &c.vers).await?.is_none()
+            {
+                changed_crates.push(c.without_extra());
+            }
+        }
+    }
 
-                out
-            })
-        })
-        .buffer_unordered(crates.download_threads)
-        .collect::<Vec<_>>()
-        .await;
+    Ok(IndexChanges {
+        changed: changed_crates,
+        removed: removed_crates,
+        skipped: skipped_crates,
+    })
+}
 
-    for t in tasks {
-        let res = t.unwrap();
-        match res {
-            Ok(())
-            | Err(DownloadError::NotFound {
-                status: _,
-                url: _,
-                data: _,
-            })
-            | Err(DownloadError::MismatchedHash {
-                expected: _,
-                actual: _,
-            }) => {}
+/// The crate versions to sync from one changed index file.
+#[derive(Default)]
+struct IndexFileEntries {
+    changed: Vec<CrateEntry>,
+    skipped: Vec<SkippedCrate>,
+    malformed: usize,
+    mismatched: usize,
+}
 
+/// Parse the changed index file at `p`, keeping the versions `index_changes` syncs.
+fn parse_index_file(
+    p: &Path,
+    data: &[u8],
+    whitelist: Option<&[CrateEntry]>,
+    pins: &[CratePin],
+    floor: &VersionFloor,
+) -> IndexFileEntries {
+    let mut entries = IndexFileEntries::default();
+    // Download one crate for each of the versions in the crate file
+    for line in data.split(|&b| b == b'\n') {
+        // A line that isn't UTF-8 is malformed, but the rest of the file is still read.
+        let Ok(line) = std::str::from_utf8(line) else {
+            entries.malformed += 1;
+            continue;
+        };
+        let c = match serde_json::from_str::<CrateEntry>(line) {
+            Ok(c) if !is_index_path_of(p, &c.name) => {
+                // Its files would go somewhere other than where they're looked for.
+                entries.mismatched += 1;
+                entries.skipped.push(SkippedCrate {
+                    name: c.name,
+                    vers: c.vers,
+                    reason: SkipReason::IndexPathMismatch,
+                });
+                continue;
+            }
+            Ok(c) => {
+                // if vendor_path, check for matching crate name/version
+                if let Some(whitelist) = whitelist {
+                    if whitelist
+                        .iter()
+                        .any(|a| a.name == c.name && a.vers == c.vers)
+                        || pins.iter().any(|p| p.matches(&c.name, &c.vers))
+                    {
+                        c
+                    } else {
+                        continue;
+                    }
+                } else if floor.excludes(&c.name, &c.vers)
+                    && !pins.iter().any(|p| p.matches(&c.name, &c.vers))
+                {
+                    entries.skipped.push(SkippedCrate {
+                        name: c.name,
+                        vers: c.vers,
+                        reason: SkipReason::BelowMinVersion,
+                    });
+                    continue;
+                } else {
+                    c
+                }
+            }
             Err(e) => {
-                eprintln!("Downloading failed: {e:?}");
+                if !line.trim().is_empty() {
+                    entries.malformed += 1;
+                    log::debug!("Skipping malformed entry in {}: {e}", p.display());
+                }
+                continue;
             }
-        }
+        };
+
+        entries.changed.push(c.without_extra());
     }
+    entries
+}
 
-    // Delete any removed crates
-    for rc in removed_crates {
-        // Try to remove the file, but ignore it if it doesn't exist
-        let _ = fs::remove_file(repo_path.join(rc));
+/// Find what syncing the crate files would download, without changing the mirror.
+///
+/// The index is fetched into `DRY_RUN_REF` rather than origin/master, and crates are
+/// compared against it. Returns the summary and the number of removed crates, or `None`
+/// if crates.io-index hasn't been cloned yet.
+pub async fn dry_run_crates_files(
+    path: &Path,
+    vendor_path: Option<PathBuf>,
+    cargo_lock_filepath: Option<PathBuf>,
+    crate_set: Option<&[CratePin]>,
+    from_commit: Option<&str>,
+    crates: &ConfigCrates,
+    user_agent: &HeaderValue,
+) -> Result<Option<(DryRunSummary, usize)>, SyncError> {
+    if !fetch_for_dry_run(path, crates)? {
+        return Ok(None);
     }
 
-    // Set master to origin/master.
-    //
-    // Note that this means config.json changes will have to be rewritten on every sync.
-    fast_forward(&repo_path)?;
+    let is_crate_whitelist_only =
+        vendor_path.is_some() || cargo_lock_filepath.is_some() || crate_set.is_some();
+    let mut mirror_entries = vec![];
+    vendor_path_to_mirror_entries(&mut mirror_entries, vendor_path.as_ref());
+    cargo_lock_to_mirror_entries(&mut mirror_entries, cargo_lock_filepath.as_slice());
 
-    Ok(())
-}
+    let repo = Repository::open(path.join("crates.io-index"))?;
+    let mut pins = crate_pins(crates)?;
+    pins.extend(crate_set.unwrap_or_default().iter().cloned());
+    let store = crate_store(path, Some(crates))?;
 
-/// Detect if the crates directory is using the old format.
-pub fn is_new_crates_format(path: &Path) -> Result<bool, io::Error> {
-    if !path.exists() {
-        // Path doesn't exist, so we can start with a clean slate.
-        return Ok(true);
+    let fetched_tree = repo.find_reference(DRY_RUN_REF)?.peel_to_tree()?;
+    let master_tree = synced_tree(&repo, from_commit)?;
+    let IndexChanges {
+        changed: mut changed_crates,
+        removed,
+        ..
+    } = index_changes(
+        &repo,
+        master_tree.as_ref(),
+        &fetched_tree,
+        is_crate_whitelist_only.then_some(&mirror_entries[..]),
+        &pins,
+        &VersionFloor::from_config(crates)?,
+        store.as_ref(),
+        crates.index_parse_threads.unwrap_or(0),
+        &ProgressBar::hidden(),
+    )
+    .await?;
+    retain_unpruned(&mut changed_crates, &read_pruned(path)?, &pins);
+    if is_crate_whitelist_only {
+        changed_crates.append(&mut mirror_entries);
     }
 
-    for crate_dir in read_dir(path)? {
-        let crate_dir = crate_dir?;
-        if !crate_dir.file_type()?.is_dir() {
-            // Ignore any files in the directory. Only look at other directories.
-            continue;
+    let crates_source = crates_source(crates);
+    let mut missing_urls = Vec::new();
+    for c in &changed_crates {
+        if store.locate(&c.name, &c.vers).await?.is_none() {
+            missing_urls.push(crate_url(crates_source.as_deref(), c));
         }
-
-        let dir_name = crate_dir
-            .file_name()
-            .into_string()
-            .map_err(|_| io::ErrorKind::Other)?;
-        match dir_name.as_str() {
-            // 1-letter crate names cannot be numbers, so this must be new format.
-            "1" | "2" | "3" => continue,
-            // 2-letter directories are used for crates longer than 3 characters.
-            x if x.len() == 2 => continue,
-            // Unrecognized directory found, might be crate in old format.
-            _ => {
-                return Ok(false);
-            }
-        };
     }
 
-    Ok(true)
+    let mut summary = DryRunSummary::default();
+    summary
+        .add(
+            changed_crates.len(),
+            missing_urls,
+            crates.download_threads,
+            user_agent,
+        )
+        .await;
+    Ok(Some((summary, removed.len())))
 }
 
-pub fn get_crate_path(
-    mirror_path: &Path,
-    crate_name: &str,
-    crate_version: &str,
-) -> Option<PathBuf> {
-    let crate_path = match crate_name.len() {
-        1 => PathBuf::from("1"),
-        2 => PathBuf::from("2"),
+/// Find the crate files in the mirror for versions added to the index since `since_commit`,
+/// or for every version without it. The files are relative to the mirror.
+pub async fn crate_files_since(
+    path: &Path,
+    since_commit: Option<&str>,
+) -> Result<Vec<PathBuf>, SyncError> {
+    let repo = Repository::open(path.join("crates.io-index"))?;
+    let old_tree = since_commit
+        .map(|commit| repo.revparse_single(commit)?.peel_to_tree())
+        .transpose()?;
+    let new_tree = repo.find_reference("refs/heads/master")?.peel_to_tree()?;
+    let IndexChanges { changed, .. } = index_changes(
+        &repo,
+        old_tree.as_ref(),
+        &new_tree,
+        None,
+        &[],
+        &VersionFloor::default(),
+        &FsCrateStore::new(path),
+        0,
+        &ProgressBar::hidden(),
+    )
+    .await?;
+
+    // Changed index files list every version of their crate, including older ones.
+    let mut old_versions: HashMap<String, HashSet<String>> = HashMap::new();
+    if let Some(old_tree) = &old_tree {
+        for c in &changed {
+            if old_versions.contains_key(&c.name) {
+                continue;
+            }
+            let name = c.name.to_lowercase();
+            let versions = crate_prefix(&name)
+                .and_then(|prefix| old_tree.get_path(&prefix.join(&name)).ok())
+                .and_then(|entry| repo.find_blob(entry.id()).ok())
+                .map(|blob| {
+                    Cursor::new(blob.content())
+                        .lines()
+                        .map_while(Result::ok)
+                        .filter_map(|line| serde_json::from_str::<CrateEntry>(&line).ok())
+                        .map(|old| old.vers)
+                        .collect()
+                })
+                .unwrap_or_default();
+            old_versions.insert(c.name.clone(), versions);
+        }
+    }
+
+    Ok(changed
+        .iter()
+        .filter(|c| {
+            !old_versions
+                .get(&c.name)
+                .is_some_and(|versions| versions.contains(&c.vers))
+        })
+        .filter_map(|c| find_crate_file(path, &c.name, &c.vers))
+        .filter_map(|file| file.strip_prefix(path).ok().map(Path::to_path_buf))
+        .collect())
+}
+
+/// Synchronize the crate files themselves, using the index for a list of files.
+///
+/// Returns the number of crates that failed to download.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_crates_files(
+    path: &Path,
+    vendor_path: Option<PathBuf>,
+    cargo_lock_filepath: Option<PathBuf>,
+    crate_set: Option<&[CratePin]>,
+    from_commit: Option<&str>,
+    options: &DownloadOptions,
+    crates: &ConfigCrates,
+    user_agent: &HeaderValue,
+    prefix: String,
+) -> Result<usize, SyncError> {
+    let is_crate_whitelist_only =
+        vendor_path.is_some() || cargo_lock_filepath.is_some() || crate_set.is_some();
+
+    // if a vendor_path, parse the filepath for Cargo.toml files for each crate, filling vendors
+    let mut mirror_entries = vec![];
+    vendor_path_to_mirror_entries(&mut mirror_entries, vendor_path.as_ref());
+    // gather crates from Cargo.lock if supplied
+    cargo_lock_to_mirror_entries(&mut mirror_entries, cargo_lock_filepath.as_slice());
+
+    // For now, assume successful crates.io-index download
+    let repo_path = path.join("crates.io-index");
+    let repo = Repository::open(&repo_path)?;
+
+    // Crates from the crate set are synced like pinned crates.
+    let mut pins = crate_pins(crates)?;
+    pins.extend(crate_set.unwrap_or_default().iter().cloned());
+
+    // Scheduled syncs often fetch nothing new. Pinned and whitelisted crates are synced
+    // whether or not they changed, so they always need the full sync.
+    if from_commit.is_none()
+        && !is_crate_whitelist_only
+        && pins.is_empty()
+        && is_index_synced(&repo)
+    {
+        // Consumers of the progress events still see the phase, with nothing in it.
+        let _events = PhaseEvents::start(options.events.as_ref(), "crates", Some(0));
+        eprintln!("{prefix} crates already up to date");
+        return Ok(0);
+    }
+    let store = crate_store(path, Some(crates))?;
+
+    // Find Reference for origin/master
+    let origin_master = repo.find_reference("refs/remotes/origin/master")?;
+    let origin_master_tree = origin_master.peel_to_tree()?;
+    let master_tree = synced_tree(&repo, from_commit)?;
+
+    let pb = progress_spinner()
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
+                .expect("template is correct")
+                .progress_chars("  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix.clone());
+    pb.enable_steady_tick(Duration::from_millis(10));
+
+    // Diff between master and origin/master (i.e. everything since the last fetch)
+    let IndexChanges {
+        changed: mut changed_crates,
+        removed: removed_crates,
+        skipped: mut skipped_crates,
+    } = index_changes(
+        &repo,
+        master_tree.as_ref(),
+        &origin_master_tree,
+        is_crate_whitelist_only.then_some(&mirror_entries[..]),
+        &pins,
+        &VersionFloor::from_config(crates)?,
+        store.as_ref(),
+        crates.index_parse_threads.unwrap_or(0),
+        &pb,
+    )
+    .await?;
+    let pruned = read_pruned(path)?;
+    retain_unpruned(&mut changed_crates, &pruned, &pins);
+
+    pb.finish_and_clear();
+    // Most crates are already present on a re-sync, so the ETA is based on downloaded bytes.
+    let progress = DownloadProgress::new(changed_crates.len(), prefix.clone());
+    let pb = progress.bar().clone();
+    pb.enable_steady_tick(Duration::from_millis(10));
+    let options = &DownloadOptions {
+        on_bytes: Some(progress.on_bytes()),
+        ..options.clone()
+    };
+
+    // Dirty hack:
+    // Since we can't rely on diff tree because these crates are manually set
+    // we force them to always update.
+    if is_crate_whitelist_only {
+        changed_crates.append(&mut mirror_entries);
+    }
+
+    // Skip crates already downloaded by an interrupted sync, without re-hashing them.
+    let checkpoint_path = path.join(CHECKPOINT_FILE);
+    let completed = read_crate_list(&checkpoint_path)?;
+    if !completed.is_empty() {
+        changed_crates.retain(|c| !completed.contains(&format!("{}@{}", c.name, c.vers)));
+        pb.suspend(|| {
+            eprintln!(
+                "Resuming from {CHECKPOINT_FILE}, skipping {} crates already downloaded.",
+                completed.len()
+            )
+        });
+    }
+    if let Some(popularity_file) = &crates.popularity_file {
+        order_by_popularity(
+            &mut changed_crates,
+            &read_popularity_file(&path.join(popularity_file))?,
+        );
+    }
+    pb.set_length(changed_crates.len() as u64);
+    let events = PhaseEvents::start(
+        options.events.as_ref(),
+        "crates",
+        Some(changed_crates.len()),
+    );
+    let checkpoint = Arc::new(Mutex::new(
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&checkpoint_path)?,
+    ));
+
+    let on_done = {
+        let progress = progress.clone();
+        move |c: &CrateEntry, out: &Result<DownloadStatus, DownloadError>| {
+            if out.is_ok() {
+                // The checkpoint only saves time, so failing to write it isn't an error.
+                if let Ok(mut f) = checkpoint.lock() {
+                    let _ = writeln!(f, "{}@{}", c.name, c.vers);
+                }
+            }
+            progress.finish_file(out.as_ref().ok().copied());
+        }
+    };
+    let tasks =
+        download_crate_entries(changed_crates, store, crates, options, user_agent, on_done).await;
+
+    let mut errors_occurred = 0usize;
+    let mut restored = Vec::new();
+    for (c, res) in tasks {
+        let item = format!("{}@{}", c.get_name(), c.get_vers());
+        match &res {
+            Ok(_) => events.completed(&item),
+            Err(e) => events.failed(&item, e),
+        }
+        match res {
+            Ok(_) => {
+                let key = pruned_key(&c.name, &c.vers);
+                if pruned.contains(&key) {
+                    restored.push(key);
+                }
+            }
+
+            Err(DownloadError::NotFound { status, .. }) => {
+                let reason = if crate::verify::is_known_403(c.get_name(), c.get_vers()) {
+                    SkipReason::Known403
+                } else {
+                    SkipReason::NotFound(status)
+                };
+                skipped_crates.push(SkippedCrate {
+                    name: c.name,
+                    vers: c.vers,
+                    reason,
+                });
+            }
+
+            // The hash still didn't match after retrying, which usually means
+            // the source is serving a bad file. Report it rather than skipping it.
+            Err(DownloadError::MismatchedHash { expected, actual }) => {
+                errors_occurred += 1;
+                eprintln!(
+                    "Crate {}@{} has a mismatched hash after {} retries - expected '{expected}', got '{actual}'",
+                    c.get_name(),
+                    c.get_vers(),
+                    options.retries
+                );
+            }
+
+            Err(e) => {
+                errors_occurred += 1;
+                eprintln!("Downloading failed: {e:?}");
+            }
+        }
+    }
+
+    unprune(path, &restored)?;
+
+    let skip_report = crates.skip_report.as_ref().map(|report| path.join(report));
+    if let Err(e) = log_skipped_crates(&skipped_crates, skip_report.as_deref()) {
+        eprintln!("Warning: could not write the skipped crates report: {e}");
+    }
+
+    // Master stays behind, so the next sync picks up from the checkpoint.
+    if options.cancel.is_cancelled() {
+        return Err(SyncError::Interrupted);
+    }
+
+    // Delete any removed crates
+    if !removed_crates.is_empty() {
+        let pb = progress_bar(removed_crates.len() as u64)
+            .with_style(
+                ProgressStyle::default_bar()
+                    .template("{prefix} {wide_bar} {pos}/{len} removed [{elapsed_precise}]")
+                    .expect("template is correct")
+                    .progress_chars("█▉▊▋▌▍▎▏  "),
+            )
+            .with_finish(ProgressFinish::AndLeave)
+            .with_prefix(prefix);
+
+        futures::stream::iter(removed_crates)
+            .map(|rc| {
+                let path = repo_path.join(rc);
+                let pb = pb.clone();
+                async move {
+                    // Try to remove the file, but ignore it if it doesn't exist
+                    let _ = tokio::fs::remove_file(path).await;
+                    pb.inc(1);
+                }
+            })
+            .buffer_unordered(crates.download_threads)
+            .collect::<Vec<_>>()
+            .await;
+    }
+
+    // Set master to origin/master.
+    //
+    // Note that this means config.json changes will have to be rewritten on every sync.
+    let _index_guard = INDEX_LOCK.write().await;
+    fast_forward(&repo_path)?;
+
+    // Master is now past these crates, so the next sync won't look at them again.
+    fs::remove_file(&checkpoint_path)?;
+
+    Ok(errors_occurred)
+}
+
+/// Whether master is already at the fetched origin/master, leaving no index changes to sync.
+fn is_index_synced(repo: &Repository) -> bool {
+    let Some(origin_master) = repo
+        .find_reference("refs/remotes/origin/master")
+        .ok()
+        .and_then(|r| r.target())
+    else {
+        return false;
+    };
+    if let Some(upstream) = repo
+        .find_reference(UPSTREAM_REF)
+        .ok()
+        .and_then(|r| r.target())
+    {
+        return upstream == origin_master;
+    }
+    let Ok(master) = repo
+        .find_reference("refs/heads/master")
+        .and_then(|r| r.peel_to_commit())
+    else {
+        return false;
+    };
+    // The rewritten config.json is committed on top of the synced commit.
+    master.id() == origin_master || master.parent_ids().eq([origin_master])
+}
+
+/// Crates downloaded so far by a sync of the crates files, one `name@version` per line.
+///
+/// A sync that is interrupted before master is fast-forwarded re-diffs the same crates,
+/// so this lets it skip the ones that were already downloaded.
+const CHECKPOINT_FILE: &str = "crates.checkpoint";
+
+/// Read a file of `name@version` lines, such as the checkpoint. A missing file is empty.
+fn read_crate_list(list_path: &Path) -> Result<HashSet<String>, io::Error> {
+    match fs::read_to_string(list_path) {
+        Ok(data) => Ok(data.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Crate versions deleted by `prune_crates_files`, one lowercased `name@version` per line.
+///
+/// Pruned versions are removed from the served crates.io-index, so cargo never resolves a
+/// version whose file is gone, and neither sync nor verify downloads them again. Pinning a
+/// pruned version downloads it again, which takes it off the list.
+pub const PRUNED_FILE: &str = "crates.pruned";
+
+/// The key of a crate version in `PRUNED_FILE`.
+pub fn pruned_key(name: &str, version: &str) -> String {
+    format!("{}@{version}", name.to_lowercase())
+}
+
+/// Read the crate versions pruned from the mirror at `path`.
+pub fn read_pruned(path: &Path) -> Result<HashSet<String>, io::Error> {
+    read_crate_list(&path.join(PRUNED_FILE))
+}
+
+/// Drop pruned versions from the crates to sync, unless they're pinned.
+fn retain_unpruned(crates: &mut Vec<CrateEntry>, pruned: &HashSet<String>, pins: &[CratePin]) {
+    if !pruned.is_empty() {
+        crates.retain(|c| {
+            !pruned.contains(&pruned_key(&c.name, &c.vers))
+                || pins.iter().any(|p| p.matches(&c.name, &c.vers))
+        });
+    }
+}
+
+/// Take crate versions that were downloaded again off the pruned list.
+fn unprune(path: &Path, restored: &[String]) -> Result<(), io::Error> {
+    if restored.is_empty() {
+        return Ok(());
+    }
+    let mut pruned: Vec<_> = read_pruned(path)?.into_iter().collect();
+    pruned.retain(|key| !restored.contains(key));
+    pruned.sort();
+    let mut data = pruned.join("\n");
+    if !data.is_empty() {
+        data.push('\n');
+    }
+    fs::write(path.join(PRUNED_FILE), data)
+}
+
+/// Synchronize only the given crates, e.g. `serde@1.0.130`, or `serde` for all of its versions.
+///
+/// The crates are looked up in the local crates.io-index, without fetching or diffing it.
+/// Returns the number of crates that were not found or failed to download.
+pub async fn sync_only_crates_files(
+    path: &Path,
+    only: &[String],
+    options: &DownloadOptions,
+    crates: &ConfigCrates,
+    user_agent: &HeaderValue,
+    prefix: String,
+) -> Result<usize, SyncError> {
+    let mut errors_occurred = 0usize;
+    let mut crate_entries = Vec::new();
+
+    for spec in only {
+        let (name, version) = match spec.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (spec.as_str(), None),
+        };
+
+        let entries: Vec<CrateEntry> = read_index_entries(path, name)?
+            .into_iter()
+            .filter(|c| version.is_none_or(|v| c.vers == v))
+            .collect();
+        if entries.is_empty() {
+            errors_occurred += 1;
+            eprintln!("Crate {spec} was not found in crates.io-index.");
+        }
+        crate_entries.extend(entries);
+    }
+
+    let pb = progress_bar(crate_entries.len() as u64)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
+                )
+                .expect("template is correct")
+                .progress_chars("█▉▊▋▌▍▎▏  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix);
+
+    let store = crate_store(path, Some(crates))?;
+    let on_done = move |_: &CrateEntry, _: &Result<DownloadStatus, DownloadError>| pb.inc(1);
+    let tasks =
+        download_crate_entries(crate_entries, store, crates, options, user_agent, on_done).await;
+    for (c, res) in tasks {
+        if let Err(e) = res {
+            errors_occurred += 1;
+            eprintln!("Downloading {}@{} failed: {e:?}", c.name, c.vers);
+        }
+    }
+
+    if options.cancel.is_cancelled() {
+        return Err(SyncError::Interrupted);
+    }
+    Ok(errors_occurred)
+}
+
+/// Download crate files, `download_threads` at a time, until the sync is cancelled.
+///
+/// `on_done` is called as each download finishes, and the results are returned in the
+/// order they finished. Crates are small, so they are never split into `parallel_chunks`.
+pub(crate) async fn download_crate_entries<F>(
+    crate_entries: Vec<CrateEntry>,
+    store: Arc<dyn CrateStore>,
+    crates: &ConfigCrates,
+    options: &DownloadOptions,
+    user_agent: &HeaderValue,
+    on_done: F,
+) -> Vec<(CrateEntry, Result<DownloadStatus, DownloadError>)>
+where
+    F: Fn(&CrateEntry, &Result<DownloadStatus, DownloadError>) + Clone + Send + 'static,
+{
+    let client = Client::new();
+    let options = &DownloadOptions {
+        parallel_chunks: 1,
+        ..options.clone()
+    };
+    // Set the crates.io URL, or None if default
+    let crates_source = crates_source(crates);
+    let write_sha256 = crates.write_sha256.unwrap_or(false);
+
+    futures::stream::iter(crate_entries)
+        .take_while(|_| futures::future::ready(!options.cancel.is_cancelled()))
+        .map(|c| {
+            // Duplicate variables used in the async closure.
+            let client = client.clone();
+            let store = store.clone();
+            let options = options.clone();
+            let crates_source = crates_source.clone();
+            let user_agent = user_agent.to_owned();
+            let on_done = on_done.clone();
+
+            tokio::spawn(async move {
+                let out = sync_one_crate_entry(
+                    &client,
+                    store.as_ref(),
+                    crates_source.as_deref(),
+                    &options,
+                    &c,
+                    write_sha256,
+                    &user_agent,
+                )
+                .await;
+                on_done(&c, &out);
+                (c, out)
+            })
+        })
+        .buffer_unordered(crates.download_threads)
+        .map(|t| t.expect("crate download task panicked"))
+        .collect()
+        .await
+}
+
+/// Read all entries of one crate from the local crates.io-index.
+///
+/// A crate that isn't in the index has no entries.
+fn read_index_entries(path: &Path, crate_name: &str) -> Result<Vec<CrateEntry>, SyncError> {
+    let crate_name = crate_name.to_lowercase();
+    let Some(prefix) = crate_prefix(&crate_name) else {
+        return Ok(Vec::new());
+    };
+
+    let index_path = path.join("crates.io-index").join(prefix).join(crate_name);
+    let data = match fs::read_to_string(index_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Default `download_url_template`, matching the crates.io download API.
+pub const DEFAULT_DOWNLOAD_URL_TEMPLATE: &str = "{source}/{name}/{version}/download";
+
+/// The URL template to download crates from, with the source filled in, or None if crates
+/// should be downloaded from the static.crates.io CDN.
+///
+/// The CDN is used for the crates.io default source, unless `use_cdn` is false.
+pub fn crates_source(crates: &ConfigCrates) -> Option<String> {
+    if crates.source == "https://crates.io/api/v1/crates" && crates.use_cdn.unwrap_or(true) {
+        None
+    } else {
+        let template = crates
+            .download_url_template
+            .as_deref()
+            .unwrap_or(DEFAULT_DOWNLOAD_URL_TEMPLATE);
+        Some(template.replace("{source}", crates.source.trim_end_matches('/')))
+    }
+}
+
+/// One crate file found in the crates directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrateFile {
+    /// Name of the crate's directory, which is lowercase in the current layout.
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+    pub len: u64,
+}
+
+/// Find every crate file in a crates directory.
+///
+/// Crate files are stored as `<prefix>/<name>/<version>/<name>-<version>.crate`, whatever
+/// the prefix layout. The subdirectories are walked in parallel, and the files are returned
+/// in no particular order.
+///
+/// `crates_path` may be a symlink, but symlinks below it are neither followed nor returned,
+/// so callers deleting the files found can't delete anything elsewhere through them.
+pub fn walk_crate_files(crates_path: &Path) -> Result<Vec<CrateFile>, io::Error> {
+    if !crates_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    // Split the tree at the second level, which has thousands of directories in a full mirror.
+    let mut subdirs = Vec::new();
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(crates_path).min_depth(1).max_depth(2) {
+        let entry = entry?;
+        if entry.depth() == 2 && entry.file_type().is_dir() {
+            subdirs.push(entry.into_path());
+        } else if let Some(file) = crate_file(&entry)? {
+            files.push(file);
+        }
+    }
+
+    let found = subdirs
+        .par_iter()
+        .map(|subdir| {
+            let mut files = Vec::new();
+            for entry in walkdir::WalkDir::new(subdir) {
+                if let Some(file) = crate_file(&entry?)? {
+                    files.push(file);
+                }
+            }
+            Ok(files)
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    files.extend(found.into_iter().flatten());
+    Ok(files)
+}
+
+/// The crate file at a directory entry, or `None` if it isn't one.
+fn crate_file(entry: &walkdir::DirEntry) -> Result<Option<CrateFile>, io::Error> {
+    let file_path = entry.path();
+    if !entry.file_type().is_file() || file_path.extension() != Some(OsStr::new("crate")) {
+        return Ok(None);
+    }
+
+    let version_dir = file_path.parent();
+    let name = version_dir
+        .and_then(Path::parent)
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str);
+    let version = version_dir
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str);
+    let (Some(name), Some(version)) = (name, version) else {
+        return Ok(None);
+    };
+    Ok(Some(CrateFile {
+        name: name.to_string(),
+        version: version.to_string(),
+        path: file_path.to_path_buf(),
+        len: entry.metadata()?.len(),
+    }))
+}
+
+/// Delete the oldest crate versions until the crates directory fits in `max_total_bytes`.
+///
+/// The latest version of each crate, and any crate from the vendor path or Cargo.lock,
+/// is never deleted. Deleted versions are added to `PRUNED_FILE`. Returns the number of
+/// bytes freed.
+pub fn prune_crates_files(
+    path: &Path,
+    max_total_bytes: u64,
+    vendor_path: Option<&PathBuf>,
+    cargo_lock_filepath: Option<&PathBuf>,
+    pins: &[CratePin],
+    prefix: String,
+) -> Result<u64, SyncError> {
+    let mut pinned = vec![];
+    vendor_path_to_mirror_entries(&mut pinned, vendor_path);
+    cargo_lock_to_mirror_entries(
+        &mut pinned,
+        cargo_lock_filepath
+            .map(std::slice::from_ref)
+            .unwrap_or_default(),
+    );
+
+    let mut total_bytes = 0;
+    let mut crate_files: HashMap<String, Vec<(semver::Version, CrateFile)>> = HashMap::new();
+    for file in walk_crate_files(&path.join("crates"))? {
+        total_bytes += file.len;
+        // Files that can't be ordered are never deleted, but still count towards the total.
+        if let Ok(version) = semver::Version::parse(&file.version) {
+            crate_files
+                .entry(file.name.clone())
+                .or_default()
+                .push((version, file));
+        }
+    }
+
+    if total_bytes <= max_total_bytes {
+        return Ok(0);
+    }
+
+    // Evict the oldest version of every crate first, then the second oldest, and so on.
+    let mut removable: Vec<(usize, semver::Version, CrateFile)> = Vec::new();
+    for mut versions in crate_files.into_values() {
+        versions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        // Always keep the latest version.
+        versions.pop();
+        removable.extend(
+            versions
+                .into_iter()
+                .enumerate()
+                .map(|(age, (version, c))| (age, version, c)),
+        );
+    }
+    removable.retain(|(_, _, c)| {
+        !pinned
+            .iter()
+            .any(|p| p.name.to_lowercase() == c.name && p.vers == c.version)
+            && !pins.iter().any(|p| p.matches(&c.name, &c.version))
+    });
+    removable.sort_by(|(a_age, a_version, a), (b_age, b_version, b)| {
+        a_age
+            .cmp(b_age)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a_version.cmp(b_version))
+    });
+
+    let pb = progress_bar(total_bytes - max_total_bytes)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("{prefix} {wide_bar} {bytes}/{total_bytes} [{elapsed_precise}]")
+                .expect("template is correct")
+                .progress_chars("█▉▊▋▌▍▎▏  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix);
+
+    let mut pruned_list = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.join(PRUNED_FILE))?;
+    let mut freed = 0;
+    for (_, _, c) in removable {
+        if total_bytes - freed <= max_total_bytes {
+            break;
+        }
+        fs::remove_file(&c.path)?;
+        writeln!(pruned_list, "{}", pruned_key(&c.name, &c.version))?;
+        let _ = fs::remove_file(append_to_path(&c.path, ".sha256"));
+        // Remove the version directory too, if nothing else is in it.
+        if let Some(version_dir) = c.path.parent() {
+            let _ = fs::remove_dir(version_dir);
+        }
+        freed += c.len;
+        pb.inc(c.len);
+    }
+
+    if total_bytes - freed > max_total_bytes {
+        eprintln!("The crates directory is still larger than max_total_bytes, but only the latest or pinned crate versions are left.");
+    }
+
+    Ok(freed)
+}
+
+/// Detect if the crates directory is using the old format.
+pub fn is_new_crates_format(path: &Path) -> Result<bool, io::Error> {
+    if !path.exists() {
+        // Path doesn't exist, so we can start with a clean slate.
+        return Ok(true);
+    }
+
+    for crate_dir in read_dir(path)? {
+        let crate_dir = crate_dir?;
+        if !crate_dir.file_type()?.is_dir() {
+            // Ignore any files in the directory. Only look at other directories.
+            continue;
+        }
+
+        let dir_name = crate_dir
+            .file_name()
+            .into_string()
+            .map_err(|_| io::ErrorKind::Other)?;
+        match dir_name.as_str() {
+            // 1-letter crate names cannot be numbers, so this must be new format.
+            "1" | "2" | "3" => continue,
+            // 2-letter directories are used for crates longer than 3 characters.
+            x if x.len() == 2 => continue,
+            // Unrecognized directory found, might be crate in old format.
+            _ => {
+                return Ok(false);
+            }
+        };
+    }
+
+    Ok(true)
+}
+
+/// Get the prefix directory of a crate, as used by both crates.io-index and the crates directory.
+fn crate_prefix(crate_name: &str) -> Option<PathBuf> {
+    match crate_name.len() {
+        1 => Some(PathBuf::from("1")),
+        2 => Some(PathBuf::from("2")),
         3 => {
             let first_char = crate_name.get(0..1)?;
-            PathBuf::from("3").join(first_char)
+            Some(PathBuf::from("3").join(first_char))
         }
         n if n >= 4 => {
             let first_two = crate_name.get(0..2)?;
             let second_two = crate_name.get(2..4)?;
-            [first_two, second_two].iter().collect()
+            Some([first_two, second_two].iter().collect())
         }
-        _ => return None,
+        _ => None,
+    }
+}
+
+/// Check if a path within crates.io-index is a crate file, e.g. `se/rd/serde`.
+///
+/// Anything else in the repository (config.json, .github/, READMEs, etc.) is rejected.
+pub fn is_crate_index_path(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
     };
 
+    // Index file names are lowercased crate names, so they never contain a '.'.
+    let valid_name = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    valid_name && crate_prefix(name).is_some_and(|prefix| path == prefix.join(name))
+}
+
+/// Write every entry of the crates.io-index at `index_path` to `out`, as a line of JSON each.
+///
+/// Entries are read from the synced master branch, or from the index files on disk if the
+/// index isn't a git repository, e.g. a copy of a sparse index. Returns the number of entries.
+pub fn write_catalog(index_path: &Path, out: &mut dyn Write) -> Result<usize, SyncError> {
+    let mut count = 0;
+    let Ok(repo) = Repository::open(index_path) else {
+        for entry in walkdir::WalkDir::new(index_path).sort_by_file_name() {
+            let entry = entry.map_err(io::Error::from)?;
+            let is_index_file = entry
+                .path()
+                .strip_prefix(index_path)
+                .is_ok_and(is_crate_index_path);
+            if entry.file_type().is_file() && is_index_file {
+                count += write_catalog_entries(&fs::read(entry.path())?, out)?;
+            }
+        }
+        return Ok(count);
+    };
+
+    let tree = repo.find_reference("refs/heads/master")?.peel_to_tree()?;
+    let mut result = Ok(());
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        let path = Path::new(dir).join(entry.name().unwrap_or_default());
+        if entry.kind() != Some(ObjectType::Blob) || !is_crate_index_path(&path) {
+            return TreeWalkResult::Ok;
+        }
+        result = repo
+            .find_blob(entry.id())
+            .map_err(SyncError::from)
+            .and_then(|blob| Ok(write_catalog_entries(blob.content(), out)?))
+            .map(|n| count += n);
+        if result.is_ok() {
+            TreeWalkResult::Ok
+        } else {
+            TreeWalkResult::Abort
+        }
+    })?;
+    result.map(|_| count)
+}
+
+/// Write the entries of one index file as lines of JSON, skipping lines that aren't entries.
+fn write_catalog_entries(data: &[u8], out: &mut dyn Write) -> Result<usize, io::Error> {
+    let mut count = 0;
+    for line in Cursor::new(data).lines() {
+        let Ok(entry) = serde_json::from_str::<CrateEntry>(&line?) else {
+            continue;
+        };
+        serde_json::to_writer(&mut *out, &entry)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Check that a crate named `crate_name` belongs in the index file at `path`, e.g. `se/rd/serde`.
+///
+/// The mirror stores crate files by the name in their index entry, so an entry in the wrong
+/// index file would be stored where nothing looks for it.
+pub fn is_index_path_of(path: &Path, crate_name: &str) -> bool {
+    let name = crate_name.to_lowercase();
+    crate_prefix(&name).is_some_and(|prefix| path == prefix.join(&name))
+}
+
+/// Path of a crate's file within crates.io-index, e.g. `se/rd/serde`.
+pub fn index_file_path(crate_name: &str) -> Option<PathBuf> {
+    let name = crate_name.to_lowercase();
+    crate_prefix(&name).map(|prefix| prefix.join(name))
+}
+
+/// Find the entry of a crate version in the checked out crates.io-index at `index_path`.
+pub fn read_index_entry(index_path: &Path, name: &str, version: &str) -> Option<CrateEntry> {
+    let name = name.to_lowercase();
+    let relative = crate_prefix(&name)?.join(&name);
+    if !is_crate_index_path(&relative) {
+        return None;
+    }
+    fs::read_to_string(index_path.join(relative))
+        .ok()?
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CrateEntry>(line).ok())
+        .find(|c| c.vers == version && c.name.to_lowercase() == name)
+}
+
+/// Get the path of a crate file in the mirror.
+///
+/// Directories use the lowercased crate name, matching the layout of crates.io-index,
+/// while the file name keeps the original casing. Paths opened on disk go through
+/// `download::long_path`, as this is also used for relative S3 keys.
+pub fn get_crate_path(
+    mirror_path: &Path,
+    crate_name: &str,
+    crate_version: &str,
+) -> Option<PathBuf> {
+    let lower_name = crate_name.to_lowercase();
+    let crate_path = crate_prefix(&lower_name)?;
+
     Some(
         mirror_path
             .join("crates")
             .join(crate_path)
-            .join(crate_name)
+            .join(lower_name)
             .join(crate_version)
             .join(format!("{crate_name}-{crate_version}.crate")),
     )
 }
 
+/// Link the directory of a mixed-case crate, under its original casing, to its lowercased
+/// directory, e.g. `crates/in/fl/Inflector -> inflector`.
+///
+/// config.json's `dl` is `{lowerprefix}/{crate}/...`, as cargo has no marker for the
+/// lowercased name, so this lets static file servers find the crate. Nothing is done for
+/// lowercase names, where the link already exists (or is the directory itself, on
+/// case-insensitive file systems), or on platforms other than unix.
+pub fn link_crate_dir(mirror_path: &Path, crate_name: &str) -> Result<(), io::Error> {
+    let lower_name = crate_name.to_lowercase();
+    let Some(prefix) = crate_prefix(&lower_name).filter(|_| lower_name != crate_name) else {
+        return Ok(());
+    };
+    let link = mirror_path.join("crates").join(prefix).join(crate_name);
+    if link.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    match std::os::unix::fs::symlink(&lower_name, &link) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Find a crate file on disk, whatever the casing of `crate_name`.
+///
+/// Besides the path from `get_crate_path`, this also finds crates stored under
+/// directories with the original casing of their name, as older versions did.
+pub fn find_crate_file(
+    mirror_path: &Path,
+    crate_name: &str,
+    crate_version: &str,
+) -> Option<PathBuf> {
+    let crate_path = long_path(&get_crate_path(mirror_path, crate_name, crate_version)?);
+    if crate_path.exists() {
+        return Some(crate_path);
+    }
+
+    // The file name may be cased differently than the requested name.
+    let file_name = crate_path.file_name()?.to_str()?.to_lowercase();
+    if let Ok(entries) = read_dir(crate_path.parent()?) {
+        for entry in entries.flatten() {
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.to_lowercase() == file_name)
+            {
+                return Some(entry.path());
+            }
+        }
+    }
+
+    let legacy_path = long_path(
+        &mirror_path
+            .join("crates")
+            .join(crate_prefix(crate_name)?)
+            .join(crate_name)
+            .join(crate_version)
+            .join(format!("{crate_name}-{crate_version}.crate")),
+    );
+    legacy_path.exists().then_some(legacy_path)
+}
+
+/// Move crates stored under directories with the original casing of their name, as older
+/// versions did, to the lowercased directories of `get_crate_path`, linked from
+/// `link_crate_dir`.
+///
+/// Files that already exist at the new path are left where they are, which also keeps
+/// case-insensitive file systems from moving a file onto itself.
+/// Returns the number of crate files moved.
+pub fn lowercase_crate_dirs(mirror_path: &Path) -> Result<usize, io::Error> {
+    let crates_path = mirror_path.join("crates");
+    if !crates_path.exists() {
+        return Ok(0);
+    }
+
+    // crates/<prefix>/<name>/<version>/<name>-<version>.crate, with one or two prefix levels.
+    let mut legacy_dirs = Vec::new();
+    for entry in walkdir::WalkDir::new(&crates_path)
+        .min_depth(4)
+        .max_depth(5)
+    {
+        let entry = entry?;
+        let is_crate = entry.path().extension().is_some_and(|ext| ext == "crate");
+        if !entry.file_type().is_file() || !is_crate {
+            continue;
+        }
+        let Some(version_dir) = entry.path().parent() else {
+            continue;
+        };
+        let (Some(name), Some(version)) = (
+            version_dir.parent().and_then(|d| d.file_name()?.to_str()),
+            version_dir.file_name().and_then(|v| v.to_str()),
+        ) else {
+            continue;
+        };
+        if name != name.to_lowercase() {
+            if let Some(crate_path) = get_crate_path(mirror_path, name, version) {
+                legacy_dirs.push((version_dir.to_path_buf(), crate_path));
+            }
+        }
+    }
+
+    let mut moved = 0;
+    let mut names = HashSet::new();
+    for (version_dir, crate_path) in legacy_dirs {
+        if let Some(name) = version_dir.parent().and_then(|d| d.file_name()) {
+            names.insert(name.to_string_lossy().into_owned());
+        }
+        let Some(new_dir) = crate_path.parent() else {
+            continue;
+        };
+        fs::create_dir_all(new_dir)?;
+        for entry in read_dir(&version_dir)? {
+            let entry = entry?;
+            let to = new_dir.join(entry.file_name());
+            if to.exists() {
+                continue;
+            }
+            fs::rename(entry.path(), &to)?;
+            if to.extension().is_some_and(|ext| ext == "crate") {
+                moved += 1;
+            }
+        }
+
+        // Remove the legacy directories, up to the crates directory, once they are empty.
+        let mut dir = version_dir.as_path();
+        while dir != crates_path && fs::remove_dir(dir).is_ok() {
+            match dir.parent() {
+                Some(parent) => dir = parent,
+                None => break,
+            }
+        }
+    }
+    for name in names {
+        link_crate_dir(mirror_path, &name)?;
+    }
+    Ok(moved)
+}
+
+/// The parts of a vendored crate's Cargo.toml used to find it in the index.
+#[derive(Deserialize)]
+struct VendorManifest {
+    package: Option<VendorPackage>,
+}
+
+#[derive(Deserialize)]
+struct VendorPackage {
+    name: String,
+    version: Option<ManifestVersion>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestVersion {
+    Version(String),
+    /// `version.workspace = true`, which can't be resolved from the crate alone.
+    Inherited {
+        workspace: bool,
+    },
+}
+
+/// Read the name and version of a vendored crate from its Cargo.toml.
+fn read_vendor_manifest(path: &Path) -> Result<(String, String), String> {
+    let manifest = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let manifest: VendorManifest =
+        toml_edit::easy::from_str(&manifest).map_err(|e| e.to_string())?;
+    let package = manifest
+        .package
+        .ok_or_else(|| "no [package] section".to_string())?;
+    match package.version {
+        Some(ManifestVersion::Version(version)) => Ok((package.name, version)),
+        Some(ManifestVersion::Inherited { workspace: true }) => Err(format!(
+            "the version of {} is inherited from its workspace",
+            package.name
+        )),
+        Some(ManifestVersion::Inherited { workspace: false }) | None => {
+            Err(format!("{} has no version", package.name))
+        }
+    }
+}
+
 pub(crate) fn vendor_path_to_mirror_entries(
     mirror_entries: &mut Vec<CrateEntry>,
     vendor_path: Option<&PathBuf>,
@@ -384,61 +1842,662 @@ pub(crate) fn vendor_path_to_mirror_entries(
         {
             let path = entry.as_ref().unwrap().path();
             if path.file_name() == Some(OsStr::new("Cargo.toml")) {
-                let s = fs::read_to_string(entry.unwrap().path()).unwrap();
-                let crate_toml = s.parse::<toml_edit::easy::Value>().unwrap();
-                if let toml_edit::easy::Value::Table(crate_f) = crate_toml {
-                    let name = crate_f["package"]["name"].to_string().replace('\"', "");
-                    let version = crate_f["package"]["version"].to_string().replace('\"', "");
-                    mirror_entries.push(CrateEntry {
+                match read_vendor_manifest(path) {
+                    Ok((name, version)) => mirror_entries.push(CrateEntry {
                         name,
                         vers: version,
                         cksum: None,
                         yanked: None,
-                    });
+                        extra: Default::default(),
+                    }),
+                    Err(e) => eprintln!("Skipping vendored crate {}: {e}", path.display()),
                 }
             }
         }
     }
 }
 
+/// Find the Cargo.lock files in `path`, or `path` itself if it's a file.
+///
+/// Build output and hidden directories, such as target/ and .git/, are skipped.
+fn find_cargo_locks(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+    walkdir::WalkDir::new(path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name == "target" || name.starts_with('.'))
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && entry.file_name() == "Cargo.lock")
+        .map(walkdir::DirEntry::into_path)
+        .collect()
+}
+
+/// Add the crates.io packages of Cargo.lock files to `mirror_entries`, listing packages shared
+/// by several of them once. Each path is either a Cargo.lock file, or a directory to look for
+/// them in.
 pub(crate) fn cargo_lock_to_mirror_entries(
     mirror_entries: &mut Vec<CrateEntry>,
-    cargo_lock_filepath: Option<&PathBuf>,
+    cargo_lock_paths: &[PathBuf],
 ) {
-    if let Some(cargo_lock_filepath) = &cargo_lock_filepath {
-        if cargo_lock_filepath.is_file() {
-            let s = fs::read_to_string(cargo_lock_filepath).unwrap();
-            let cargo_lock = s.parse::<toml_edit::easy::Value>().unwrap();
-            if let toml_edit::easy::Value::Table(global) = cargo_lock {
-                let packages_array = &global["package"];
-
-                if let toml_edit::easy::Value::Array(packages) = packages_array {
-                    packages.iter().for_each(|package| {
-                        if let toml_edit::easy::Value::Table(package) = package {
-                            // filter out non crates-io crates
-                            if let Some(source) = package.get("source") {
-                                let source = source.to_string().replace('\"', "");
-                                if source.contains(
-                                    "registry+https://github.com/rust-lang/crates.io-index",
-                                ) {
-                                    let name = package["name"].to_string().replace('\"', "");
-                                    let version = package["version"].to_string().replace('\"', "");
-                                    let checksum =
-                                        package["checksum"].to_string().replace('\"', "");
-                                    mirror_entries.push(CrateEntry {
-                                        name,
-                                        vers: version,
-                                        cksum: Some(checksum),
-                                        yanked: None,
-                                    });
-                                }
-                            }
-                        }
-                    });
-                }
-            }
-        } else {
+    let mut seen = HashSet::new();
+    let mut lock_entries = vec![];
+    for cargo_lock_filepath in cargo_lock_paths.iter().flat_map(|p| find_cargo_locks(p)) {
+        if !cargo_lock_filepath.is_file() {
             eprintln!("{:?} is not a Cargo.lock!", cargo_lock_filepath);
+            continue;
         }
+        match read_cargo_lock(&cargo_lock_filepath) {
+            Ok(entries) => lock_entries.extend(entries),
+            Err(e) => eprintln!("Skipping {}: {e}", cargo_lock_filepath.display()),
+        }
+    }
+    mirror_entries.extend(
+        lock_entries
+            .into_iter()
+            .filter(|c| seen.insert((c.name.clone(), c.vers.clone()))),
+    );
+}
+
+/// The parts of a Cargo.lock used to find its crates in the index.
+#[derive(Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<LockPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    checksum: Option<String>,
+}
+
+/// Read the crates.io crates of a Cargo.lock.
+fn read_cargo_lock(cargo_lock_filepath: &Path) -> Result<Vec<CrateEntry>, String> {
+    let s = fs::read_to_string(cargo_lock_filepath).map_err(|e| e.to_string())?;
+    let cargo_lock: CargoLock = toml_edit::easy::from_str(&s).map_err(|e| e.to_string())?;
+    Ok(cargo_lock
+        .package
+        .into_iter()
+        // filter out non crates-io crates
+        .filter(|package| {
+            package.source.as_deref().is_some_and(|s| {
+                s.contains("registry+https://github.com/rust-lang/crates.io-index")
+            })
+        })
+        .map(|package| CrateEntry {
+            name: package.name,
+            vers: package.version,
+            cksum: package.checksum,
+            yanked: None,
+            extra: Default::default(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        cargo_lock_to_mirror_entries, crate_checksum_matches, crate_url, crates_source,
+        find_crate_file, get_crate_path, is_crate_index_path, is_index_path_of, is_index_synced,
+        log_skipped_crates, lowercase_crate_dirs, order_by_popularity, parse_floor,
+        parse_index_file, prune_crates_files, read_crate_sha256, read_index_entry,
+        read_popularity_file, vendor_path_to_mirror_entries, walk_crate_files, write_catalog,
+        CrateEntry, CratePin, SkipReason, SkippedCrate, VersionFloor,
+    };
+    use crate::crates_index::UPSTREAM_REF;
+    use crate::download::append_to_path;
+    use git2::Repository;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn crate_index_paths() {
+        for p in [
+            "1/u",
+            "2/bm",
+            "3/c/cde",
+            "se/rd/serde",
+            "ti/ny/tiny_http",
+            "ab/c-/abc-d",
+        ] {
+            assert!(is_crate_index_path(Path::new(p)), "{p}");
+        }
+    }
+
+    #[test]
+    fn index_paths_of_crates() {
+        assert!(is_index_path_of(Path::new("se/rd/serde"), "serde"));
+        assert!(is_index_path_of(Path::new("in/fl/inflector"), "Inflector"));
+        assert!(is_index_path_of(Path::new("3/c/cde"), "cde"));
+        assert!(!is_index_path_of(Path::new("se/rd/serde"), "serde_json"));
+        assert!(!is_index_path_of(Path::new("se/rd/serde"), "sedr"));
+        assert!(!is_index_path_of(Path::new("3/c/cde"), "cd"));
+    }
+
+    #[test]
+    fn malformed_index_lines() {
+        let mut data = b"{\"name\":\"serde\",\"vers\":\"1.0.0\",\"cksum\":\"abc\"}\n".to_vec();
+        data.extend(b"{\"name\":\"serde\",\"vers\":\"\xff\"}\n");
+        data.extend(b"not json\n");
+        data.extend(b"{\"name\":\"serde\",\"vers\":\"1.0.1\",\"cksum\":\"def\"}\n");
+        let entries = parse_index_file(
+            Path::new("se/rd/serde"),
+            &data,
+            None,
+            &[],
+            &VersionFloor::default(),
+        );
+        let versions: Vec<_> = entries.changed.iter().map(|c| c.vers.as_str()).collect();
+        assert_eq!(versions, ["1.0.0", "1.0.1"]);
+        assert_eq!(entries.malformed, 2);
+    }
+
+    #[test]
+    fn non_crate_index_paths() {
+        for p in [
+            "config.json",
+            "README.md",
+            ".gitignore",
+            ".github/workflows/ci.yml",
+            "1/ab",
+            "3/d/cde",
+            "se/rd/Serde",
+            "se/rd/serde.json",
+            "xx/se/rd/serde",
+        ] {
+            assert!(!is_crate_index_path(Path::new(p)), "{p}");
+        }
+    }
+
+    #[test]
+    fn crate_pins() {
+        let pin = CratePin::parse("tokio@1.*").unwrap();
+        assert!(pin.matches("tokio", "1.25.0"));
+        assert!(pin.matches("Tokio", "1.0.0"));
+        assert!(!pin.matches("tokio", "0.2.0"));
+        assert!(!pin.matches("tokio-util", "1.0.0"));
+
+        let pin = CratePin::parse("serde").unwrap();
+        assert!(pin.matches("serde", "1.0.130"));
+
+        let entry = CratePin::parse_set_entry("tokio >=1.2, <2").unwrap();
+        assert!(entry.matches("tokio", "1.25.0"));
+        assert!(!entry.matches("tokio", "1.1.0"));
+        assert!(!entry.matches("tokio", "2.0.0"));
+        let entry = CratePin::parse_set_entry("serde@1.0.130").unwrap();
+        assert!(entry.matches("serde", "1.0.130"));
+        assert!(!entry.matches("serde", "1.0.131"));
+        assert!(CratePin::parse_set_entry("serde@not a version").is_err());
+
+        assert!(CratePin::parse("@1.0.0").is_err());
+        assert!(CratePin::parse("serde@[").is_err());
+    }
+
+    #[test]
+    fn version_floors() {
+        let floor = VersionFloor {
+            global: Some(parse_floor("0.2").unwrap()),
+            per_crate: [("tokio".to_string(), parse_floor(">=1.0").unwrap())].into(),
+        };
+        assert!(floor.excludes("serde", "0.1.9"));
+        assert!(!floor.excludes("serde", "0.2.0"));
+        assert!(floor.excludes("Tokio", "0.3.0"));
+        assert!(!floor.excludes("tokio", "1.0.0-beta.1"));
+        // Versions that aren't semver are kept.
+        assert!(!floor.excludes("serde", "0.1"));
+
+        assert!(!VersionFloor::default().excludes("serde", "0.0.1"));
+        assert!(parse_floor("not a version").is_err());
+    }
+
+    #[test]
+    fn download_urls() {
+        let default: crate::mirror::ConfigCrates =
+            toml_edit::easy::from_str::<crate::mirror::Config>(include_str!("mirror.default.toml"))
+                .unwrap()
+                .crates
+                .unwrap();
+        let entry: CrateEntry = serde_json::from_str(r#"{"name":"serde","vers":"1.0.0"}"#).unwrap();
+        let url = |crates: &crate::mirror::ConfigCrates| {
+            crate_url(crates_source(crates).as_deref(), &entry)
+        };
+
+        assert_eq!(
+            url(&default),
+            "https://static.crates.io/crates/serde/serde-1.0.0.crate"
+        );
+        let custom = crate::mirror::ConfigCrates {
+            source: "https://registry.internal/api/v1/crates/".to_string(),
+            ..default
+        };
+        assert_eq!(
+            url(&custom),
+            "https://registry.internal/api/v1/crates/serde/1.0.0/download"
+        );
+        let templated = crate::mirror::ConfigCrates {
+            download_url_template: Some(
+                "{source}/dl/{prefix}/{name}/{name}-{version}.crate".to_string(),
+            ),
+            ..custom
+        };
+        assert_eq!(
+            url(&templated),
+            "https://registry.internal/api/v1/crates/dl/se/rd/serde/serde-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn index_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        std::fs::create_dir_all(path.join("cf/g-")).unwrap();
+        std::fs::write(
+            path.join("cf/g-/cfg-if"),
+            "{\"name\":\"cfg-if\",\"vers\":\"0.1.10\",\"cksum\":\"abc\"}\n{\"name\":\"cfg-if\",\"vers\":\"1.0.0\",\"cksum\":\"def\"}\n",
+        )
+        .unwrap();
+
+        let entry = read_index_entry(path, "CFG-if", "1.0.0").unwrap();
+        assert_eq!(entry.get_cksum(), Some("def"));
+        assert!(read_index_entry(path, "cfg-if", "2.0.0").is_none());
+        assert!(read_index_entry(path, "cfg_if", "1.0.0").is_none());
+        assert!(read_index_entry(path, "../cfg-if", "1.0.0").is_none());
+    }
+
+    #[test]
+    fn skip_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("skipped.log");
+        let skipped = |reason| SkippedCrate {
+            name: "glib-2-0-sys".to_string(),
+            vers: "0.0.1".to_string(),
+            reason,
+        };
+        log_skipped_crates(&[skipped(SkipReason::Known403)], Some(&path)).unwrap();
+        log_skipped_crates(&[], Some(&path)).unwrap();
+        log_skipped_crates(&[skipped(SkipReason::NotFound(404))], Some(&path)).unwrap();
+
+        let report = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = report
+            .lines()
+            .map(|line| line.split_once('\t').unwrap().1)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "glib-2-0-sys@0.0.1\tknown 403",
+                "glib-2-0-sys@0.0.1\tnot found (404)"
+            ]
+        );
+    }
+
+    #[test]
+    fn catalog() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        std::fs::create_dir_all(path.join("cf/g-")).unwrap();
+        std::fs::write(path.join("config.json"), "{\"dl\":\"http://localhost\"}\n").unwrap();
+        std::fs::write(
+            path.join("cf/g-/cfg-if"),
+            "{\"name\":\"cfg-if\",\"vers\":\"1.0.0\",\"cksum\":\"def\"}\n",
+        )
+        .unwrap();
+
+        // Without a repository, the index files on disk are read.
+        let mut out = vec![];
+        assert_eq!(write_catalog(path, &mut out).unwrap(), 1);
+        let entry: CrateEntry = serde_json::from_slice(&out).unwrap();
+        assert_eq!(entry.get_cksum(), Some("def"));
+
+        // With one, only what's committed to master counts.
+        let repo = Repository::init(path).unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Panamax", "panamax@panamax").unwrap();
+        repo.commit(
+            Some("refs/heads/master"),
+            &signature,
+            &signature,
+            "index",
+            &tree,
+            &[],
+        )
+        .unwrap();
+        std::fs::write(path.join("cf/g-/cfg-if"), "").unwrap();
+        let mut out = vec![];
+        assert_eq!(write_catalog(path, &mut out).unwrap(), 1);
+        assert!(out.ends_with(b"\n"));
+    }
+
+    #[test]
+    fn index_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let repo = Repository::init(path).unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let signature = git2::Signature::now("Panamax", "panamax@panamax").unwrap();
+        repo.commit(
+            Some("refs/heads/master"),
+            &signature,
+            &signature,
+            "index",
+            &tree,
+            &[],
+        )
+        .unwrap();
+
+        // A sync has nothing to do until origin/master moves past master.
+        assert!(!is_index_synced(&repo));
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+        repo.reference("refs/remotes/origin/master", master, true, "fetch")
+            .unwrap();
+        assert!(is_index_synced(&repo));
+        let head = repo.find_commit(master).unwrap();
+        repo.commit(
+            Some("refs/heads/master"),
+            &signature,
+            &signature,
+            "Rewrite config.json",
+            &tree,
+            &[&head],
+        )
+        .unwrap();
+        assert!(is_index_synced(&repo));
+        let next = repo
+            .commit(None, &signature, &signature, "next", &tree, &[&head])
+            .unwrap();
+        repo.reference("refs/remotes/origin/master", next, true, "fetch")
+            .unwrap();
+        assert!(!is_index_synced(&repo));
+
+        // Once recorded, the upstream commit is compared instead of master.
+        repo.reference(UPSTREAM_REF, next, true, "sync").unwrap();
+        assert!(is_index_synced(&repo));
+    }
+
+    #[test]
+    fn vendor_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        for (dir, manifest) in [
+            ("serde", "[package]\nname = \"serde\"\nversion = \"1.0.0\"\n"),
+            (
+                "log",
+                "[package]\nname = 'log' # comment\nversion = '0.4.20'\n[dependencies]\nserde = { version = \"1\" }\n",
+            ),
+            ("member", "[package]\nname = \"member\"\nversion.workspace = true\n"),
+            ("virtual", "[workspace]\nmembers = []\n"),
+        ] {
+            std::fs::create_dir_all(path.join(dir)).unwrap();
+            std::fs::write(path.join(dir).join("Cargo.toml"), manifest).unwrap();
+        }
+
+        let mut entries = vec![];
+        vendor_path_to_mirror_entries(&mut entries, Some(&path));
+        let mut entries: Vec<_> = entries.into_iter().map(|c| (c.name, c.vers)).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                ("log".to_string(), "0.4.20".to_string()),
+                ("serde".to_string(), "1.0.0".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn cargo_lock_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let package = |name: &str| {
+            format!(
+                "[[package]]\nname = \"{name}\"\nversion = \"1.0.0\"\nsource = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"abc\"\n"
+            )
+        };
+        for (dir, packages) in [
+            ("app", [package("serde"), package("log")]),
+            ("tools/cli", [package("serde"), package("clap")]),
+            ("app/target/package", [package("rand"), package("rand")]),
+        ] {
+            std::fs::create_dir_all(path.join(dir)).unwrap();
+            std::fs::write(path.join(dir).join("Cargo.lock"), packages.concat()).unwrap();
+        }
+
+        let names = |paths: &[std::path::PathBuf]| {
+            let mut entries = vec![];
+            cargo_lock_to_mirror_entries(&mut entries, paths);
+            entries.into_iter().map(|c| c.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names(std::slice::from_ref(&path)), ["serde", "log", "clap"]);
+        assert_eq!(
+            names(&[path.join("tools/cli/Cargo.lock"), path.join("app")]),
+            ["serde", "clap", "log"]
+        );
+
+        // A broken Cargo.lock is skipped, rather than stopping the sync.
+        std::fs::create_dir_all(path.join("broken")).unwrap();
+        std::fs::write(path.join("broken/Cargo.lock"), "[[package]\nname = ").unwrap();
+        assert_eq!(names(std::slice::from_ref(&path)), ["serde", "log", "clap"]);
+    }
+
+    #[test]
+    fn popularity_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("popularity.csv");
+        std::fs::write(&path, "# crate,rank\nserde,1\nTokio 2\n\nrand\n").unwrap();
+        let ranks = read_popularity_file(&path).unwrap();
+        assert_eq!(ranks["tokio"], 2);
+        assert_eq!(ranks["rand"], 5);
+        std::fs::write(&path, "serde,first\n").unwrap();
+        assert!(read_popularity_file(&path).is_err());
+
+        let entry = |name: &str, vers: &str| {
+            serde_json::from_str::<CrateEntry>(&format!(
+                "{{\"name\":\"{name}\",\"vers\":\"{vers}\"}}"
+            ))
+            .unwrap()
+        };
+        let mut crates = [
+            entry("libc", "0.2.0"),
+            entry("rand", "0.8.0"),
+            entry("zzz", "0.1.0"),
+            entry("tokio", "1.0.0"),
+            entry("serde", "1.0.0"),
+            entry("tokio", "1.0.1"),
+        ];
+        order_by_popularity(&mut crates, &ranks);
+        let order: Vec<_> = crates
+            .iter()
+            .map(|c| (c.get_name(), c.get_vers()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("serde", "1.0.0"),
+                ("tokio", "1.0.0"),
+                ("tokio", "1.0.1"),
+                ("rand", "0.8.0"),
+                ("libc", "0.2.0"),
+                ("zzz", "0.1.0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_keeps_latest_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let crates = [
+            ("serde", "1.0.0"),
+            ("serde", "1.0.10"),
+            ("serde", "1.0.9"),
+            ("rand", "0.8.0"),
+        ];
+        for (name, version) in crates {
+            let crate_path = get_crate_path(path, name, version).unwrap();
+            std::fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+            std::fs::write(crate_path, [0u8; 10]).unwrap();
+        }
+
+        let pins = [CratePin::parse("serde@1.0.9").unwrap()];
+        let freed = prune_crates_files(path, 25, None, None, &pins, String::new()).unwrap();
+        assert_eq!(freed, 10);
+        assert!(!get_crate_path(path, "serde", "1.0.0").unwrap().exists());
+        assert!(get_crate_path(path, "serde", "1.0.9").unwrap().exists());
+
+        let freed = prune_crates_files(path, 25, None, None, &[], String::new()).unwrap();
+        assert_eq!(freed, 10);
+        assert!(!get_crate_path(path, "serde", "1.0.9").unwrap().exists());
+        assert!(get_crate_path(path, "serde", "1.0.10").unwrap().exists());
+        assert!(get_crate_path(path, "rand", "0.8.0").unwrap().exists());
+    }
+
+    #[test]
+    fn crate_entry_keeps_unknown_fields() {
+        let line = r#"{"name":"serde","vers":"1.0.200","deps":[],"cksum":"ab","features":{},"features2":{"derive":["dep:serde_derive"]},"yanked":false,"rust_version":"1.31","v":2}"#;
+        let entry: CrateEntry = serde_json::from_str(line).unwrap();
+        assert_eq!(entry.get_name(), "serde");
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            serde_json::from_str::<serde_json::Value>(line).unwrap()
+        );
+
+        // Missing optional fields stay missing.
+        let line = r#"{"name":"serde","vers":"1.0.0"}"#;
+        let entry: CrateEntry = serde_json::from_str(line).unwrap();
+        assert_eq!(serde_json::to_string(&entry).unwrap(), line);
+    }
+
+    #[test]
+    fn walk_finds_crate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        assert!(walk_crate_files(&path.join("crates")).unwrap().is_empty());
+
+        let crates = [("a", "0.1.0"), ("cfg", "1.0.0"), ("Inflector", "0.11.4")];
+        for (name, version) in crates {
+            let crate_path = get_crate_path(path, name, version).unwrap();
+            std::fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+            std::fs::write(&crate_path, [0u8; 10]).unwrap();
+            std::fs::write(append_to_path(&crate_path, ".sha256"), "").unwrap();
+        }
+
+        let mut files = walk_crate_files(&path.join("crates")).unwrap();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let found: Vec<_> = files
+            .iter()
+            .map(|f| (f.name.as_str(), f.version.as_str(), f.len))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("a", "0.1.0", 10),
+                ("cfg", "1.0.0", 10),
+                ("inflector", "0.11.4", 10)
+            ]
+        );
+        assert_eq!(
+            files[2].path,
+            get_crate_path(path, "Inflector", "0.11.4").unwrap()
+        );
+    }
+
+    #[test]
+    fn mixed_case_crate_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+
+        let crate_path = get_crate_path(path, "Inflector", "0.11.4").unwrap();
+        assert_eq!(
+            crate_path,
+            path.join("crates/in/fl/inflector/0.11.4/Inflector-0.11.4.crate")
+        );
+        assert_eq!(find_crate_file(path, "Inflector", "0.11.4"), None);
+
+        std::fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+        std::fs::write(&crate_path, []).unwrap();
+        assert_eq!(
+            find_crate_file(path, "inflector", "0.11.4"),
+            Some(crate_path.clone())
+        );
+        std::fs::remove_file(&crate_path).unwrap();
+
+        // Crates stored with the original casing are still found.
+        let legacy_path = path.join("crates/In/fl/Inflector/0.11.4/Inflector-0.11.4.crate");
+        std::fs::create_dir_all(legacy_path.parent().unwrap()).unwrap();
+        std::fs::write(&legacy_path, []).unwrap();
+        assert_eq!(
+            find_crate_file(path, "Inflector", "0.11.4"),
+            Some(legacy_path.clone())
+        );
+
+        // Migrating moves them to the lowercased directories, with their other files.
+        let legacy_sha256 = append_to_path(&legacy_path, ".sha256");
+        std::fs::write(&legacy_sha256, "00").unwrap();
+        let other_legacy_path = path.join("crates/In/fl/Inflector/0.11.3/Inflector-0.11.3.crate");
+        std::fs::create_dir_all(other_legacy_path.parent().unwrap()).unwrap();
+        std::fs::write(&other_legacy_path, []).unwrap();
+        assert_eq!(lowercase_crate_dirs(path).unwrap(), 2);
+        assert_eq!(
+            find_crate_file(path, "Inflector", "0.11.4"),
+            Some(crate_path.clone())
+        );
+        assert!(append_to_path(&crate_path, ".sha256").exists());
+        assert!(get_crate_path(path, "Inflector", "0.11.3")
+            .unwrap()
+            .exists());
+        assert!(!path.join("crates/In").exists());
+        // Static servers find them from the crate's own name, as in config.json.
+        #[cfg(unix)]
+        assert!(path
+            .join("crates/in/fl/Inflector/0.11.4/Inflector-0.11.4.crate")
+            .exists());
+        assert_eq!(lowercase_crate_dirs(path).unwrap(), 0);
+    }
+
+    #[test]
+    fn crate_sha256_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+
+        // SHA-256 of an empty file.
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let crate_path = path.join("empty-0.1.0.crate");
+        std::fs::write(&crate_path, []).unwrap();
+        assert_eq!(read_crate_sha256(&crate_path), None);
+        assert!(crate_checksum_matches(&crate_path, empty).unwrap());
+
+        // An up-to-date .sha256 file is trusted without re-hashing.
+        let sha256_path = append_to_path(&crate_path, ".sha256");
+        std::fs::write(&sha256_path, "0000  empty-0.1.0.crate\n").unwrap();
+        assert_eq!(read_crate_sha256(&crate_path).as_deref(), Some("0000"));
+        assert!(!crate_checksum_matches(&crate_path, empty).unwrap());
+
+        // A .sha256 file older than its crate is ignored.
+        let old = std::time::SystemTime::now() - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&sha256_path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        assert_eq!(read_crate_sha256(&crate_path), None);
+        assert!(crate_checksum_matches(&crate_path, empty).unwrap());
     }
 }
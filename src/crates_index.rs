@@ -1,15 +1,28 @@
+use flate2::read::GzDecoder;
 use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
-use serde::Serialize;
-use std::{io, num::TryFromIntError, path::Path, time::Duration};
+use reqwest::{header::HeaderValue, Client};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs, io,
+    num::TryFromIntError,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use git2::{
     build::{CheckoutBuilder, RepoBuilder},
-    FetchOptions, RemoteCallbacks, Repository, Signature,
+    AutotagOption, ErrorClass, ErrorCode, FetchOptions, RemoteCallbacks, Repository, Signature,
 };
 use thiserror::Error;
+use tokio::sync::RwLock;
+use url::Url;
 
+use crate::crates::{index_file_path, pruned_key, read_pruned, CrateEntry};
+use crate::credentials::{Credentials, GitAttempts};
+use crate::download::{download, DownloadError, DownloadOptions};
 use crate::mirror::ConfigCrates;
-use crate::progress_bar::padded_prefix_message;
+use crate::progress_bar::progress_spinner;
 
 #[derive(Error, Debug)]
 pub enum IndexSyncError {
@@ -24,12 +37,43 @@ pub enum IndexSyncError {
 
     #[error("Number conversion error: {0}")]
     IntegerConversionError(#[from] TryFromIntError),
+
+    #[error("Download error: {0}")]
+    Download(#[from] DownloadError),
+
+    #[error("Bad index snapshot: {0}")]
+    BadSnapshot(String),
+
+    #[error("{0} is not empty, so it can't be replaced by the index snapshot. Move it away, or remove index_snapshot_url to fetch into it.")]
+    IndexNotEmpty(PathBuf),
+
+    #[error("source_index {0} does not exist")]
+    SourceNotFound(String),
+
+    #[error("The crates.io-index repository is corrupt ({0}). Sync again with --reclone-index to delete and re-clone it.")]
+    CorruptIndex(git2::Error),
+
+    #[error("Invalid base_url \"{url}\": {reason}")]
+    BadBaseUrl { url: String, reason: String },
+
+    #[error("Fetching crates.io-index failed after {attempts} attempts, which looks like a network problem: {source}")]
+    Network {
+        attempts: usize,
+        source: git2::Error,
+    },
 }
 
-#[derive(Debug, Serialize)]
-struct ConfigJson {
-    dl: String,
-    api: String,
+/// Held for writing while the crates.io-index working tree is being changed,
+/// and for reading while a git client is being served from it.
+///
+/// This only coordinates sync and serve within one process, as in `panamax run`.
+pub static INDEX_LOCK: RwLock<()> = RwLock::const_new(());
+
+/// The crates.io-index config.json, which tells cargo where to download crates from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigJson {
+    pub dl: String,
+    pub api: String,
 }
 
 /// Synchronize the crates.io-index repository.
@@ -37,11 +81,39 @@ struct ConfigJson {
 /// `mirror_path`: Root path to the mirror directory.
 ///
 /// `crates`: The crates section of the `mirror.toml` config file.
-pub fn sync_crates_repo(mirror_path: &Path, crates: &ConfigCrates) -> Result<(), IndexSyncError> {
+///
+/// `reclone`: Delete and re-clone the repository if it is corrupt, instead of failing.
+///
+/// `retries`: Number of times a failed clone or fetch is retried, if it failed because of the network.
+///
+/// `prefix`: Progress bar prefix for this step.
+pub fn sync_crates_repo(
+    mirror_path: &Path,
+    crates: &ConfigCrates,
+    reclone: bool,
+    retries: usize,
+    prefix: String,
+) -> Result<(), IndexSyncError> {
     let repo_path = mirror_path.join("crates.io-index");
 
-    let prefix = padded_prefix_message(1, 3, "Fetching crates.io-index");
-    let pb = ProgressBar::new_spinner()
+    // A corrupt repository is only replaced once the new clone is complete,
+    // as it may be served in the meantime.
+    let mut reclone_path = None;
+    if repo_path.join(".git").exists() {
+        if let Err(e) = check_repository(&repo_path) {
+            if !reclone {
+                return Err(IndexSyncError::CorruptIndex(e));
+            }
+            eprintln!("The crates.io-index repository is corrupt ({e}), re-cloning it.");
+            let path = mirror_path.join("crates.io-index.reclone");
+            if path.exists() {
+                fs::remove_dir_all(&path)?;
+            }
+            reclone_path = Some(path);
+        }
+    }
+
+    let pb = progress_spinner()
         .with_style(
             ProgressStyle::default_bar()
                 .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
@@ -54,10 +126,59 @@ pub fn sync_crates_repo(mirror_path: &Path, crates: &ConfigCrates) -> Result<(),
     // updating the progress bar.
     pb.enable_steady_tick(Duration::from_millis(10));
 
+    let source_index = resolve_source_index(&crates.source_index)?;
+    let credentials = Credentials::from_config(mirror_path, crates)?;
+
+    if reclone_path.is_some() || !repo_path.join(".git").exists() {
+        let clone_path = reclone_path.as_deref().unwrap_or(&repo_path);
+        // A failed clone cleans up after itself, so it can be retried from scratch.
+        with_retries(retries, &pb, || {
+            clone_repository(fetch_options(&credentials), &source_index, clone_path)
+        })?;
+        // Remove master in order to ensure full scan is performed
+        let repo = Repository::open(clone_path)?;
+        repo.find_reference("refs/heads/master")?.delete()?;
+
+        if let Some(reclone_path) = &reclone_path {
+            let corrupt_path = mirror_path.join("crates.io-index.corrupt");
+            if corrupt_path.exists() {
+                fs::remove_dir_all(&corrupt_path)?;
+            }
+            fs::rename(&repo_path, &corrupt_path)?;
+            fs::rename(reclone_path, &repo_path)?;
+            fs::remove_dir_all(&corrupt_path)?;
+        }
+    } else {
+        // Get (fetch) the branch's latest remote "master" commit
+        let repo = Repository::open(&repo_path)?;
+        // Follow changes to source_index since the repository was cloned.
+        if repo.find_remote("origin")?.url() != Some(source_index.as_str()) {
+            repo.remote_set_url("origin", &source_index)?;
+        }
+        let mut remote = repo.find_remote("origin")?;
+        with_retries(retries, &pb, || {
+            Ok(remote.fetch(&["master"], Some(&mut fetch_options(&credentials)), None)?)
+        })
+        .map_err(|e| match e {
+            IndexSyncError::GitError(e) if is_corruption(&e) => IndexSyncError::CorruptIndex(e),
+            e => e,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Options for cloning and fetching crates.io-index, logging in with `credentials`.
+fn fetch_options<'a>(credentials: &Credentials) -> FetchOptions<'a> {
     // Libgit2 has callbacks that allow us to update the progress bar
     // as the git download progresses.
     // FIXME: Enabling progress updates causes checkout times to balloon.
-    let remote_callbacks = RemoteCallbacks::new();
+    let mut remote_callbacks = RemoteCallbacks::new();
+    let credentials = credentials.clone();
+    let mut attempts = GitAttempts::default();
+    remote_callbacks.credentials(move |url, username, allowed| {
+        credentials.git_cred(url, username, allowed, &mut attempts)
+    });
     /*
     remote_callbacks.transfer_progress(|p| {
         if p.received_objects() == p.total_objects() {
@@ -78,23 +199,247 @@ pub fn sync_crates_repo(mirror_path: &Path, crates: &ConfigCrates) -> Result<(),
     let mut fetch_opts = FetchOptions::new();
     fetch_opts.remote_callbacks(remote_callbacks);
     fetch_opts.proxy_options(proxy_opts);
+    // Only the master branch is used, so skip negotiating tags and writing FETCH_HEAD.
+    fetch_opts.download_tags(AutotagOption::None);
+    fetch_opts.update_fetchhead(false);
+    fetch_opts
+}
+
+/// Time to wait before retrying a failed clone or fetch.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Check if a git error was caused by the network, and may go away if retried.
+fn is_network_error(e: &git2::Error) -> bool {
+    matches!(
+        e.class(),
+        ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssl | ErrorClass::Ssh | ErrorClass::Os
+    ) && e.code() != ErrorCode::Auth
+        && e.code() != ErrorCode::Certificate
+}
+
+/// Check if a git error was caused by a broken local repository.
+fn is_corruption(e: &git2::Error) -> bool {
+    matches!(
+        e.class(),
+        ErrorClass::Odb
+            | ErrorClass::Object
+            | ErrorClass::Index
+            | ErrorClass::Reference
+            | ErrorClass::Repository
+            | ErrorClass::Zlib
+    )
+}
+
+/// Run a git network operation, retrying it up to `retries` times if it fails because
+/// of the network.
+fn with_retries<T>(
+    retries: usize,
+    pb: &ProgressBar,
+    mut f: impl FnMut() -> Result<T, IndexSyncError>,
+) -> Result<T, IndexSyncError> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match f() {
+            Err(IndexSyncError::GitError(e)) if is_network_error(&e) => {
+                if attempts > retries {
+                    return Err(IndexSyncError::Network {
+                        attempts,
+                        source: e,
+                    });
+                }
+                pb.println(format!(
+                    "Fetching crates.io-index failed ({e}), retrying..."
+                ));
+                std::thread::sleep(RETRY_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Reference that `sync --dry-run` fetches the index into, leaving origin/master untouched.
+pub const DRY_RUN_REF: &str = "refs/panamax/dry-run";
 
+/// Reference to the upstream commit master was last fast-forwarded to, below the commits
+/// panamax adds on top of it.
+pub const UPSTREAM_REF: &str = "refs/panamax/upstream";
+
+/// Fetch the master branch of `source_index` into `DRY_RUN_REF`, without fast-forwarding.
+///
+/// Returns false if crates.io-index hasn't been cloned yet, as there is nothing to fetch into.
+pub fn fetch_for_dry_run(
+    mirror_path: &Path,
+    crates: &ConfigCrates,
+) -> Result<bool, IndexSyncError> {
+    let repo_path = mirror_path.join("crates.io-index");
     if !repo_path.join(".git").exists() {
-        clone_repository(fetch_opts, &crates.source_index, &repo_path)?;
-        // Remove master in order to ensure full scan is performed
-        let repo = Repository::open(&repo_path)?;
-        repo.find_reference("refs/heads/master")?.delete()?;
+        return Ok(false);
+    }
+
+    let mut fetch_opts = fetch_options(&Credentials::from_config(mirror_path, crates)?);
+
+    // An anonymous remote has no fetch refspec of its own, so only DRY_RUN_REF is updated.
+    let repo = Repository::open(&repo_path)?;
+    let source_index = resolve_source_index(&crates.source_index)?;
+    let mut remote = repo.remote_anonymous(&source_index)?;
+    remote.fetch(
+        &[&format!("+refs/heads/master:{DRY_RUN_REF}")],
+        Some(&mut fetch_opts),
+        None,
+    )?;
+
+    Ok(true)
+}
+
+/// Check if `path` is missing, empty, or only holds a repository without any commits,
+/// so that replacing it loses nothing.
+fn is_replaceable(path: &Path) -> Result<bool, IndexSyncError> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    let entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(match entries.as_slice() {
+        [] => true,
+        [name] if name == ".git" => Repository::open(path)
+            .and_then(|repo| repo.is_empty())
+            .unwrap_or(false),
+        _ => false,
+    })
+}
+
+/// Bootstrap crates.io-index from a snapshot tarball, instead of cloning its full history.
+///
+/// The tarball (.tar.gz) must contain the index as a non-shallow git repository, either at
+/// its root or within a single top-level directory. Its origin is pointed at `source_index`,
+/// so the following fetch only needs the commits made since the snapshot.
+pub async fn bootstrap_from_snapshot(
+    mirror_path: &Path,
+    crates: &ConfigCrates,
+    snapshot_url: &str,
+    options: &DownloadOptions,
+    user_agent: &HeaderValue,
+) -> Result<(), IndexSyncError> {
+    let repo_path = mirror_path.join("crates.io-index");
+    let tarball_path = mirror_path.join("crates.io-index.snapshot.tar.gz");
+    let extract_path = mirror_path.join("crates.io-index.snapshot");
+
+    if !is_replaceable(&repo_path)? {
+        return Err(IndexSyncError::IndexNotEmpty(repo_path));
+    }
+
+    // Only rustup components are split into parallel_chunks.
+    let options = &DownloadOptions {
+        parallel_chunks: 1,
+        ..options.clone()
+    };
+    download(
+        &Client::new(),
+        snapshot_url,
+        &tarball_path,
+        None,
+        options,
+        true,
+        user_agent,
+    )
+    .await?;
+
+    if extract_path.exists() {
+        fs::remove_dir_all(&extract_path)?;
+    }
+    let (from, to) = (tarball_path.clone(), extract_path.clone());
+    tokio::task::spawn_blocking(move || {
+        tar::Archive::new(GzDecoder::new(fs::File::open(from)?)).unpack(to)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    // Find the repository, which may be nested in a top-level directory.
+    let snapshot_root = if extract_path.join(".git").exists() {
+        extract_path.clone()
     } else {
-        // Get (fetch) the branch's latest remote "master" commit
-        let repo = Repository::open(&repo_path)?;
-        let mut remote = repo.find_remote("origin")?;
-        remote.fetch(&["master"], Some(&mut fetch_opts), None)?;
+        let mut entries = fs::read_dir(&extract_path)?.collect::<Result<Vec<_>, _>>()?;
+        match entries.pop() {
+            Some(entry) if entries.is_empty() && entry.path().join(".git").exists() => entry.path(),
+            _ => {
+                return Err(IndexSyncError::BadSnapshot(
+                    "no git repository found in the tarball".to_string(),
+                ))
+            }
+        }
+    };
+
+    // `init` creates an empty crates.io-index, which is replaced by the snapshot.
+    if repo_path.exists() {
+        fs::remove_dir_all(&repo_path)?;
+    }
+    fs::rename(&snapshot_root, &repo_path)?;
+    if extract_path.exists() {
+        fs::remove_dir_all(&extract_path)?;
+    }
+    fs::remove_file(&tarball_path)?;
+
+    let source_index = resolve_source_index(&crates.source_index)?;
+    let repo = Repository::open(&repo_path)?;
+    if repo.find_remote("origin").is_ok() {
+        repo.remote_set_url("origin", &source_index)?;
+    } else {
+        repo.remote("origin", &source_index)?;
+    }
+
+    // Crates are synced from origin/master, so make sure it exists.
+    if repo.find_reference("refs/remotes/origin/master").is_err() {
+        let snapshot_commit = repo
+            .find_reference("refs/heads/master")
+            .and_then(|r| r.peel_to_commit())
+            .map_err(|_| {
+                IndexSyncError::BadSnapshot("the repository has no master branch".to_string())
+            })?;
+        repo.reference(
+            "refs/remotes/origin/master",
+            snapshot_commit.id(),
+            true,
+            "Index snapshot",
+        )?;
+    }
+
+    // Remove master in order to ensure full scan is performed, as with a fresh clone.
+    for refname in ["refs/heads/master", UPSTREAM_REF] {
+        if let Ok(mut reference) = repo.find_reference(refname) {
+            reference.delete()?;
+        }
     }
 
     Ok(())
 }
 
+/// Check that `base_url` is an absolute http or https URL, and strip any trailing slash.
+pub fn normalize_base_url(base_url: &str) -> Result<String, IndexSyncError> {
+    let bad = |reason: &str| IndexSyncError::BadBaseUrl {
+        url: base_url.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let base_url = base_url.trim();
+    let url = Url::parse(base_url).map_err(|e| bad(&e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(bad("the scheme must be http or https"));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(bad("a host is required"));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(bad("a query or fragment can't be used"));
+    }
+
+    Ok(base_url.trim_end_matches('/').to_string())
+}
+
 /// Update the config.json file within crates-io.index.
+///
+/// Pruned crate versions are also removed from the index.
 pub fn update_crates_config(
     mirror_path: &Path,
     crates: &ConfigCrates,
@@ -102,12 +447,94 @@ pub fn update_crates_config(
     let repo_path = mirror_path.join("crates.io-index");
 
     if let Some(base_url) = &crates.base_url {
-        rewrite_config_json(&repo_path, base_url)?;
+        rewrite_config_json(&repo_path, base_url, crates)?;
+    }
+
+    let removed = remove_index_entries(&repo_path, &read_pruned(mirror_path)?, crates)?;
+    if removed > 0 {
+        eprintln!("Removed {removed} pruned crate versions from crates.io-index.");
     }
 
     Ok(())
 }
 
+/// Remove crate versions from the index, in a commit on top of master.
+///
+/// `versions` are lowercased `name@version` keys, as in the pruned list. Returns the number
+/// of entries removed, which is 0 once master no longer has any of them.
+pub fn remove_index_entries(
+    repo_path: &Path,
+    versions: &HashSet<String>,
+    crates: &ConfigCrates,
+) -> Result<usize, IndexSyncError> {
+    if versions.is_empty() {
+        return Ok(0);
+    }
+    let repo = Repository::open(repo_path)?;
+    let parent_commit = repo.find_reference("refs/heads/master")?.peel_to_commit()?;
+    let tree = parent_commit.tree()?;
+    let names: HashSet<&str> = versions
+        .iter()
+        .filter_map(|key| key.rsplit_once('@'))
+        .map(|(name, _)| name)
+        .collect();
+
+    let mut index = repo.index()?;
+    let mut removed = 0;
+    for name in names {
+        let Some(relative) = index_file_path(name) else {
+            continue;
+        };
+        let Ok(entry) = tree.get_path(&relative) else {
+            continue;
+        };
+        let blob = repo.find_blob(entry.id())?;
+        let mut kept = Vec::with_capacity(blob.content().len());
+        let mut file_removed = 0;
+        for line in blob.content().split_inclusive(|&b| b == b'\n') {
+            let is_removed = serde_json::from_slice::<CrateEntry>(line)
+                .is_ok_and(|c| versions.contains(&pruned_key(c.get_name(), c.get_vers())));
+            if is_removed {
+                file_removed += 1;
+            } else {
+                kept.extend_from_slice(line);
+            }
+        }
+        if file_removed > 0 {
+            fs::write(repo_path.join(&relative), &kept)?;
+            index.add_path(&relative)?;
+            removed += file_removed;
+        }
+    }
+    if removed == 0 {
+        return Ok(0);
+    }
+
+    let oid = index.write_tree()?;
+    index.write()?;
+    let signature = commit_signature(crates)?;
+    repo.commit(
+        Some("refs/heads/master"),
+        &signature,
+        &signature,
+        "Remove pruned crate versions",
+        &repo.find_tree(oid)?,
+        &[&parent_commit],
+    )?;
+    Ok(removed)
+}
+
+/// Author and committer of the commits panamax adds to the index.
+fn commit_signature(crates: &ConfigCrates) -> Result<Signature<'static>, git2::Error> {
+    Signature::now(
+        crates.commit_author_name.as_deref().unwrap_or("Panamax"),
+        crates
+            .commit_author_email
+            .as_deref()
+            .unwrap_or("panamax@panamax"),
+    )
+}
+
 /// Perform a git fast-forward on the repository. This will destroy any local changes that have
 /// been made to the repo, and will make the local master identical to the remote master.
 pub fn fast_forward(repo_path: &Path) -> Result<(), IndexSyncError> {
@@ -128,6 +555,13 @@ pub fn fast_forward(repo_path: &Path) -> Result<(), IndexSyncError> {
         }
     }
 
+    repo.reference(
+        UPSTREAM_REF,
+        fetch_commit.id(),
+        true,
+        "Performing fast-forward",
+    )?;
+
     // Set the "HEAD" reference to our new master commit.
     repo.set_head(refname)?;
 
@@ -139,6 +573,50 @@ pub fn fast_forward(repo_path: &Path) -> Result<(), IndexSyncError> {
     Ok(())
 }
 
+/// Check that an existing crates.io-index repository can be opened and synced from.
+fn check_repository(repo_path: &Path) -> Result<(), git2::Error> {
+    let repo = Repository::open(repo_path)?;
+    repo.find_remote("origin")?;
+    repo.find_reference("refs/remotes/origin/master")?
+        .peel_to_commit()?;
+    match repo.head() {
+        // Master is deleted after cloning, to force a full scan of the index.
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch => {}
+        res => {
+            res?.peel_to_commit()?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolve `source_index` into something libgit2 can clone and fetch from.
+///
+/// Local repositories can be given as a path or a file:// URL. They must exist, and
+/// are made absolute so the origin remote doesn't depend on the working directory.
+fn resolve_source_index(source_index: &str) -> Result<String, IndexSyncError> {
+    let local_path = if source_index.starts_with("file://") {
+        Url::parse(source_index)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+    } else if Path::new(source_index).is_absolute() || !source_index.contains(':') {
+        // Anything else with a colon is a URL, or an scp-like ssh address.
+        Some(PathBuf::from(source_index))
+    } else {
+        None
+    };
+
+    match local_path {
+        Some(local_path) => match local_path.canonicalize() {
+            Ok(local_path) => Ok(local_path.to_string_lossy().into_owned()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(IndexSyncError::SourceNotFound(source_index.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        },
+        None => Ok(source_index.to_string()),
+    }
+}
+
 /// Clone a repository from scratch. This assumes the path does not exist.
 fn clone_repository(
     fetch_opts: FetchOptions,
@@ -152,27 +630,49 @@ fn clone_repository(
 }
 
 /// Fast-forward master, then rewrite the crates.io-index config.json.
-pub fn rewrite_config_json(repo_path: &Path, base_url: &str) -> Result<(), IndexSyncError> {
+///
+/// The commit is authored by `commit_author_name` and `commit_author_email` from `crates`,
+/// or by "Panamax <panamax@panamax>" if those aren't set.
+pub fn rewrite_config_json(
+    repo_path: &Path,
+    base_url: &str,
+    crates: &ConfigCrates,
+) -> Result<(), IndexSyncError> {
     let repo = Repository::open(repo_path)?;
     let refname = "refs/heads/master";
-    let signature = Signature::now("Panamax", "panamax@panamax")?;
-
-    eprintln!("{}", padded_prefix_message(3, 3, "Syncing config"));
+    let signature = commit_signature(crates)?;
 
+    let base_url = normalize_base_url(base_url)?;
     let mut index = repo.index()?;
 
     let crate_path = format!(
         "{}/{}",
-        base_url, "{prefix}/{crate}/{version}/{crate}-{version}.crate"
+        base_url, "{lowerprefix}/{crate}/{version}/{crate}-{version}.crate"
     );
 
     // Create the new config.json.
     let config_json = ConfigJson {
         dl: crate_path,
-        api: base_url.to_string(),
+        api: base_url,
     };
     let contents = serde_json::to_vec_pretty(&config_json)?;
-    std::fs::write(repo_path.join("config.json"), contents)?;
+    let config_path = repo_path.join("config.json");
+    if std::fs::read(&config_path).ok().as_deref() != Some(&contents[..]) {
+        std::fs::write(&config_path, &contents)?;
+    }
+
+    // Committing the same config.json again would only grow the index history.
+    let master = repo.find_reference(refname)?;
+    let parent_commit = master.peel_to_commit()?;
+    let committed = parent_commit
+        .tree()?
+        .get_path(Path::new("config.json"))
+        .ok()
+        .and_then(|entry| repo.find_blob(entry.id()).ok());
+    if committed.is_some_and(|blob| blob.content() == contents) {
+        eprintln!("config.json up to date");
+        return Ok(());
+    }
 
     // Add config.json into the working index.
     // (a.k.a. "git add")
@@ -180,9 +680,6 @@ pub fn rewrite_config_json(repo_path: &Path, base_url: &str) -> Result<(), Index
     let oid = index.write_tree()?;
     index.write()?;
 
-    // Get the master commit's tree.
-    let master = repo.find_reference(refname)?;
-    let parent_commit = master.peel_to_commit()?;
     let tree = repo.find_tree(oid)?;
 
     // Commit this change to the repository.
@@ -197,3 +694,200 @@ pub fn rewrite_config_json(repo_path: &Path, base_url: &str) -> Result<(), Index
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_replaceable, normalize_base_url, remove_index_entries, resolve_source_index,
+        rewrite_config_json, with_retries, IndexSyncError,
+    };
+    use git2::{ErrorClass, ErrorCode, Repository, Signature};
+    use indicatif::ProgressBar;
+    use std::path::Path;
+
+    #[test]
+    fn source_index_urls() {
+        for url in [
+            "https://github.com/rust-lang/crates.io-index",
+            "git@github.com:rust-lang/crates.io-index.git",
+            "ssh://git@example.com/crates.io-index.git",
+        ] {
+            assert_eq!(resolve_source_index(url).unwrap(), url);
+        }
+
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let dir_str = dir.to_string_lossy().into_owned();
+        assert_eq!(resolve_source_index(&dir_str).unwrap(), dir_str);
+        let file_url = url::Url::from_file_path(&dir).unwrap().to_string();
+        assert_eq!(resolve_source_index(&file_url).unwrap(), dir_str);
+
+        let missing = dir
+            .join("panamax-missing-index")
+            .to_string_lossy()
+            .into_owned();
+        assert!(matches!(
+            resolve_source_index(&missing),
+            Err(IndexSyncError::SourceNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn fetch_retries() {
+        let error = |class| {
+            IndexSyncError::GitError(git2::Error::new(ErrorCode::GenericError, class, "failed"))
+        };
+        let pb = ProgressBar::hidden();
+
+        // Network errors are retried.
+        let mut calls = 0;
+        let res = with_retries(1, &pb, || {
+            calls += 1;
+            match calls {
+                1 => Err(error(ErrorClass::Net)),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(res.unwrap(), 2);
+
+        let mut calls = 0;
+        let res: Result<(), _> = with_retries(0, &pb, || {
+            calls += 1;
+            Err(error(ErrorClass::Http))
+        });
+        assert!(matches!(
+            res,
+            Err(IndexSyncError::Network { attempts: 1, .. })
+        ));
+
+        // Anything else fails right away.
+        let mut calls = 0;
+        let res: Result<(), _> = with_retries(3, &pb, || {
+            calls += 1;
+            Err(error(ErrorClass::Odb))
+        });
+        assert!(matches!(res, Err(IndexSyncError::GitError(_))));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn base_urls() {
+        assert_eq!(
+            normalize_base_url("http://panamax.internal/crates/").unwrap(),
+            "http://panamax.internal/crates"
+        );
+        assert_eq!(
+            normalize_base_url("https://10.0.0.1:8443/crates").unwrap(),
+            "https://10.0.0.1:8443/crates"
+        );
+        for url in [
+            "panamax.internal/crates",
+            "ftp://panamax.internal/crates",
+            "http://",
+            "http://panamax.internal/crates?x=1",
+        ] {
+            assert!(
+                matches!(
+                    normalize_base_url(url),
+                    Err(IndexSyncError::BadBaseUrl { .. })
+                ),
+                "{url} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn unchanged_config_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let repo = Repository::init(path).unwrap();
+        let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let crates =
+            toml_edit::easy::from_str::<crate::mirror::Config>(include_str!("mirror.default.toml"))
+                .unwrap()
+                .crates
+                .unwrap();
+        let head = || repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        rewrite_config_json(path, "http://panamax.internal/crates", &crates).unwrap();
+        let rewritten = head();
+        rewrite_config_json(path, "http://panamax.internal/crates", &crates).unwrap();
+        assert_eq!(head(), rewritten);
+        rewrite_config_json(path, "http://panamax.internal/other", &crates).unwrap();
+        assert_ne!(head(), rewritten);
+    }
+
+    #[test]
+    fn replaceable_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crates.io-index");
+        assert!(is_replaceable(&path).unwrap());
+        std::fs::create_dir(&path).unwrap();
+        assert!(is_replaceable(&path).unwrap());
+
+        let repo = Repository::init(&path).unwrap();
+        assert!(is_replaceable(&path).unwrap());
+        std::fs::write(path.join("config.json"), "{}").unwrap();
+        assert!(!is_replaceable(&path).unwrap());
+
+        std::fs::remove_file(path.join("config.json")).unwrap();
+        let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        assert!(!is_replaceable(&path).unwrap());
+    }
+
+    #[test]
+    fn pruned_entries_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let repo = Repository::init(path).unwrap();
+        std::fs::create_dir_all(path.join("se/rd")).unwrap();
+        std::fs::write(
+            path.join("se/rd/serde"),
+            "{\"name\":\"serde\",\"vers\":\"1.0.0\"}\n{\"name\":\"serde\",\"vers\":\"1.0.1\"}\n",
+        )
+        .unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("se/rd/serde")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let crates =
+            toml_edit::easy::from_str::<crate::mirror::Config>(include_str!("mirror.default.toml"))
+                .unwrap()
+                .crates
+                .unwrap();
+        let head = || repo.head().unwrap().peel_to_commit().unwrap();
+
+        let pruned = ["serde@1.0.0".to_string(), "rand@0.8.0".to_string()].into();
+        assert_eq!(remove_index_entries(path, &pruned, &crates).unwrap(), 1);
+        let kept = "{\"name\":\"serde\",\"vers\":\"1.0.1\"}\n";
+        assert_eq!(
+            std::fs::read_to_string(path.join("se/rd/serde")).unwrap(),
+            kept
+        );
+        let committed = head()
+            .tree()
+            .unwrap()
+            .get_path(Path::new("se/rd/serde"))
+            .unwrap();
+        assert_eq!(
+            repo.find_blob(committed.id()).unwrap().content(),
+            kept.as_bytes()
+        );
+
+        // Once removed, there's nothing left to commit.
+        let removed = head().id();
+        assert_eq!(remove_index_entries(path, &pruned, &crates).unwrap(), 0);
+        assert_eq!(head().id(), removed);
+    }
+}
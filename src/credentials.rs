@@ -0,0 +1,342 @@
+//! Credentials for mirroring private registries, used both for crate downloads and
+//! for fetching the index.
+//!
+//! The `auth_token` of `[crates]` is only sent to the hosts of the crates source and index,
+//! while logins from a netrc file are sent to the machines they are listed for.
+//! The index can also be fetched with its own login, or over ssh with a key or ssh-agent.
+
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use git2::{Cred, CredentialType, ErrorClass, ErrorCode};
+use reqwest::RequestBuilder;
+use url::Url;
+
+use crate::mirror::ConfigCrates;
+
+/// A login for one host, from a netrc file.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NetrcLogin {
+    pub machine: String,
+    pub login: String,
+    pub password: String,
+}
+
+/// Parse the `machine` entries of a netrc file.
+///
+/// `default` entries are ignored, so a login is never sent to a host it wasn't listed for,
+/// and so are macros, which can only come last.
+pub fn parse_netrc(data: &str) -> Vec<NetrcLogin> {
+    let mut logins = Vec::new();
+    let mut current: Option<NetrcLogin> = None;
+    let mut tokens = data.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" | "macdef" => {
+                logins.extend(current.take());
+                match token {
+                    "machine" => {
+                        current = tokens.next().map(|machine| NetrcLogin {
+                            machine: machine.to_string(),
+                            login: String::new(),
+                            password: String::new(),
+                        });
+                    }
+                    "macdef" => break,
+                    _ => {}
+                }
+            }
+            "login" | "password" | "account" => {
+                let value = tokens.next().unwrap_or_default().to_string();
+                match (current.as_mut(), token) {
+                    (Some(login), "login") => login.login = value,
+                    (Some(login), "password") => login.password = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    logins.extend(current);
+    logins
+}
+
+/// Credentials to send with requests to private registries.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    /// Sent as a bearer token to `token_hosts`.
+    token: Option<String>,
+    token_hosts: Vec<String>,
+    netrc: Vec<NetrcLogin>,
+    /// Username and password for fetching the index.
+    index_login: Option<(String, String)>,
+    /// Username for fetching the index over ssh, if the URL doesn't have one.
+    index_username: Option<String>,
+    index_ssh_key: Option<PathBuf>,
+    /// Whether an ssh-agent is running, by `SSH_AUTH_SOCK`.
+    ssh_agent: bool,
+}
+
+/// What has been offered to libgit2 so far, as it asks again whenever a login is rejected.
+#[derive(Default)]
+pub struct GitAttempts {
+    login: bool,
+    ssh_key: bool,
+    ssh_agent: bool,
+}
+
+// Credentials must never end up in logs or error messages.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("token_hosts", &self.token_hosts)
+            .field(
+                "netrc_machines",
+                &self.netrc.iter().map(|l| &l.machine).collect::<Vec<_>>(),
+            )
+            .field(
+                "index_login",
+                &self.index_login.as_ref().map(|(username, _)| username),
+            )
+            .field("index_ssh_key", &self.index_ssh_key)
+            .field("ssh_agent", &self.ssh_agent)
+            .finish()
+    }
+}
+
+/// Host of a URL, if it has one.
+fn url_host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+impl Credentials {
+    /// Load the credentials of `[crates]`. The index login falls back to the
+    /// `PANAMAX_INDEX_USERNAME` and `PANAMAX_INDEX_PASSWORD` environment variables.
+    /// Relative paths are from the mirror directory.
+    pub fn from_config(mirror_path: &Path, crates: &ConfigCrates) -> Result<Self, io::Error> {
+        let netrc = match &crates.netrc_file {
+            Some(netrc_file) => {
+                let netrc_path = mirror_path.join(netrc_file);
+                let data = fs::read_to_string(&netrc_path).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("could not read {}: {e}", netrc_path.display()),
+                    )
+                })?;
+                parse_netrc(&data)
+            }
+            None => Vec::new(),
+        };
+        let download_url = crates
+            .download_url_template
+            .as_ref()
+            .map(|template| template.replace("{source}", &crates.source));
+        let token_hosts = [Some(&crates.source), Some(&crates.source_index)]
+            .into_iter()
+            .chain([download_url.as_ref()])
+            .flatten()
+            .filter_map(|url| url_host(url))
+            .collect();
+        let index_username = crates
+            .index_username
+            .clone()
+            .or_else(|| env::var("PANAMAX_INDEX_USERNAME").ok())
+            .filter(|u| !u.is_empty());
+        let index_password = crates
+            .index_password
+            .clone()
+            .or_else(|| env::var("PANAMAX_INDEX_PASSWORD").ok());
+        let index_ssh_key = match &crates.index_ssh_key {
+            Some(key) => {
+                let key = mirror_path.join(key);
+                if !key.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("could not read ssh key {}", key.display()),
+                    ));
+                }
+                Some(key)
+            }
+            None => None,
+        };
+        Ok(Self {
+            token: crates.auth_token.clone().filter(|t| !t.is_empty()),
+            token_hosts,
+            netrc,
+            index_login: index_username.clone().zip(index_password),
+            index_username,
+            index_ssh_key,
+            ssh_agent: env::var_os("SSH_AUTH_SOCK").is_some_and(|s| !s.is_empty()),
+        })
+    }
+
+    fn netrc_login(&self, host: &str) -> Option<&NetrcLogin> {
+        self.netrc.iter().find(|l| l.machine == host)
+    }
+
+    fn token_for(&self, host: &str) -> Option<&str> {
+        self.token
+            .as_deref()
+            .filter(|_| self.token_hosts.iter().any(|h| h == host))
+    }
+
+    /// Add the credentials for `url`'s host to a request, if there are any.
+    pub fn authorize(&self, request: RequestBuilder, url: &str) -> RequestBuilder {
+        let Some(host) = url_host(url) else {
+            return request;
+        };
+        if let Some(login) = self.netrc_login(&host) {
+            request.basic_auth(&login.login, Some(&login.password))
+        } else if let Some(token) = self.token_for(&host) {
+            request.bearer_auth(token)
+        } else {
+            request
+        }
+    }
+
+    /// Username and password to fetch the git repository at `url` with.
+    ///
+    /// The index login comes first. The token is used as the password, which git hosts
+    /// accept along with any username.
+    pub fn git_login(&self, url: &str, username: Option<&str>) -> Option<(String, String)> {
+        if let Some(login) = &self.index_login {
+            return Some(login.clone());
+        }
+        let host = url_host(url)?;
+        if let Some(login) = self.netrc_login(&host) {
+            return Some((login.login.clone(), login.password.clone()));
+        }
+        let token = self.token_for(&host)?;
+        Some((username.unwrap_or("token").to_string(), token.to_string()))
+    }
+
+    /// Credentials to answer libgit2 with when fetching the git repository at `url`, of
+    /// one of the `allowed` types.
+    ///
+    /// Over ssh, the configured key is offered before ssh-agent. Over https, the login
+    /// from [`Credentials::git_login`] is. Each is offered once.
+    pub fn git_cred(
+        &self,
+        url: &str,
+        username: Option<&str>,
+        allowed: CredentialType,
+        attempts: &mut GitAttempts,
+    ) -> Result<Cred, git2::Error> {
+        // ssh URLs without a username, e.g. `ssh://example.com/index`, ask for one first.
+        let ssh_username = username.or(self.index_username.as_deref()).unwrap_or("git");
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(ssh_username);
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if let Some(key) = self.index_ssh_key.as_deref().filter(|_| !attempts.ssh_key) {
+                attempts.ssh_key = true;
+                return Cred::ssh_key(ssh_username, None, key, None);
+            }
+            if self.ssh_agent && !attempts.ssh_agent {
+                attempts.ssh_agent = true;
+                return Cred::ssh_key_from_agent(ssh_username);
+            }
+            return Err(git2::Error::new(
+                ErrorCode::Auth,
+                ErrorClass::Ssh,
+                format!("no ssh key for {url}, set index_ssh_key in [crates] or run ssh-agent"),
+            ));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !attempts.login {
+            if let Some((username, password)) = self.git_login(url, username) {
+                attempts.login = true;
+                return Cred::userpass_plaintext(&username, &password);
+            }
+        }
+        Err(git2::Error::new(
+            ErrorCode::Auth,
+            ErrorClass::Http,
+            format!("no credentials for {url}, set index_password, auth_token or netrc_file in [crates]"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_netrc, Credentials, GitAttempts};
+    use git2::CredentialType;
+
+    #[test]
+    fn netrc_logins() {
+        let logins = parse_netrc(
+            "machine git.example.com login alice password s3cret\n\
+             default login anonymous password guest\n\
+             machine crates.example.com\n  login bob\n  account x\n  password hunter2\n\
+             macdef init\nmachine ignored.example.com login eve password x\n",
+        );
+        let logins: Vec<_> = logins
+            .iter()
+            .map(|l| (l.machine.as_str(), l.login.as_str(), l.password.as_str()))
+            .collect();
+        assert_eq!(
+            logins,
+            [
+                ("git.example.com", "alice", "s3cret"),
+                ("crates.example.com", "bob", "hunter2")
+            ]
+        );
+    }
+
+    #[test]
+    fn scoped_credentials() {
+        let credentials = Credentials {
+            token: Some("t0ken".to_string()),
+            token_hosts: vec!["registry.example.com".to_string()],
+            netrc: parse_netrc("machine git.example.com login alice password s3cret"),
+            ..Default::default()
+        };
+        assert_eq!(
+            credentials.git_login("https://registry.example.com/index", None),
+            Some(("token".to_string(), "t0ken".to_string()))
+        );
+        assert_eq!(
+            credentials.git_login("https://git.example.com/index", Some("git")),
+            Some(("alice".to_string(), "s3cret".to_string()))
+        );
+        assert_eq!(
+            credentials.git_login("https://static.rust-lang.org/dist", None),
+            None
+        );
+        assert!(!format!("{credentials:?}").contains("t0ken"));
+    }
+
+    #[test]
+    fn git_creds_offered_once() {
+        let credentials = Credentials {
+            index_login: Some(("alice".to_string(), "s3cret".to_string())),
+            ssh_agent: true,
+            ..Default::default()
+        };
+        let mut attempts = GitAttempts::default();
+        let url = "https://git.example.com/index";
+        let https = CredentialType::USER_PASS_PLAINTEXT;
+        assert!(credentials
+            .git_cred(url, None, https, &mut attempts)
+            .is_ok());
+        // A rejected login isn't offered again.
+        assert!(credentials
+            .git_cred(url, None, https, &mut attempts)
+            .is_err());
+
+        let url = "ssh://git.example.com/index";
+        let ssh = CredentialType::SSH_KEY;
+        let username = CredentialType::USERNAME;
+        assert!(credentials
+            .git_cred(url, None, username, &mut attempts)
+            .is_ok());
+        assert!(credentials
+            .git_cred(url, Some("git"), ssh, &mut attempts)
+            .is_ok());
+        assert!(credentials
+            .git_cred(url, Some("git"), ssh, &mut attempts)
+            .is_err());
+    }
+}
@@ -0,0 +1,547 @@
+//! Incremental exports of a mirror, to carry updates across an air gap without copying
+//! or rescanning the whole mirror.
+//!
+//! A delta is a directory holding the files added to the mirror since the previous delta,
+//! a pack of the new crates.io-index objects, and a manifest. The manifest of one delta is
+//! what the next one is exported `--since`, and deltas are imported in the same order.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::crates::{crate_files_since, SyncError};
+use crate::crates_index::UPSTREAM_REF;
+use crate::download::{append_to_path, sha256_file, FileSuffixes};
+use crate::progress_bar::panamax_progress_bar;
+
+#[derive(Error, Debug)]
+pub enum DeltaError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error("Sync error: {0}")]
+    Sync(#[from] SyncError),
+    #[error("TOML serialization error: {0}")]
+    Serialize(#[from] toml_edit::ser::Error),
+    #[error("TOML deserialization error: {0}")]
+    Deserialize(#[from] toml_edit::de::Error),
+    #[error("{0} already contains a delta")]
+    DestinationNotEmpty(PathBuf),
+    #[error("The delta applies to index commit {expected}, but the mirror is at {found}")]
+    BaseMismatch { expected: String, found: String },
+    #[error("{0} doesn't match its checksum in the delta manifest")]
+    Mismatch(String),
+    #[error("Invalid path \"{0}\" in the delta manifest")]
+    BadPath(String),
+}
+
+/// Name of the manifest in a delta directory.
+pub const DELTA_MANIFEST: &str = "panamax-delta.toml";
+
+/// Name of the pack of crates.io-index objects in a delta directory.
+pub const INDEX_PACK: &str = "crates.io-index.pack";
+
+/// Describes the contents of a delta, and the state of the mirror it was exported from.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct DeltaManifest {
+    /// The crates.io-index commit of the previous delta, if any.
+    pub since_commit: Option<String>,
+    /// The crates.io-index commit the mirror was at, if it has an index.
+    pub index_commit: Option<String>,
+    /// Files included in the delta, relative to the mirror, with their SHA-256 hashes.
+    pub files: BTreeMap<String, String>,
+    /// Rustup files removed from the mirror since the previous delta.
+    pub removed: Vec<String>,
+    /// Size and modification time of every rustup file in the mirror, to compare the next
+    /// delta against.
+    pub rustup_files: BTreeMap<String, (u64, u64)>,
+}
+
+impl DeltaManifest {
+    pub fn load(path: &Path) -> Result<Self, DeltaError> {
+        Ok(toml_edit::easy::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Turn a path relative to the mirror into a manifest entry, with forward slashes.
+fn manifest_path(path: &Path) -> String {
+    path.iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Find the rustup files of a mirror, with their size and modification time.
+///
+/// These are the files under `dist` and `rustup`, and the channel histories.
+/// Partial and failed downloads, named with `suffixes`, are left out.
+fn rustup_files(
+    path: &Path,
+    suffixes: &FileSuffixes,
+) -> Result<BTreeMap<String, (u64, u64)>, io::Error> {
+    let history_files = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("mirror-") && n.ends_with("-history.toml"))
+        });
+
+    let mut files = BTreeMap::new();
+    let walk = [path.join("dist"), path.join("rustup")]
+        .into_iter()
+        .filter(|dir| dir.exists())
+        .flat_map(walkdir::WalkDir::new);
+    for entry in walk {
+        let entry = entry?;
+        let file_path = entry.path();
+        if entry.file_type().is_file() && !suffixes.is_partial(file_path) {
+            files.insert(file_path.to_path_buf(), entry.metadata()?);
+        }
+    }
+    for file_path in history_files {
+        let metadata = fs::metadata(&file_path)?;
+        files.insert(file_path, metadata);
+    }
+
+    files
+        .into_iter()
+        .map(|(file_path, metadata)| {
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let relative = file_path.strip_prefix(path).unwrap_or(&file_path);
+            Ok((manifest_path(relative), (metadata.len(), modified)))
+        })
+        .collect()
+}
+
+/// Write a pack of the crates.io-index objects added since `since_commit` to `pack_path`.
+///
+/// Only the upstream history is exported, not the config.json commit panamax adds on top
+/// of it, as the importing mirror rewrites config.json with its own `base_url`.
+/// Returns the upstream commit, or `None` if the mirror has no index.
+fn export_index(
+    path: &Path,
+    since_commit: Option<&str>,
+    pack_path: &Path,
+) -> Result<Option<String>, DeltaError> {
+    let Ok(repo) = Repository::open(path.join("crates.io-index")) else {
+        return Ok(None);
+    };
+    let upstream = upstream_commit(&repo)?;
+
+    let mut walk = repo.revwalk()?;
+    walk.push(upstream.id())?;
+    if let Some(since_commit) = since_commit {
+        walk.hide(repo.revparse_single(since_commit)?.peel_to_commit()?.id())?;
+    }
+    let mut builder = repo.packbuilder()?;
+    builder.insert_walk(&mut walk)?;
+    if builder.object_count() > 0 {
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack)?;
+        fs::write(pack_path, &*pack)?;
+    }
+    Ok(Some(upstream.id().to_string()))
+}
+
+/// Export the files added to the mirror at `path` since the delta described by `since`,
+/// or the whole mirror without it, to the directory `dest`.
+pub async fn export(
+    path: &Path,
+    dest: &Path,
+    since: Option<&DeltaManifest>,
+    suffixes: &FileSuffixes,
+) -> Result<DeltaManifest, DeltaError> {
+    if dest.join(DELTA_MANIFEST).exists() {
+        return Err(DeltaError::DestinationNotEmpty(dest.to_path_buf()));
+    }
+    fs::create_dir_all(dest)?;
+
+    let since_commit = since.and_then(|s| s.index_commit.clone());
+    let index_commit = export_index(path, since_commit.as_deref(), &dest.join(INDEX_PACK))?;
+
+    let mut files = Vec::new();
+    if index_commit.is_some() {
+        for crate_file in crate_files_since(path, since_commit.as_deref()).await? {
+            let sha256_file = append_to_path(&crate_file, ".sha256");
+            files.push(manifest_path(&crate_file));
+            if path.join(&sha256_file).exists() {
+                files.push(manifest_path(&sha256_file));
+            }
+        }
+    }
+
+    let rustup_files = rustup_files(path, suffixes)?;
+    let previous = since.map(|s| &s.rustup_files);
+    files.extend(
+        rustup_files
+            .iter()
+            .filter(|(file, state)| previous.and_then(|p| p.get(*file)) != Some(state))
+            .map(|(file, _)| file.clone()),
+    );
+    let removed = previous
+        .into_iter()
+        .flat_map(|p| p.keys())
+        .filter(|file| !rustup_files.contains_key(*file))
+        .cloned()
+        .collect();
+
+    let pb = panamax_progress_bar(files.len(), "Copying files...".to_string());
+    let mut hashes = BTreeMap::new();
+    for file in files {
+        let to = dest.join(&file);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(path.join(&file), &to)?;
+        hashes.insert(file, sha256_file(&to)?);
+        pb.inc(1);
+    }
+
+    let manifest = DeltaManifest {
+        since_commit,
+        index_commit,
+        files: hashes,
+        removed,
+        rustup_files,
+    };
+    fs::write(
+        dest.join(DELTA_MANIFEST),
+        toml_edit::easy::to_string(&manifest)?,
+    )?;
+    Ok(manifest)
+}
+
+/// Check that a manifest path stays within the mirror.
+fn checked_path(file: &str) -> Result<PathBuf, DeltaError> {
+    let path = PathBuf::from(file);
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Ok(path)
+    } else {
+        Err(DeltaError::BadPath(file.to_string()))
+    }
+}
+
+/// The upstream commit of a crates.io-index repository, below any commits panamax added.
+///
+/// Indexes synced before `UPSTREAM_REF` was recorded fall back to master.
+fn upstream_commit(repo: &Repository) -> Result<git2::Commit<'_>, git2::Error> {
+    repo.find_reference(UPSTREAM_REF)
+        .or_else(|_| repo.find_reference("refs/heads/master"))?
+        .peel_to_commit()
+}
+
+/// Add the objects of a delta's index pack to the mirror's crates.io-index, and move master
+/// to the delta's commit. Rewriting config.json is left to the caller.
+fn import_index(path: &Path, bundle: &Path, index_commit: &str) -> Result<(), DeltaError> {
+    let repo_path = path.join("crates.io-index");
+    let repo = Repository::open(&repo_path).or_else(|_| Repository::init(&repo_path))?;
+
+    let pack_path = bundle.join(INDEX_PACK);
+    if pack_path.exists() {
+        let odb = repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        io::copy(&mut fs::File::open(pack_path)?, &mut writer)?;
+        writer.commit()?;
+    }
+
+    let commit = repo.find_commit(git2::Oid::from_str(index_commit)?)?;
+    repo.reference(
+        "refs/heads/master",
+        commit.id(),
+        true,
+        "panamax import-delta",
+    )?;
+    repo.reference(UPSTREAM_REF, commit.id(), true, "panamax import-delta")?;
+    repo.set_head("refs/heads/master")?;
+    // The working tree is served as the sparse index.
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+    Ok(())
+}
+
+/// Import a delta exported by `export` into the mirror at `path`.
+///
+/// The delta must follow the upstream index commit the mirror is at, so no changes are
+/// skipped. Master is left at the delta's upstream commit, without a rewritten config.json.
+/// Every file is checked against its hash before anything is changed. Files are moved into
+/// place before the index is updated, so the index never lists crates that aren't there yet.
+pub fn import(
+    bundle: &Path,
+    path: &Path,
+    suffixes: &FileSuffixes,
+) -> Result<DeltaManifest, DeltaError> {
+    let manifest = DeltaManifest::load(&bundle.join(DELTA_MANIFEST))?;
+
+    let repo = Repository::open(path.join("crates.io-index")).ok();
+    let current = repo
+        .as_ref()
+        .and_then(|repo| upstream_commit(repo).ok())
+        .map(|commit| commit.id().to_string());
+    if manifest.index_commit.is_some() && current != manifest.since_commit {
+        let describe = |commit: Option<String>| commit.unwrap_or_else(|| "(none)".to_string());
+        return Err(DeltaError::BaseMismatch {
+            expected: describe(manifest.since_commit),
+            found: describe(current),
+        });
+    }
+
+    let pb = panamax_progress_bar(manifest.files.len(), "Checking files...".to_string());
+    for (file, hash) in &manifest.files {
+        if sha256_file(&bundle.join(checked_path(file)?))? != *hash {
+            return Err(DeltaError::Mismatch(file.clone()));
+        }
+        pb.inc(1);
+    }
+
+    let pb = panamax_progress_bar(manifest.files.len(), "Importing files...".to_string());
+    for file in manifest.files.keys() {
+        let to = path.join(checked_path(file)?);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let part_path = append_to_path(&to, &suffixes.part);
+        fs::copy(bundle.join(file), &part_path)?;
+        fs::rename(&part_path, &to)?;
+        pb.inc(1);
+    }
+    for file in &manifest.removed {
+        match fs::remove_file(path.join(checked_path(file)?)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    if let Some(index_commit) = &manifest.index_commit {
+        import_index(path, bundle, index_commit)?;
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, import, DeltaError, DeltaManifest, DELTA_MANIFEST, INDEX_PACK};
+    use crate::crates::get_crate_path;
+    use crate::crates_index::{rewrite_config_json, UPSTREAM_REF};
+    use crate::download::FileSuffixes;
+    use crate::mirror::{Config, ConfigCrates};
+    use git2::{Repository, Signature};
+    use std::fs;
+    use std::path::Path;
+
+    /// Commit an index file for `name` with one entry for each version.
+    fn commit_index_file(repo: &Repository, name: &str, versions: &[&str]) {
+        let lines: String = versions
+            .iter()
+            .map(|v| format!("{{\"name\":\"{name}\",\"vers\":\"{v}\",\"cksum\":\"00\"}}\n"))
+            .collect();
+        let index_path = format!("{}/{}/{name}", &name[..2], &name[2..4]);
+        let workdir = repo.workdir().unwrap();
+        fs::create_dir_all(workdir.join(&index_path).parent().unwrap()).unwrap();
+        fs::write(workdir.join(&index_path), lines).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(&index_path)).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        index.write().unwrap();
+        let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            name,
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap();
+    }
+
+    /// Record the index's head as upstream, then rewrite config.json on top of it, as sync does.
+    fn rewrite_config(repo: &Repository, base_url: &str) {
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        repo.reference(UPSTREAM_REF, head, true, "test").unwrap();
+        let crates: ConfigCrates =
+            toml_edit::easy::from_str::<Config>(include_str!("mirror.default.toml"))
+                .unwrap()
+                .crates
+                .unwrap();
+        rewrite_config_json(repo.workdir().unwrap(), base_url, &crates).unwrap();
+    }
+
+    fn write_crate(path: &Path, name: &str, version: &str) {
+        let crate_path = get_crate_path(path, name, version).unwrap();
+        fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+        fs::write(crate_path, version).unwrap();
+    }
+
+    #[tokio::test]
+    async fn export_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirror");
+        let dest = dir.path().join("export");
+
+        let repo = Repository::init(path.join("crates.io-index")).unwrap();
+        commit_index_file(&repo, "serde", &["1.0.0"]);
+        write_crate(&path, "serde", "1.0.0");
+        fs::create_dir_all(path.join("dist/2023-05-01")).unwrap();
+        fs::write(path.join("dist/2023-05-01/rustc.tar.xz"), "rustc").unwrap();
+        fs::write(path.join("dist/2023-05-01/cargo.tar.xz.part"), "").unwrap();
+
+        let first = export(&path, &dest.join("1"), None, &FileSuffixes::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            first.files.keys().collect::<Vec<_>>(),
+            [
+                "crates/se/rd/serde/1.0.0/serde-1.0.0.crate",
+                "dist/2023-05-01/rustc.tar.xz"
+            ]
+        );
+        assert!(dest.join("1").join(INDEX_PACK).exists());
+        assert!(dest.join("1/dist/2023-05-01/rustc.tar.xz").exists());
+        assert_eq!(
+            DeltaManifest::load(&dest.join("1").join(DELTA_MANIFEST)).unwrap(),
+            first
+        );
+        assert!(
+            export(&path, &dest.join("1"), None, &FileSuffixes::default())
+                .await
+                .is_err()
+        );
+
+        commit_index_file(&repo, "serde", &["1.0.0", "1.0.1"]);
+        write_crate(&path, "serde", "1.0.1");
+        fs::remove_dir_all(path.join("dist/2023-05-01")).unwrap();
+        fs::create_dir_all(path.join("dist/2023-06-01")).unwrap();
+        fs::write(path.join("dist/2023-06-01/rustc.tar.xz"), "rustc").unwrap();
+
+        let second = export(
+            &path,
+            &dest.join("2"),
+            Some(&first),
+            &FileSuffixes::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(second.since_commit, first.index_commit);
+        assert_ne!(second.index_commit, first.index_commit);
+        assert_eq!(
+            second.files.keys().collect::<Vec<_>>(),
+            [
+                "crates/se/rd/serde/1.0.1/serde-1.0.1.crate",
+                "dist/2023-06-01/rustc.tar.xz"
+            ]
+        );
+        assert_eq!(second.removed, ["dist/2023-05-01/rustc.tar.xz"]);
+
+        // Nothing changed, so nothing is exported.
+        let third = export(
+            &path,
+            &dest.join("3"),
+            Some(&second),
+            &FileSuffixes::default(),
+        )
+        .await
+        .unwrap();
+        assert!(third.files.is_empty());
+        assert!(!dest.join("3").join(INDEX_PACK).exists());
+    }
+
+    #[tokio::test]
+    async fn import_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirror");
+        let dest = dir.path().join("export");
+        let target = dir.path().join("target");
+
+        let repo = Repository::init(path.join("crates.io-index")).unwrap();
+        commit_index_file(&repo, "serde", &["1.0.0"]);
+        write_crate(&path, "serde", "1.0.0");
+        fs::create_dir_all(path.join("dist/2023-05-01")).unwrap();
+        fs::write(path.join("dist/2023-05-01/rustc.tar.xz"), "rustc").unwrap();
+        rewrite_config(&repo, "http://exporter.internal/crates");
+        let first = export(&path, &dest.join("1"), None, &FileSuffixes::default())
+            .await
+            .unwrap();
+        // The exporter's config.json commit isn't part of the delta.
+        let upstream = repo.find_reference(UPSTREAM_REF).unwrap();
+        assert_eq!(
+            first.index_commit,
+            Some(upstream.target().unwrap().to_string())
+        );
+
+        commit_index_file(&repo, "serde", &["1.0.0", "1.0.1"]);
+        rewrite_config(&repo, "http://exporter.internal/crates");
+        write_crate(&path, "serde", "1.0.1");
+        fs::remove_dir_all(path.join("dist/2023-05-01")).unwrap();
+        let second = export(
+            &path,
+            &dest.join("2"),
+            Some(&first),
+            &FileSuffixes::default(),
+        )
+        .await
+        .unwrap();
+
+        // Deltas must be imported in order.
+        assert!(matches!(
+            import(&dest.join("2"), &target, &FileSuffixes::default()),
+            Err(DeltaError::BaseMismatch { .. })
+        ));
+        import(&dest.join("1"), &target, &FileSuffixes::default()).unwrap();
+        assert!(target.join("dist/2023-05-01/rustc.tar.xz").exists());
+        assert!(target
+            .join("crates/se/rd/serde/1.0.0/serde-1.0.0.crate")
+            .exists());
+        assert!(target.join("crates.io-index/se/rd/serde").exists());
+        // The importer rewrites config.json with its own base_url, so its master moves on
+        // from the delta's commit, but the next delta still applies.
+        let target_repo = Repository::open(target.join("crates.io-index")).unwrap();
+        rewrite_config(&target_repo, "http://importer.internal/crates");
+
+        // Tampered files are rejected before anything changes.
+        let crate_file = "crates/se/rd/serde/1.0.1/serde-1.0.1.crate";
+        fs::write(dest.join("2").join(crate_file), "tampered").unwrap();
+        assert!(matches!(
+            import(&dest.join("2"), &target, &FileSuffixes::default()),
+            Err(DeltaError::Mismatch(file)) if file == crate_file
+        ));
+        assert!(!target.join(crate_file).exists());
+
+        fs::write(dest.join("2").join(crate_file), "1.0.1").unwrap();
+        import(&dest.join("2"), &target, &FileSuffixes::default()).unwrap();
+        assert!(target.join(crate_file).exists());
+        assert!(!target.join("dist/2023-05-01/rustc.tar.xz").exists());
+        assert_eq!(
+            Some(
+                target_repo
+                    .head()
+                    .unwrap()
+                    .peel_to_commit()
+                    .unwrap()
+                    .id()
+                    .to_string()
+            ),
+            second.index_commit
+        );
+        assert!(
+            fs::read_to_string(target.join("crates.io-index/se/rd/serde"))
+                .unwrap()
+                .contains("1.0.1")
+        );
+    }
+}
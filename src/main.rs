@@ -2,13 +2,19 @@
 use clap::Parser;
 use std::{net::IpAddr, path::PathBuf};
 
+mod crate_store;
 mod crates;
 mod crates_index;
+mod credentials;
+mod delta;
 mod download;
 mod mirror;
 mod progress_bar;
 mod rustup;
+mod s3;
 mod serve;
+mod staging;
+mod upload_pack;
 mod verify;
 
 /// Mirror rustup and crates.io repositories, for offline Rust and cargo usage.
@@ -22,6 +28,15 @@ enum Panamax {
         /// set [rustup] sync = false
         #[arg(long)]
         ignore_rustup: bool,
+
+        /// set [crates] sync = false
+        #[arg(long)]
+        ignore_crates: bool,
+
+        /// Write a mirror.toml that documents every option, commented out with its default.
+        /// This is already what `init` writes, so the flag only makes it explicit.
+        #[arg(long)]
+        full_config: bool,
     },
 
     /// Update an existing mirror directory.
@@ -38,8 +53,90 @@ enum Panamax {
         #[arg(long = "cargo-lock")]
         cargo_lock_filepath: Option<PathBuf>,
 
+        /// File listing the crates to sync, one per line.
+        ///
+        /// Lines are `name@version`, `name@requirement` or `name requirement`, where requirement
+        /// is a semver range such as `>=1.2, <2`, or just `name` for all versions.
+        /// Only matching crates are downloaded, as with --vendor-path and --cargo-lock.
+        #[arg(long, value_name = "PATH")]
+        crate_set: Option<PathBuf>,
+
         #[arg(long)]
         skip_rustup: bool,
+
+        /// Don't re-hash files that already exist in the mirror.
+        ///
+        /// This makes re-syncs much faster, but a corrupted file will not be re-downloaded.
+        #[arg(long)]
+        no_verify_existing: bool,
+
+        /// Re-clone crates.io-index if the repository is corrupt, replacing it once the clone
+        /// is complete.
+        #[arg(long)]
+        reclone_index: bool,
+
+        /// Sync the crates changed since this crates.io-index commit, instead of since the last sync.
+        ///
+        /// This can be used to recover from a bad sync.
+        #[arg(long, value_name = "COMMIT")]
+        from_commit: Option<String>,
+
+        /// Only sync this crate, as `name@version`, or `name` for all of its versions.
+        ///
+        /// The crate is looked up in the local crates.io-index, and nothing else is synced.
+        /// Can be given multiple times.
+        #[arg(long, value_name = "CRATE")]
+        only: Vec<String>,
+
+        /// Only download the rustup files of this platform, for the channels already
+        /// in the mirror, e.g. to backfill a newly needed target.
+        ///
+        /// The mirrored channel files are kept, and nothing else is synced.
+        /// The platform must be listed by `panamax list-platforms`. Can be given multiple times.
+        #[arg(
+            long = "platform",
+            value_name = "PLATFORM",
+            conflicts_with_all = ["skip_rustup", "only"]
+        )]
+        platforms: Vec<String>,
+
+        /// Download every file of this rustup channel again, e.g. `nightly-2024-01-15`,
+        /// re-verifying their hashes, to repair a corrupted date.
+        ///
+        /// Nothing else is synced. Can be given multiple times.
+        #[arg(long, value_name = "CHANNEL", conflicts_with_all = ["only", "skip_rustup", "dry_run"])]
+        rustup_refresh: Vec<String>,
+
+        /// Report how many files would be downloaded, and their size, without changing the mirror.
+        ///
+        /// crates.io-index is fetched into a separate reference, without fast-forwarding it.
+        #[arg(long, conflicts_with = "only")]
+        dry_run: bool,
+
+        /// Sync into a copy of the mirror, <path>.staging, and swap it in once the sync succeeds.
+        ///
+        /// Unchanged files are hardlinked into the copy. If <path> is a symlink, the copy is made
+        /// next to its target and the symlink is pointed at it atomically, so serving <path>
+        /// never shows a half-updated mirror. Old copies are removed, unless panamax didn't
+        /// create them.
+        #[arg(long, conflicts_with_all = ["dry_run", "only", "rustup_refresh", "platforms"])]
+        staging: bool,
+
+        /// Use crates download_threads above 64. Higher concurrency risks getting the mirror
+        /// rate limited or blocked by crates.io.
+        #[arg(long)]
+        allow_high_concurrency: bool,
+
+        /// Print progress events to stdout as JSON lines, for programs driving the sync.
+        ///
+        /// Progress bars are not drawn, as the events replace them.
+        #[arg(long)]
+        progress_events: bool,
+
+        /// Log every HTTP request and response, with their main headers, to debug
+        /// failures against proxies or custom sources. Same as `RUST_LOG=panamax::http=trace`.
+        #[arg(long)]
+        trace_http: bool,
     },
 
     /// Rewrite the config.json within crates.io-index.
@@ -57,6 +154,17 @@ enum Panamax {
         base_url: Option<String>,
     },
 
+    /// Upgrade mirror.toml to the format used by this version of Panamax.
+    ///
+    /// The old mirror.toml is saved as mirror.toml.bak. Crates stored in mixed-case
+    /// directories by older versions are moved to the lowercased ones used now, and linked
+    /// from their old names.
+    Migrate {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+    },
+
     /// Serve a mirror directory.
     #[command(name = "serve")]
     Serve {
@@ -84,6 +192,83 @@ enum Panamax {
         key_path: Option<PathBuf>,
     },
 
+    /// Serve a mirror directory, and sync it periodically.
+    ///
+    /// The interval between syncs is set by sync_interval in mirror.toml.
+    #[command(name = "run")]
+    Run {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// IP address to listen on. Defaults to listening on everything.
+        #[arg(short, long)]
+        listen: Option<IpAddr>,
+
+        /// Port to listen on.
+        /// Defaults to 8080, or 8443 if TLS certificate provided.
+        #[arg(short, long)]
+        port: Option<u16>,
+
+        /// Path to a TLS certificate file. This enables TLS.
+        /// Also requires key_path.
+        #[arg(long)]
+        cert_path: Option<PathBuf>,
+
+        /// Path to a TLS key file.
+        /// Also requires cert_path.
+        #[arg(long)]
+        key_path: Option<PathBuf>,
+
+        /// Use crates download_threads above 64. Higher concurrency risks getting the mirror
+        /// rate limited or blocked by crates.io.
+        #[arg(long)]
+        allow_high_concurrency: bool,
+    },
+
+    /// Export the files added to a mirror since an earlier export, along with the new
+    /// crates.io-index commits, to carry them across an air gap.
+    #[command(name = "export-delta")]
+    ExportDelta {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Directory to export to.
+        #[arg(value_parser)]
+        dest: PathBuf,
+
+        /// Manifest of the previous export, to only export what changed since then.
+        /// Without it, the whole mirror is exported.
+        #[arg(long, value_name = "MANIFEST")]
+        since: Option<PathBuf>,
+    },
+
+    /// Import a directory created by export-delta into a mirror. Deltas must be imported
+    /// in the order they were exported.
+    #[command(name = "import-delta")]
+    ImportDelta {
+        /// Directory created by export-delta.
+        #[arg(value_parser)]
+        bundle: PathBuf,
+
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+    },
+
+    /// Write every crate version in the mirror's crates.io-index as a line of JSON,
+    /// for dependency analysis and SBOM tooling. Nothing is fetched from the network.
+    Catalog {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// File to write to, instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+
     /// List platforms currently available.
     ///
     /// This is useful for finding what can be used for
@@ -95,6 +280,36 @@ enum Panamax {
 
         #[arg(long, default_value = "nightly")]
         channel: String,
+
+        /// Mirror directory. If given, platforms are read from the mirrored
+        /// channel file instead of being downloaded from the source.
+        #[arg(long, value_parser)]
+        path: Option<PathBuf>,
+
+        /// Print the platforms as a JSON array, for scripts.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List components (rustc, cargo, clippy, rust-docs...) currently available.
+    ///
+    /// These are the package names listed in a channel file.
+    #[command(name = "list-components")]
+    ListComponents {
+        #[arg(long, default_value = "https://static.rust-lang.org")]
+        source: String,
+
+        #[arg(long, default_value = "nightly")]
+        channel: String,
+
+        /// Mirror directory. If given, components are read from the mirrored
+        /// channel file instead of being downloaded from the source.
+        #[arg(long, value_parser)]
+        path: Option<PathBuf>,
+
+        /// Print the components as a JSON array, for scripts.
+        #[arg(long)]
+        json: bool,
     },
 
     /// Verify coherence between local mirror and local crates.io-index.
@@ -110,37 +325,110 @@ enum Panamax {
         #[arg(long)]
         dry_run: bool,
 
+        /// Verify without any network access, failing if crates are missing or mismatched.
+        /// Requires --dry-run, as repairing the mirror needs network access.
+        #[arg(long)]
+        offline: bool,
+
         /// Assume yes from user.
         /// Ignored if dry-run is supplied.
         #[arg(long)]
         assume_yes: bool,
 
+        /// Also check the checksums of crates in the mirror, re-downloading mismatched ones.
+        /// Crates with an up-to-date .sha256 file are not re-hashed.
+        #[arg(long)]
+        checksum: bool,
+
+        /// Re-hash crates last modified or re-hashed more than this many days ago,
+        /// re-downloading those that no longer match the index, in case they were corrected
+        /// upstream.
+        #[arg(long, value_name = "DAYS")]
+        refresh_older_than: Option<u64>,
+
         /// cargo-vendor directory.
         #[arg(value_parser)]
         vendor_path: Option<PathBuf>,
 
-        /// cargo-lock file.
+        /// cargo-lock file, or a directory to look for Cargo.lock files in.
+        /// Can be given several times, to verify the crates of all the lockfiles.
         #[arg(long = "cargo-lock")]
-        cargo_lock_filepath: Option<PathBuf>,
+        cargo_lock_paths: Vec<PathBuf>,
+
+        /// Use crates download_threads above 64 when repairing the mirror. Higher concurrency
+        /// risks getting the mirror rate limited or blocked by crates.io.
+        #[arg(long)]
+        allow_high_concurrency: bool,
+
+        /// Print progress events to stdout as JSON lines, for programs driving the verification.
+        ///
+        /// Progress bars are not drawn, as the events replace them.
+        #[arg(long)]
+        progress_events: bool,
     },
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
     let opt = Panamax::parse();
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Panamax::Sync {
+        trace_http: true, ..
+    } = opt
+    {
+        logger.filter_module(download::HTTP_LOG_TARGET, log::LevelFilter::Trace);
+    }
+    logger.init();
     match opt {
         Panamax::Init {
             path,
             ignore_rustup,
-        } => mirror::init(&path, ignore_rustup),
+            ignore_crates,
+            full_config: _,
+        } => mirror::init(&path, ignore_rustup, ignore_crates),
         Panamax::Sync {
             path,
             vendor_path,
             cargo_lock_filepath,
+            crate_set,
             skip_rustup,
-        } => mirror::sync(&path, vendor_path, cargo_lock_filepath, skip_rustup).await,
+            no_verify_existing,
+            reclone_index,
+            from_commit,
+            only,
+            rustup_refresh,
+            platforms,
+            dry_run,
+            staging,
+            allow_high_concurrency,
+            progress_events,
+            trace_http: _,
+        } => {
+            let (events, printer) = progress_events.then(progress_bar::print_events).unzip();
+            let sync_options = mirror::SyncOptions {
+                vendor_path,
+                cargo_lock_filepath,
+                crate_set_path: crate_set,
+                skip_rustup,
+                no_verify_existing,
+                reclone_index,
+                from_commit,
+                only,
+                rustup_refresh,
+                platforms,
+                dry_run,
+                staging,
+                allow_high_concurrency,
+            };
+            let result =
+                mirror::sync(&path, sync_options, events, mirror::cancel_on_signal()).await;
+            if let Some(printer) = printer {
+                let _ = printer.await;
+            }
+            result
+        }
         Panamax::Rewrite { path, base_url } => mirror::rewrite(&path, base_url),
+        Panamax::Migrate { path } => mirror::migrate(&path),
         Panamax::Serve {
             path,
             listen,
@@ -148,14 +436,72 @@ async fn main() {
             cert_path,
             key_path,
         } => mirror::serve(path, listen, port, cert_path, key_path).await,
-        Panamax::ListPlatforms { source, channel } => mirror::list_platforms(source, channel).await,
+        Panamax::Run {
+            path,
+            listen,
+            port,
+            cert_path,
+            key_path,
+            allow_high_concurrency,
+        } => {
+            mirror::run(
+                path,
+                listen,
+                port,
+                cert_path,
+                key_path,
+                allow_high_concurrency,
+            )
+            .await
+        }
+        Panamax::ExportDelta { path, dest, since } => {
+            mirror::export_delta(&path, &dest, since).await
+        }
+        Panamax::ImportDelta { bundle, path } => mirror::import_delta(&bundle, &path),
+        Panamax::Catalog { path, output } => mirror::catalog(&path, output),
+        Panamax::ListPlatforms {
+            source,
+            channel,
+            path,
+            json,
+        } => mirror::list_platforms(source, channel, path, json).await,
+        Panamax::ListComponents {
+            source,
+            channel,
+            path,
+            json,
+        } => mirror::list_components(source, channel, path, json).await,
         Panamax::Verify {
             path,
             dry_run,
+            offline,
             assume_yes,
+            checksum,
+            refresh_older_than,
             vendor_path,
-            cargo_lock_filepath,
-        } => mirror::verify(path, dry_run, assume_yes, vendor_path, cargo_lock_filepath).await,
+            cargo_lock_paths,
+            allow_high_concurrency,
+            progress_events,
+        } => {
+            let (events, printer) = progress_events.then(progress_bar::print_events).unzip();
+            let result = mirror::verify(
+                path,
+                dry_run,
+                offline,
+                assume_yes,
+                checksum,
+                refresh_older_than,
+                vendor_path,
+                cargo_lock_paths,
+                allow_high_concurrency,
+                events,
+            )
+            .await;
+            if let Some(printer) = printer {
+                let _ = printer.await;
+            }
+            result
+        }
     }
     .unwrap_or_else(|e| {
         eprintln!("Panamax command failed! {e}");
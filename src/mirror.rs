@@ -1,17 +1,32 @@
+use std::collections::HashMap;
+use std::io::Write;
 use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
 
 use console::style;
 use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 
+use crate::crate_store::{crate_store, RedirectCrateStore};
 use crate::crates::is_new_crates_format;
-use crate::crates_index::rewrite_config_json;
+use crate::crates::{read_crate_set, CratePin, VersionFloor};
+use crate::crates_index::{normalize_base_url, rewrite_config_json, IndexSyncError};
+use crate::credentials::Credentials;
+use crate::delta::{DeltaManifest, DELTA_MANIFEST};
+use crate::download::{DownloadOptions, FileSuffixes};
 
-use crate::rustup::download_platform_list;
-use crate::serve::TlsConfig;
+use crate::progress_bar::{padded_prefix_message, EventSink};
+use crate::rustup::{
+    components_from_channel, download_channel_file, download_platform_list, local_channel_file,
+    local_platform_list, DEFAULT_DIST_PATH_TEMPLATE,
+};
+use crate::serve::{AccessControl, GitBackend, RequestLimits, ServerOptions, TlsConfig};
 use crate::verify;
 
 #[derive(Error, Debug)]
@@ -36,12 +51,77 @@ pub enum MirrorError {
 
     #[error("Toml error: {0}")]
     Serialize(#[from] toml_edit::TomlError),
+
+    #[error("Crate storage error: {0}")]
+    Store(#[from] crate::crate_store::CrateStoreError),
+
+    #[error("{0} crates are missing or don't match their checksum")]
+    Unverified(usize),
+
+    #[error("Delta error: {0}")]
+    Delta(#[from] crate::delta::DeltaError),
+
+    #[error("Sync interrupted, resume it with `panamax sync`.")]
+    Interrupted,
+
+    #[error("Crates error: {0}")]
+    Crates(#[from] crate::crates::SyncError),
+
+    #[error("{count} {what} could not be synced")]
+    Incomplete { count: usize, what: &'static str },
+
+    #[error("Failed to load the TLS certificate or key: {0}")]
+    Tls(io::Error),
+
+    #[error("Failed to listen on {addr}: {source}")]
+    Listen { addr: SocketAddr, source: io::Error },
+}
+
+/// Contact information for the user agent: an address, a list of them, or an email and a URL.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Contact {
+    One(String),
+    List(Vec<String>),
+    Structured {
+        email: Option<String>,
+        url: Option<String>,
+    },
+}
+
+impl Contact {
+    /// The contacts to list in the user agent, leaving out blanks and the template's placeholder.
+    fn entries(&self) -> Vec<&str> {
+        let entries: Vec<&String> = match self {
+            Contact::One(contact) => vec![contact],
+            Contact::List(contacts) => contacts.iter().collect(),
+            Contact::Structured { email, url } => email.iter().chain(url).collect(),
+        };
+        entries
+            .into_iter()
+            .map(|contact| contact.trim())
+            .filter(|contact| !contact.is_empty() && *contact != "your@email.com")
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigMirror {
+    pub config_version: Option<i64>,
     pub retries: usize,
-    pub contact: Option<String>,
+    pub contact: Option<Contact>,
+    pub fsync: Option<bool>,
+    pub post_sync_hook: Option<String>,
+    pub file_mode: Option<String>,
+    pub dir_mode: Option<String>,
+    pub file_uid: Option<u32>,
+    pub file_gid: Option<u32>,
+    pub sync_interval: Option<u64>,
+    pub parallel_chunks: Option<usize>,
+    pub hardlink: Option<bool>,
+    pub part_suffix: Option<String>,
+    pub notfound_suffix: Option<String>,
+    pub badsha256_suffix: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -50,17 +130,25 @@ pub struct ConfigRustup {
     pub download_threads: usize,
     pub source: String,
     pub download_dev: Option<bool>,
+    pub dev_channels: Option<Vec<String>>,
     pub download_gz: Option<bool>,
     pub download_xz: Option<bool>,
+    pub download_zst: Option<bool>,
+    pub formats: Option<HashMap<String, Vec<String>>>,
     pub platforms_unix: Option<Vec<String>>,
     pub platforms_windows: Option<Vec<String>>,
+    pub extra_std_targets: Option<Vec<String>>,
     pub keep_latest_stables: Option<usize>,
     pub keep_latest_betas: Option<usize>,
     pub keep_latest_nightlies: Option<usize>,
+    pub keep_beta_dates: Option<Vec<String>>,
+    pub keep_nightly_dates: Option<Vec<String>>,
     pub pinned_rust_versions: Option<Vec<String>>,
+    pub dist_path_template: Option<String>,
+    pub download_signatures: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigCrates {
     pub sync: bool,
     pub download_threads: usize,
@@ -68,6 +156,49 @@ pub struct ConfigCrates {
     pub source_index: String,
     pub use_new_crates_format: Option<bool>,
     pub base_url: Option<String>,
+    pub max_total_bytes: Option<u64>,
+    pub index_snapshot_url: Option<String>,
+    pub use_cdn: Option<bool>,
+    pub download_url_template: Option<String>,
+    pub write_sha256: Option<bool>,
+    pub download_crates: Option<bool>,
+    pub commit_author_name: Option<String>,
+    pub commit_author_email: Option<String>,
+    pub pinned: Option<Vec<String>>,
+    pub min_version: Option<String>,
+    pub min_versions: Option<HashMap<String, String>>,
+    pub popularity_file: Option<String>,
+    pub skip_report: Option<String>,
+    pub auth_token: Option<String>,
+    pub netrc_file: Option<String>,
+    pub index_parse_threads: Option<usize>,
+    pub index_username: Option<String>,
+    pub index_password: Option<String>,
+    pub index_ssh_key: Option<String>,
+    pub storage: Option<String>,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: Option<String>,
+    pub s3_prefix: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub s3_redirect: Option<bool>,
+    pub s3_presign_expiry: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ConfigServe {
+    pub allow_cidrs: Option<Vec<String>>,
+    pub deny_cidrs: Option<Vec<String>>,
+    pub http2_max_concurrent_streams: Option<u32>,
+    pub http2_keep_alive_interval: Option<u64>,
+    pub http1_keep_alive: Option<bool>,
+    pub crate_redirect_base: Option<String>,
+    pub git_backend: Option<String>,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_concurrent_git: Option<usize>,
+    pub max_staleness: Option<u64>,
+    pub git_timeout_secs: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -75,53 +206,216 @@ pub struct Config {
     pub mirror: ConfigMirror,
     pub rustup: Option<ConfigRustup>,
     pub crates: Option<ConfigCrates>,
+    pub serve: Option<ConfigServe>,
 }
 
-pub fn create_mirror_directories(path: &Path, ignore_rustup: bool) -> Result<(), io::Error> {
+pub fn create_mirror_directories(
+    path: &Path,
+    ignore_rustup: bool,
+    ignore_crates: bool,
+) -> Result<(), io::Error> {
     if !ignore_rustup {
         // Rustup directories
         fs::create_dir_all(path.join("rustup/dist"))?;
         fs::create_dir_all(path.join("dist"))?;
     }
 
-    // Crates directories
-    fs::create_dir_all(path.join("crates.io-index"))?;
-    fs::create_dir_all(path.join("crates"))?;
+    if !ignore_crates {
+        // Crates directories
+        fs::create_dir_all(path.join("crates.io-index"))?;
+        fs::create_dir_all(path.join("crates"))?;
+    }
+
+    // Make sure the mirror base exists, even if both halves are ignored
+    fs::create_dir_all(path)?;
     Ok(())
 }
 
-pub fn create_mirror_toml(path: &Path, ignore_rustup: bool) -> Result<bool, MirrorError> {
-    if path.join("mirror.toml").exists() {
-        return Ok(false);
-    }
-
+/// Render the default mirror.toml, with sync disabled for the ignored halves of the mirror.
+///
+/// Every option is documented in it, commented out with its default when it isn't set.
+pub fn default_mirror_toml(
+    ignore_rustup: bool,
+    ignore_crates: bool,
+) -> Result<String, MirrorError> {
     // Read the defautlt toml, edit if required, using toml_edit to keep format
-    let config = include_str!("mirror.default.toml");
-    let mut config = config.parse::<toml_edit::Document>()?;
+    let mut config = include_str!("mirror.default.toml").parse::<toml_edit::Document>()?;
 
     if ignore_rustup {
         config["rustup"]["sync"] = toml_edit::value(false);
     }
+    if ignore_crates {
+        config["crates"]["sync"] = toml_edit::value(false);
+    }
 
-    let path = path.join("mirror.toml");
-    let bytes = config.to_string();
-    fs::write(path, bytes)?;
+    Ok(config.to_string())
+}
 
-    Ok(true)
+/// Write `contents` to the mirror.toml of `path`, unless it already exists.
+///
+/// The contents are written to a temporary file in `path` first, then linked into place,
+/// so mirror.toml is never seen half-written, even by a concurrent `init` of the same path.
+///
+/// Returns whether the file was written.
+pub fn write_mirror_toml(path: &Path, contents: &str) -> Result<bool, MirrorError> {
+    static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let temp_path = path.join(format!(
+        ".mirror.toml.{}.{}.tmp",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let res = fs::write(&temp_path, contents)
+        .and_then(|()| fs::hard_link(&temp_path, path.join("mirror.toml")));
+    let _ = fs::remove_file(&temp_path);
+    match res {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
 }
 
-pub fn load_mirror_toml(path: &Path) -> Result<Config, MirrorError> {
-    Ok(toml_edit::easy::from_str(&fs::read_to_string(
-        path.join("mirror.toml"),
-    )?)?)
+/// Whether `init_mirror` created a new mirror.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStatus {
+    Created,
+    AlreadyExists,
 }
 
-pub fn init(path: &Path, ignore_rustup: bool) -> Result<(), MirrorError> {
-    create_mirror_directories(path, ignore_rustup)?;
-    if create_mirror_toml(path, ignore_rustup)? {
-        eprintln!("Successfully created mirror base at `{}`.", path.display());
+/// Create the directories and mirror.toml of a mirror, without printing anything.
+///
+/// `config` is the mirror.toml to write, as rendered by `default_mirror_toml`, so it can be
+/// shared when initializing several mirrors. Mirrors in different directories can be initialized
+/// concurrently.
+pub fn init_mirror(
+    path: &Path,
+    config: &str,
+    ignore_rustup: bool,
+    ignore_crates: bool,
+) -> Result<InitStatus, MirrorError> {
+    create_mirror_directories(path, ignore_rustup, ignore_crates)?;
+    if write_mirror_toml(path, config)? {
+        Ok(InitStatus::Created)
     } else {
-        eprintln!("Mirror base already exists at `{}`.", path.display());
+        Ok(InitStatus::AlreadyExists)
+    }
+}
+
+/// Version of the mirror.toml format, as written by `init`.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// A mirror.toml migration, returning a description of each change it made.
+type ConfigMigration = fn(&mut toml_edit::Document) -> Vec<String>;
+
+/// `CONFIG_MIGRATIONS[n]` upgrades a mirror.toml from version n to version n + 1.
+///
+/// Version 0 is a mirror.toml from before `config_version` existed, which is otherwise
+/// the same as version 1.
+const CONFIG_MIGRATIONS: [ConfigMigration; CONFIG_VERSION as usize] = [|_| Vec::new()];
+
+/// Upgrade a mirror.toml document to `CONFIG_VERSION`.
+///
+/// Returns the changes made by migrations, which doesn't include setting `config_version`.
+fn migrate_config(config: &mut toml_edit::Document) -> Vec<String> {
+    let version = config
+        .get("mirror")
+        .and_then(|m| m.get("config_version"))
+        .and_then(|v| v.as_integer())
+        .unwrap_or(0);
+    if version > CONFIG_VERSION {
+        eprintln!("Warning: mirror.toml has config_version {version}, but this version of Panamax only supports up to {CONFIG_VERSION}.");
+        eprintln!("Some options may be ignored or misunderstood. Consider upgrading Panamax.");
+        return Vec::new();
+    }
+
+    let mut changes = Vec::new();
+    for migration in &CONFIG_MIGRATIONS[version.max(0) as usize..] {
+        changes.extend(migration(config));
+    }
+    if version < CONFIG_VERSION {
+        config["mirror"]["config_version"] = toml_edit::value(CONFIG_VERSION);
+    }
+    changes
+}
+
+pub fn load_mirror_toml(path: &Path) -> Result<Config, MirrorError> {
+    let mut config =
+        fs::read_to_string(path.join("mirror.toml"))?.parse::<toml_edit::Document>()?;
+
+    let changes = migrate_config(&mut config);
+    if !changes.is_empty() {
+        eprintln!("mirror.toml is from an older version of Panamax, and was upgraded in memory:");
+        for change in changes {
+            eprintln!("  - {change}");
+        }
+        eprintln!(
+            "Run `panamax migrate {}` to save these changes.",
+            path.display()
+        );
+    }
+
+    Ok(toml_edit::easy::from_document(config)?)
+}
+
+/// Upgrade mirror.toml to the current config version, keeping a backup of the old one,
+/// and move crates out of the mixed-case directories older versions stored them in.
+pub fn migrate(path: &Path) -> Result<(), MirrorError> {
+    let config_path = path.join("mirror.toml");
+    if !config_path.exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    migrate_mirror_toml(path)?;
+
+    let moved = crate::crates::lowercase_crate_dirs(path)?;
+    if moved > 0 {
+        eprintln!("Moved {moved} crates to lowercased directories.");
+    }
+    Ok(())
+}
+
+fn migrate_mirror_toml(path: &Path) -> Result<(), MirrorError> {
+    let config_path = path.join("mirror.toml");
+    let original = fs::read_to_string(&config_path)?;
+    let mut config = original.parse::<toml_edit::Document>()?;
+    let changes = migrate_config(&mut config);
+    let migrated = config.to_string();
+    if migrated == original {
+        eprintln!("mirror.toml is already up to date.");
+        return Ok(());
+    }
+
+    let backup_path = path.join("mirror.toml.bak");
+    fs::write(&backup_path, original)?;
+    fs::write(&config_path, migrated)?;
+    for change in changes {
+        eprintln!("  - {change}");
+    }
+    eprintln!(
+        "Upgraded mirror.toml to config_version {CONFIG_VERSION}. The old file was saved to {}.",
+        backup_path.display()
+    );
+
+    Ok(())
+}
+
+pub fn init(path: &Path, ignore_rustup: bool, ignore_crates: bool) -> Result<(), MirrorError> {
+    if ignore_rustup && ignore_crates {
+        eprintln!("Both rustup and crates are ignored, so this mirror will be empty.");
+        eprintln!("Set `sync = true` in either section of mirror.toml to mirror something.");
+    }
+
+    let config = default_mirror_toml(ignore_rustup, ignore_crates)?;
+    match init_mirror(path, &config, ignore_rustup, ignore_crates)? {
+        InitStatus::Created => {
+            eprintln!("Successfully created mirror base at `{}`.", path.display())
+        }
+        InitStatus::AlreadyExists => {
+            eprintln!("Mirror base already exists at `{}`.", path.display())
+        }
     }
     eprintln!(
         "Make any desired changes to {}/mirror.toml, then run panamax sync {}.",
@@ -132,15 +426,142 @@ pub fn init(path: &Path, ignore_rustup: bool) -> Result<(), MirrorError> {
     Ok(())
 }
 
+/// File recording the state of the mirror across syncs.
+pub const STATUS_FILE: &str = "mirror-status.toml";
+
+/// Contents of `STATUS_FILE`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct MirrorStatus {
+    /// Unix time at which the last fully successful sync finished.
+    pub last_sync: Option<u64>,
+}
+
+impl MirrorStatus {
+    /// Read the status of a mirror. A missing or unreadable file is an empty status.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path.join(STATUS_FILE))
+            .ok()
+            .and_then(|data| toml_edit::easy::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path, suffixes: &FileSuffixes) -> Result<(), io::Error> {
+        let data = toml_edit::easy::to_string(self).map_err(io::Error::other)?;
+        let part_path = path.join(format!("{STATUS_FILE}{}", suffixes.part));
+        fs::write(&part_path, data)?;
+        fs::rename(part_path, path.join(STATUS_FILE))
+    }
+
+    /// Time since the last fully successful sync, if there was one.
+    pub fn age(&self) -> Option<Duration> {
+        let last_sync = UNIX_EPOCH + Duration::from_secs(self.last_sync?);
+        Some(
+            SystemTime::now()
+                .duration_since(last_sync)
+                .unwrap_or_default(),
+        )
+    }
+}
+
 pub fn default_user_agent() -> String {
     format!("Panamax/{}", env!("CARGO_PKG_VERSION"))
 }
 
+/// The user agent, with any contact information as a comment, e.g. `Panamax/x.y.z (a; b)`.
+pub fn user_agent(contact: Option<&Contact>) -> String {
+    let entries = contact.map(Contact::entries).unwrap_or_default();
+    if entries.is_empty() {
+        default_user_agent()
+    } else {
+        format!("{} ({})", default_user_agent(), entries.join("; "))
+    }
+}
+
+/// Highest crates `download_threads` used without --allow-high-concurrency.
+/// More concurrent downloads than this tend to get a mirror rate limited or blocked by crates.io.
+pub const MAX_CRATES_DOWNLOAD_THREADS: usize = 64;
+
+/// Clamp crates `download_threads` to `MAX_CRATES_DOWNLOAD_THREADS`, unless `allow_high_concurrency`.
+fn limit_download_threads(crates: &mut ConfigCrates, allow_high_concurrency: bool) {
+    if crates.download_threads > MAX_CRATES_DOWNLOAD_THREADS && !allow_high_concurrency {
+        eprintln!(
+            "Warning: download_threads = {} is too high for crates, and may get this mirror blocked by crates.io. Using {MAX_CRATES_DOWNLOAD_THREADS} instead (use --allow-high-concurrency to keep it).",
+            crates.download_threads
+        );
+        crates.download_threads = MAX_CRATES_DOWNLOAD_THREADS;
+    }
+}
+
+/// A token cancelled on Ctrl-C or SIGTERM, to stop a sync cleanly.
+///
+/// Downloads already started are finished, and what was done is recorded so the next sync
+/// resumes from there. A second signal exits right away.
+pub fn cancel_on_signal() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        eprintln!(
+            "Stopping the sync after the downloads in progress, press Ctrl-C again to abort."
+        );
+        token.cancel();
+        wait_for_signal().await;
+        std::process::exit(130);
+    });
+    cancel
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Command line options of a sync. The default is a plain full sync.
+#[derive(Debug, Default)]
+pub struct SyncOptions {
+    pub vendor_path: Option<PathBuf>,
+    pub cargo_lock_filepath: Option<PathBuf>,
+    pub crate_set_path: Option<PathBuf>,
+    pub skip_rustup: bool,
+    pub no_verify_existing: bool,
+    pub reclone_index: bool,
+    pub from_commit: Option<String>,
+    pub only: Vec<String>,
+    pub rustup_refresh: Vec<String>,
+    pub platforms: Vec<String>,
+    pub dry_run: bool,
+    pub staging: bool,
+    pub allow_high_concurrency: bool,
+}
+
+/// Steps of a full sync, once they have been planned.
+#[derive(Debug, Default, Clone, Copy)]
+struct SyncSteps {
+    num_steps: usize,
+    failed_steps: usize,
+}
+
 pub async fn sync(
     path: &Path,
-    vendor_path: Option<PathBuf>,
-    cargo_lock_filepath: Option<PathBuf>,
-    skip_rustup: bool,
+    sync_options: SyncOptions,
+    events: Option<EventSink>,
+    cancel: CancellationToken,
 ) -> Result<(), MirrorError> {
     if !path.join("mirror.toml").exists() {
         eprintln!(
@@ -149,13 +570,80 @@ pub async fn sync(
         );
         return Ok(());
     }
-    let mirror = load_mirror_toml(path)?;
+
+    // The hook only runs after full syncs, including ones that fail before any step.
+    let is_full_sync = !sync_options.dry_run
+        && sync_options.only.is_empty()
+        && sync_options.rustup_refresh.is_empty()
+        && sync_options.platforms.is_empty();
+    let hook = load_mirror_toml(path)?
+        .mirror
+        .post_sync_hook
+        .filter(|_| is_full_sync);
+
+    let mut steps = None;
+    let res = sync_mirror(path, sync_options, events, cancel, &mut steps).await;
+
+    let report = match (&res, steps) {
+        (Err(_), steps) => {
+            let num_steps = steps.unwrap_or_default().num_steps;
+            Some(("failed", num_steps, num_steps))
+        }
+        (Ok(()), Some(s)) if s.failed_steps == 0 => Some(("success", 0, s.num_steps)),
+        (Ok(()), Some(s)) if s.failed_steps == s.num_steps => {
+            Some(("failed", s.failed_steps, s.num_steps))
+        }
+        (Ok(()), Some(s)) => Some(("partial", s.failed_steps, s.num_steps)),
+        // Nothing was synced, e.g. because the crates directory is in the old format.
+        (Ok(()), None) => None,
+    };
+    if let (Some(hook), Some((result, failed_steps, num_steps))) = (hook, report) {
+        run_post_sync_hook(&hook, path, result, failed_steps, num_steps).await;
+    }
+
+    res
+}
+
+/// Sync the mirror, recording the planned and failed steps of a full sync in `steps`.
+async fn sync_mirror(
+    path: &Path,
+    sync_options: SyncOptions,
+    events: Option<EventSink>,
+    cancel: CancellationToken,
+    steps: &mut Option<SyncSteps>,
+) -> Result<(), MirrorError> {
+    let mut mirror = load_mirror_toml(path)?;
+    if let Some(crates) = mirror.crates.as_mut() {
+        limit_download_threads(crates, sync_options.allow_high_concurrency);
+    }
+
+    let crate_set = sync_options
+        .crate_set_path
+        .as_ref()
+        .map(|p| {
+            read_crate_set(p)
+                .map_err(|e| MirrorError::CmdLine(format!("could not read crate set: {e}")))
+        })
+        .transpose()?;
+
+    // Check base_url now, rather than after all crates have been downloaded.
+    if let Some(base_url) = mirror
+        .crates
+        .as_ref()
+        .filter(|c| c.sync)
+        .and_then(|c| c.base_url.as_deref())
+    {
+        normalize_base_url(base_url).map_err(|e| MirrorError::Config(e.to_string()))?;
+    }
 
     // Fail if use_new_crates_format is not true, and old format is detected.
     // If use_new_crates_format is true and new format is detected, warn the user.
     // If use_new_crates_format is true, ignore the format and assume it's new.
     if let Some(crates) = &mirror.crates {
-        if crates.sync && !is_new_crates_format(&path.join("crates"))? {
+        if crates.sync
+            && crates.use_new_crates_format != Some(true)
+            && !is_new_crates_format(&path.join("crates"))?
+        {
             eprintln!("Your crates directory is using the old 0.2 format, however");
             eprintln!("Panamax 0.3+ has deprecated this format for a new one.");
             eprintln!("Please delete crates/ from your mirror directory to continue.");
@@ -164,15 +652,7 @@ pub async fn sync(
     }
 
     // Handle the contact information
-    let user_agent_str = if let Some(ref contact) = mirror.mirror.contact {
-        if contact != "your@email.com" {
-            format!("Panamax/{} ({})", env!("CARGO_PKG_VERSION"), contact)
-        } else {
-            default_user_agent()
-        }
-    } else {
-        default_user_agent()
-    };
+    let user_agent_str = user_agent(mirror.mirror.contact.as_ref());
 
     // Set the user agent with contact information.
     let user_agent = match HeaderValue::from_str(&user_agent_str) {
@@ -185,9 +665,170 @@ pub async fn sync(
         }
     };
 
-    if let Some(rustup) = mirror.rustup {
-        if rustup.sync && !skip_rustup {
-            crate::rustup::sync(path, &mirror.mirror, &rustup, &user_agent).await?;
+    // A staged sync updates a copy of the mirror, swapped in once the sync succeeds.
+    // The copy is only made once the checks above pass, so they don't leave one behind.
+    let live_path = path;
+    let staging_path = sync_options
+        .staging
+        .then(|| crate::staging::staging_path(path))
+        .transpose()?;
+    let path = match &staging_path {
+        Some(staging_path) => {
+            eprintln!("Preparing staging copy {}...", staging_path.display());
+            let (from, to) = (live_path.to_path_buf(), staging_path.clone());
+            tokio::task::spawn_blocking(move || crate::staging::prepare(&from, &to))
+                .await
+                .map_err(io::Error::other)??;
+            staging_path.as_path()
+        }
+        None => path,
+    };
+
+    // Check the crate storage now, rather than after crates.io-index has been fetched.
+    if let Some(crates) = mirror.crates.as_ref().filter(|c| c.sync) {
+        crate_store(path, Some(crates))?;
+    }
+
+    let credentials = match &mirror.crates {
+        Some(crates) => Some(Arc::new(Credentials::from_config(path, crates)?)),
+        None => None,
+    };
+    let options = DownloadOptions {
+        verify_existing: !sync_options.no_verify_existing,
+        events,
+        cancel,
+        credentials,
+        ..DownloadOptions::try_from(&mirror.mirror)?
+    };
+
+    if sync_options.dry_run {
+        return dry_run_sync(
+            path,
+            &mirror,
+            &sync_options,
+            crate_set.as_deref(),
+            &user_agent,
+        )
+        .await;
+    }
+
+    // Only sync the requested crates, skipping everything else.
+    if !sync_options.only.is_empty() {
+        let Some(crates) = &mirror.crates else {
+            return Err(MirrorError::Config(
+                "crates section missing in mirror.toml".to_string(),
+            ));
+        };
+        let prefix = padded_prefix_message(1, 1, "Syncing crates files");
+        return match crate::crates::sync_only_crates_files(
+            path,
+            &sync_options.only,
+            &options,
+            crates,
+            &user_agent,
+            prefix,
+        )
+        .await?
+        {
+            0 => {
+                eprintln!("Sync complete.");
+                Ok(())
+            }
+            count => Err(MirrorError::Incomplete {
+                count,
+                what: "crates",
+            }),
+        };
+    }
+
+    // Only re-download the requested rustup channels, skipping everything else.
+    if !sync_options.rustup_refresh.is_empty() {
+        let Some(rustup) = &mirror.rustup else {
+            return Err(MirrorError::Config(
+                "rustup section missing in mirror.toml".to_string(),
+            ));
+        };
+        return match crate::rustup::refresh_channels(
+            path,
+            &options,
+            rustup,
+            &sync_options.rustup_refresh,
+            &sync_options.platforms,
+            &user_agent,
+        )
+        .await?
+        {
+            0 => {
+                eprintln!("Sync complete.");
+                Ok(())
+            }
+            count => Err(MirrorError::Incomplete {
+                count,
+                what: "rustup channels",
+            }),
+        };
+    }
+
+    // Only download the requested platforms of the mirrored channels, skipping everything
+    // else. This isn't a full sync, so the sync status isn't updated.
+    if !sync_options.platforms.is_empty() {
+        let Some(rustup) = &mirror.rustup else {
+            return Err(MirrorError::Config(
+                "rustup section missing in mirror.toml".to_string(),
+            ));
+        };
+        return match crate::rustup::backfill_platforms(
+            path,
+            &options,
+            rustup,
+            &sync_options.platforms,
+            &user_agent,
+        )
+        .await?
+        {
+            0 => {
+                eprintln!("Sync complete.");
+                Ok(())
+            }
+            count => Err(MirrorError::Incomplete {
+                count,
+                what: "rustup steps",
+            }),
+        };
+    }
+
+    // Plan the steps of the whole sync up front, so the progress prefixes
+    // are numbered consistently across the rustup and crates halves.
+    let rustup_steps = match &mirror.rustup {
+        Some(rustup) if rustup.sync && !sync_options.skip_rustup => {
+            crate::rustup::num_steps(rustup)
+        }
+        _ => 0,
+    };
+    let crates_steps = match &mirror.crates {
+        Some(crates) if crates.sync => crates_num_steps(crates),
+        _ => 0,
+    };
+    let num_steps = rustup_steps + crates_steps;
+    let mut step = 0;
+    let mut failed_steps = 0;
+    *steps = Some(SyncSteps {
+        num_steps,
+        failed_steps,
+    });
+
+    if let Some(rustup) = &mirror.rustup {
+        if rustup.sync && !sync_options.skip_rustup {
+            failed_steps += crate::rustup::sync(
+                path,
+                &options,
+                rustup,
+                &sync_options.platforms,
+                &user_agent,
+                &mut step,
+                num_steps,
+            )
+            .await?;
         } else {
             eprintln!("Rustup sync is disabled, skipping...");
         }
@@ -195,15 +836,19 @@ pub async fn sync(
         eprintln!("Rustup section missing, skipping...");
     }
 
-    if let Some(crates) = mirror.crates {
+    if options.cancel.is_cancelled() {
+        // The sync was interrupted during the rustup half, see below.
+    } else if let Some(crates) = &mirror.crates {
         if crates.sync {
-            sync_crates(
+            failed_steps += sync_crates(
                 path,
-                vendor_path,
-                cargo_lock_filepath,
-                &mirror.mirror,
-                &crates,
+                &sync_options,
+                crate_set.as_deref(),
+                &options,
+                crates,
                 &user_agent,
+                &mut step,
+                num_steps,
             )
             .await;
         } else {
@@ -213,11 +858,149 @@ pub async fn sync(
         eprintln!("Crates section missing, skipping...");
     }
 
+    *steps = Some(SyncSteps {
+        num_steps,
+        failed_steps,
+    });
+    if options.cancel.is_cancelled() {
+        return Err(MirrorError::Interrupted);
+    }
+
     eprintln!("Sync complete.");
 
+    // Serve checks this against max_staleness, so only complete syncs count.
+    if failed_steps == 0 {
+        let status = MirrorStatus {
+            last_sync: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+        };
+        if let Err(e) = status.save(path, &options.suffixes) {
+            eprintln!("Warning: could not write {STATUS_FILE}: {e}");
+        }
+    }
+
+    if sync_options.staging {
+        if failed_steps == 0 {
+            let kept = crate::staging::promote(live_path, path)?;
+            eprintln!("Promoted staging copy to {}.", live_path.display());
+            if let Some(kept) = kept {
+                eprintln!(
+                    "Kept the previous mirror at {}, as panamax didn't create it.",
+                    kept.display()
+                );
+            }
+        } else {
+            eprintln!(
+                "Sync failed, so {} was not promoted. Sync again to resume it.",
+                path.display()
+            );
+            return Err(MirrorError::Incomplete {
+                count: failed_steps,
+                what: "steps",
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Print what a sync would download, without changing the mirror.
+///
+/// crates.io-index is fetched into a separate reference, so the next sync still sees the changes.
+async fn dry_run_sync(
+    path: &Path,
+    mirror: &Config,
+    sync_options: &SyncOptions,
+    crate_set: Option<&[CratePin]>,
+    user_agent: &HeaderValue,
+) -> Result<(), MirrorError> {
+    eprintln!("{}", style("Dry run, nothing will be downloaded.").bold());
+
+    match &mirror.rustup {
+        Some(rustup) if rustup.sync && !sync_options.skip_rustup => {
+            let summary =
+                crate::rustup::dry_run(path, rustup, &sync_options.platforms, user_agent).await?;
+            println!("Rustup: {summary}");
+        }
+        _ => println!("Rustup: not synced"),
+    }
+
+    match &mirror.crates {
+        Some(crates) if crates.sync => {
+            match crate::crates::dry_run_crates_files(
+                path,
+                sync_options.vendor_path.clone(),
+                sync_options.cargo_lock_filepath.clone(),
+                crate_set,
+                sync_options.from_commit.as_deref(),
+                crates,
+                user_agent,
+            )
+            .await
+            {
+                Ok(Some((summary, removed))) => {
+                    if crates.download_crates == Some(false) {
+                        println!(
+                            "Crates: {} changed crate versions, not downloaded (download_crates = false)",
+                            summary.files
+                        );
+                    } else if removed > 0 {
+                        println!("Crates: {summary}, {removed} removed crates");
+                    } else {
+                        println!("Crates: {summary}");
+                    }
+                }
+                Ok(None) => println!(
+                    "Crates: crates.io-index hasn't been cloned yet, so every crate would be downloaded"
+                ),
+                Err(e) => eprintln!("Checking crates failed: {e}"),
+            }
+        }
+        _ => println!("Crates: not synced"),
+    }
+
     Ok(())
 }
 
+/// Run the post-sync hook command, passing the result of the sync through environment variables.
+///
+/// The hook is run by the shell, so it can include arguments.
+/// Failures of the hook itself are only logged, and never change the result of the sync.
+async fn run_post_sync_hook(
+    hook: &str,
+    path: &Path,
+    result: &str,
+    failed_steps: usize,
+    num_steps: usize,
+) {
+    eprintln!("Running post-sync hook `{hook}`...");
+    let mut command = if cfg!(windows) {
+        let mut command = tokio::process::Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    let status = command
+        .arg(hook)
+        .env("PANAMAX_MIRROR_PATH", path)
+        .env("PANAMAX_SYNC_RESULT", result)
+        .env("PANAMAX_FAILED_STEPS", failed_steps.to_string())
+        .env("PANAMAX_TOTAL_STEPS", num_steps.to_string())
+        .status()
+        .await;
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Post-sync hook `{hook}` exited with {status}."),
+        Err(e) => eprintln!("Post-sync hook `{hook}` could not be run: {e}"),
+    }
+}
+
 /// Rewrite the config.toml only.
 ///
 /// Note that this will also fast-forward the repository
@@ -234,7 +1017,9 @@ pub fn rewrite(path: &Path, base_url: Option<String>) -> Result<(), MirrorError>
 
     if let Some(crates) = mirror.crates {
         if let Some(base_url) = base_url.as_deref().or(crates.base_url.as_deref()) {
-            if let Err(e) = rewrite_config_json(&path.join("crates.io-index"), base_url) {
+            normalize_base_url(base_url).map_err(|e| MirrorError::Config(e.to_string()))?;
+            eprintln!("{}", padded_prefix_message(1, 1, "Syncing config"));
+            if let Err(e) = rewrite_config_json(&path.join("crates.io-index"), base_url, &crates) {
                 eprintln!("Updating crates.io-index config failed: {e:?}");
             }
         } else {
@@ -250,44 +1035,294 @@ pub fn rewrite(path: &Path, base_url: Option<String>) -> Result<(), MirrorError>
     Ok(())
 }
 
-/// Synchronize and handle the crates.io-index repository.
+/// Export the files added to a mirror since the delta described by the manifest `since`.
+pub async fn export_delta(
+    path: &Path,
+    dest: &Path,
+    since: Option<PathBuf>,
+) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    let config = load_mirror_toml(path)?;
+    if config
+        .crates
+        .as_ref()
+        .is_some_and(|crates| crates.storage.as_deref() == Some("s3"))
+    {
+        return Err(MirrorError::Config(
+            "crates stored in S3 can't be exported".to_string(),
+        ));
+    }
+
+    let suffixes = FileSuffixes::from_config(&config.mirror)?;
+
+    let since = since.as_deref().map(DeltaManifest::load).transpose()?;
+    eprintln!("{}", style("Exporting mirror changes...").bold());
+    let manifest = crate::delta::export(path, dest, since.as_ref(), &suffixes).await?;
+
+    println!(
+        "Exported {} files to {}.",
+        manifest.files.len(),
+        dest.display()
+    );
+    println!(
+        "Use --since {} to export the next changes.",
+        dest.join(DELTA_MANIFEST).display()
+    );
+    Ok(())
+}
+
+/// Import a delta exported by `export_delta` into a mirror.
+pub fn import_delta(bundle: &Path, path: &Path) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    let config = load_mirror_toml(path)?;
+    let suffixes = FileSuffixes::from_config(&config.mirror)?;
+
+    eprintln!("{}", style("Importing mirror changes...").bold());
+    let manifest = crate::delta::import(bundle, path, &suffixes)?;
+    // The delta carries the upstream index, so config.json points at this mirror again.
+    if manifest.index_commit.is_some() {
+        if let Some(crates) = &config.crates {
+            if let Some(base_url) = &crates.base_url {
+                rewrite_config_json(&path.join("crates.io-index"), base_url, crates)
+                    .map_err(crate::crates::SyncError::from)?;
+            }
+        }
+    }
+
+    println!(
+        "Imported {} files and removed {} files.",
+        manifest.files.len(),
+        manifest.removed.len()
+    );
+    if let Some(index_commit) = manifest.index_commit {
+        println!("crates.io-index is now at {index_commit}.");
+    }
+    Ok(())
+}
+
+/// Write every crate version in the mirror's crates.io-index as a line of JSON, to `output`
+/// or stdout. Nothing is fetched, so this describes the mirror as of its last sync.
+pub fn catalog(path: &Path, output: Option<PathBuf>) -> Result<(), MirrorError> {
+    let index_path = path.join("crates.io-index");
+    if !index_path.exists() {
+        eprintln!("No index repository found in {}.", index_path.display());
+        return Ok(());
+    }
+
+    let count = match &output {
+        Some(output) => {
+            let mut out = io::BufWriter::new(fs::File::create(output)?);
+            let count = crate::crates::write_catalog(&index_path, &mut out)?;
+            out.flush()?;
+            count
+        }
+        None => {
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            let count = crate::crates::write_catalog(&index_path, &mut out)?;
+            out.flush()?;
+            count
+        }
+    };
+    eprintln!("Wrote {count} crate versions.");
+    Ok(())
+}
+
+/// Number of progress steps taken by a crates sync.
+fn crates_num_steps(crates: &ConfigCrates) -> usize {
+    let download_crates = crates.download_crates.unwrap_or(true);
+    1 + // fetch crates.io-index
+    usize::from(download_crates) + // sync crates files
+    usize::from(download_crates && crates.max_total_bytes.is_some()) + // prune crates files
+    1 // sync config
+}
+
+/// Synchronize and handle the crates.io-index repository, returning the number of steps that failed.
+///
+/// Steps that are never reached because of an earlier failure count as failed.
+///
+/// `step` is the last step completed before this phase, and is advanced once per crates step.
+/// `num_steps` is the total number of steps for the whole sync.
+#[allow(clippy::too_many_arguments)]
 pub async fn sync_crates(
     path: &Path,
-    vendor_path: Option<PathBuf>,
-    cargo_lock_filepath: Option<PathBuf>,
-    mirror: &ConfigMirror,
+    sync_options: &SyncOptions,
+    crate_set: Option<&[CratePin]>,
+    options: &DownloadOptions,
     crates: &ConfigCrates,
     user_agent: &HeaderValue,
-) {
+    step: &mut usize,
+    num_steps: usize,
+) -> usize {
     eprintln!("{}", style("Syncing Crates repositories...").bold());
 
-    if let Err(e) = crate::crates_index::sync_crates_repo(path, crates) {
-        eprintln!("Downloading crates.io-index repository failed: {e:?}");
-        eprintln!("You will need to sync again to finish this download.");
-        return;
+    let crates_steps = crates_num_steps(crates);
+
+    if let Some(snapshot_url) = &crates.index_snapshot_url {
+        if !path.join("crates.io-index").join(".git").exists() {
+            eprintln!("Downloading crates.io-index snapshot from {snapshot_url}...");
+            if let Err(e) = crate::crates_index::bootstrap_from_snapshot(
+                path,
+                crates,
+                snapshot_url,
+                options,
+                user_agent,
+            )
+            .await
+            {
+                eprintln!("Bootstrapping crates.io-index from snapshot failed: {e:?}");
+                eprintln!("You will need to sync again to finish this download.");
+                return crates_steps;
+            }
+        }
     }
 
-    if let Err(e) = crate::crates::sync_crates_files(
+    *step += 1;
+    let prefix = padded_prefix_message(*step, num_steps, "Fetching crates.io-index");
+    // libgit2 blocks, including while it waits to retry, so fetch on a blocking thread.
+    let (repo_path, repo_crates) = (path.to_path_buf(), crates.clone());
+    let (reclone_index, retries) = (sync_options.reclone_index, options.retries);
+    let res = tokio::task::spawn_blocking(move || {
+        crate::crates_index::sync_crates_repo(
+            &repo_path,
+            &repo_crates,
+            reclone_index,
+            retries,
+            prefix,
+        )
+    })
+    .await
+    .unwrap_or_else(|e| Err(io::Error::other(e).into()));
+    match res {
+        Ok(()) => {}
+        Err(e @ IndexSyncError::CorruptIndex(_)) => {
+            eprintln!("{e}");
+            return crates_steps;
+        }
+        Err(e @ IndexSyncError::Network { .. }) => {
+            eprintln!("{e}");
+            eprintln!("You will need to sync again to finish this download.");
+            return crates_steps;
+        }
+        Err(e) => {
+            eprintln!("Downloading crates.io-index repository failed: {e:?}");
+            eprintln!("You will need to sync again to finish this download.");
+            return crates_steps;
+        }
+    }
+
+    let mut failed_steps = 0;
+
+    if !crates.download_crates.unwrap_or(true) {
+        // Without crates files to sync, master is fast-forwarded right away.
+        let index_guard = crate::crates_index::INDEX_LOCK.write().await;
+        let res = crate::crates_index::fast_forward(&path.join("crates.io-index"));
+        drop(index_guard);
+        if let Err(e) = res {
+            eprintln!("Updating crates.io-index failed: {e:?}");
+            eprintln!("You will need to sync again to finish this download.");
+            return crates_steps;
+        }
+        return sync_crates_config(path, crates, step, num_steps).await;
+    }
+
+    *step += 1;
+    let prefix = padded_prefix_message(*step, num_steps, "Syncing crates files");
+    match crate::crates::sync_crates_files(
         path,
-        vendor_path,
-        cargo_lock_filepath,
-        mirror,
+        sync_options.vendor_path.clone(),
+        sync_options.cargo_lock_filepath.clone(),
+        crate_set,
+        sync_options.from_commit.as_deref(),
+        options,
         crates,
         user_agent,
+        prefix,
     )
     .await
     {
-        eprintln!("Downloading crates failed: {e:?}");
-        eprintln!("You will need to sync again to finish this download.");
-        return;
+        Ok(0) => {}
+        Ok(_) => failed_steps += 1,
+        Err(e) => {
+            eprintln!("Downloading crates failed: {e:?}");
+            eprintln!("You will need to sync again to finish this download.");
+            return crates_steps - 1;
+        }
+    }
+
+    if let Some(max_total_bytes) = crates.max_total_bytes {
+        *step += 1;
+        let prefix = padded_prefix_message(*step, num_steps, "Pruning crates files");
+        let prune_path = path.to_path_buf();
+        let vendor_path = sync_options.vendor_path.clone();
+        let cargo_lock_filepath = sync_options.cargo_lock_filepath.clone();
+        let pins = crate::crates::crate_pins(crates).map(|mut pins| {
+            pins.extend(crate_set.unwrap_or_default().iter().cloned());
+            pins
+        });
+        // Walking the crates directory blocks, so it runs on a blocking thread.
+        let res = tokio::task::spawn_blocking(move || {
+            crate::crates::prune_crates_files(
+                &prune_path,
+                max_total_bytes,
+                vendor_path.as_ref(),
+                cargo_lock_filepath.as_ref(),
+                &pins?,
+                prefix,
+            )
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e).into()));
+        match res {
+            Ok(0) => {}
+            Ok(freed) => eprintln!("Pruned {freed} bytes of old crates."),
+            Err(e) => {
+                failed_steps += 1;
+                eprintln!("Pruning crates failed: {e:?}");
+            }
+        }
     }
 
-    if let Err(e) = crate::crates_index::update_crates_config(path, crates) {
+    failed_steps + sync_crates_config(path, crates, step, num_steps).await
+}
+
+/// The last crates step, rewriting config.json. Returns the number of steps that failed.
+async fn sync_crates_config(
+    path: &Path,
+    crates: &ConfigCrates,
+    step: &mut usize,
+    num_steps: usize,
+) -> usize {
+    let mut failed_steps = 0;
+
+    *step += 1;
+    eprintln!(
+        "{}",
+        padded_prefix_message(*step, num_steps, "Syncing config")
+    );
+    let index_guard = crate::crates_index::INDEX_LOCK.write().await;
+    let res = crate::crates_index::update_crates_config(path, crates);
+    drop(index_guard);
+    if let Err(e) = res {
+        failed_steps += 1;
         eprintln!("Updating crates.io-index config failed: {e:?}");
         eprintln!("You will need to sync again to finish this download.");
     }
 
     eprintln!("{}", style("Syncing Crates repositories complete!").bold());
+
+    failed_steps
 }
 
 pub async fn serve(
@@ -304,6 +1339,39 @@ pub async fn serve(
     let port = port.unwrap_or_else(|| if cert_path.is_some() { 8443 } else { 8080 });
     let socket_addr = SocketAddr::new(listen, port);
 
+    // The mirror can be served without a mirror.toml, in which case everyone is allowed.
+    let (crates_config, serve_config) = if path.join("mirror.toml").exists() {
+        let config = load_mirror_toml(&path)?;
+        (config.crates, config.serve)
+    } else {
+        (None, None)
+    };
+    let mut store = crate_store(&path, crates_config.as_ref())?;
+    if let Some(base) = serve_config
+        .as_ref()
+        .and_then(|s| s.crate_redirect_base.as_deref())
+    {
+        store = Arc::new(RedirectCrateStore::new(store, &path, base));
+    }
+    let max_staleness = serve_config
+        .as_ref()
+        .and_then(|s| s.max_staleness)
+        .map(Duration::from_secs);
+    let (access, server, git_backend, limits) = match &serve_config {
+        Some(serve) => (
+            AccessControl::try_from(serve)?,
+            ServerOptions::from(serve),
+            GitBackend::try_from(serve)?,
+            RequestLimits::try_from(serve)?,
+        ),
+        None => (
+            AccessControl::default(),
+            ServerOptions::default(),
+            GitBackend::default(),
+            RequestLimits::default(),
+        ),
+    };
+
     match (cert_path, key_path) {
         (Some(cert_path), Some(key_path)) => {
             crate::serve::serve(
@@ -313,10 +1381,29 @@ pub async fn serve(
                     cert_path,
                     key_path,
                 }),
+                store,
+                access,
+                server,
+                git_backend,
+                limits,
+                max_staleness,
             )
-            .await
+            .await?
+        }
+        (None, None) => {
+            crate::serve::serve(
+                path,
+                socket_addr,
+                None,
+                store,
+                access,
+                server,
+                git_backend,
+                limits,
+                max_staleness,
+            )
+            .await?
         }
-        (None, None) => crate::serve::serve(path, socket_addr, None).await,
         (Some(_), None) => {
             return Err(MirrorError::CmdLine(
                 "cert_path set but key_path not set.".to_string(),
@@ -332,28 +1419,159 @@ pub async fn serve(
     Ok(())
 }
 
-/// Print out a list of all platforms.
-pub(crate) async fn list_platforms(source: String, channel: String) -> Result<(), MirrorError> {
-    let targets = download_platform_list(source.as_str(), channel.as_str()).await?;
+/// Serve the mirror, while syncing it every `sync_interval` seconds.
+pub async fn run(
+    path: PathBuf,
+    listen: Option<IpAddr>,
+    port: Option<u16>,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    allow_high_concurrency: bool,
+) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    let interval = load_mirror_toml(&path)?
+        .mirror
+        .sync_interval
+        .ok_or_else(|| {
+            MirrorError::Config("sync_interval must be set to use panamax run".to_string())
+        })?;
+    let interval = Duration::from_secs(interval);
+
+    // The server runs on its own task, so it keeps accepting connections while a sync is
+    // busy. Ctrl-C stops the sync cleanly, then the server with it.
+    let server = tokio::spawn(serve(path.clone(), listen, port, cert_path, key_path));
+    let cancel = cancel_on_signal();
+    let sync_loop = async {
+        while !cancel.is_cancelled() {
+            let sync_options = SyncOptions {
+                allow_high_concurrency,
+                ..SyncOptions::default()
+            };
+            if let Err(e) = sync(&path, sync_options, None, cancel.clone()).await {
+                eprintln!("Panamax sync failed! {e}");
+            }
+            if cancel.is_cancelled() {
+                break;
+            }
+            eprintln!("Next sync in {} seconds.", interval.as_secs());
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = cancel.cancelled() => {}
+            }
+        }
+    };
 
-    println!("All currently available platforms for the {channel} channel:");
-    for t in targets {
-        println!("  {t}");
+    // Serving only returns if it fails to start, and the sync loop only once cancelled.
+    let server_handle = server.abort_handle();
+    let res = tokio::select! {
+        res = server => res.map_err(|e| MirrorError::Io(io::Error::other(e)))?,
+        _ = sync_loop => Ok(()),
+    };
+    server_handle.abort();
+    res
+}
+
+/// Format the names listed by `list-platforms` or `list-components`, under a `heading`,
+/// or as a JSON array for scripts.
+fn format_list(heading: &str, names: &[String], json: bool) -> String {
+    if json {
+        serde_json::Value::from(names).to_string()
+    } else {
+        std::iter::once(heading.to_string())
+            .chain(names.iter().map(|name| format!("  {name}")))
+            .collect::<Vec<_>>()
+            .join("\n")
     }
+}
+
+/// Print out a list of all platforms.
+///
+/// If a mirror path is given, the platforms are read from its channel file instead of `source`.
+pub(crate) async fn list_platforms(
+    source: String,
+    channel: String,
+    path: Option<PathBuf>,
+    json: bool,
+) -> Result<(), MirrorError> {
+    let targets = match &path {
+        Some(path) => local_platform_list(path, &channel)?,
+        None => {
+            download_platform_list(
+                source.as_str(),
+                DEFAULT_DIST_PATH_TEMPLATE,
+                channel.as_str(),
+            )
+            .await?
+        }
+    };
+
+    let heading = if path.is_some() {
+        format!("All platforms on the mirror for the {channel} channel:")
+    } else {
+        format!("All currently available platforms for the {channel} channel:")
+    };
+    println!("{}", format_list(&heading, &targets, json));
+
+    Ok(())
+}
+
+pub(crate) async fn list_components(
+    source: String,
+    channel: String,
+    path: Option<PathBuf>,
+    json: bool,
+) -> Result<(), MirrorError> {
+    let channel_str = match &path {
+        Some(path) => local_channel_file(path, &channel)?,
+        None => {
+            download_channel_file(
+                source.as_str(),
+                DEFAULT_DIST_PATH_TEMPLATE,
+                channel.as_str(),
+            )
+            .await?
+        }
+    };
+    let components = components_from_channel(&channel_str)?;
+
+    let heading = if path.is_some() {
+        format!("All components on the mirror for the {channel} channel:")
+    } else {
+        format!("All currently available components for the {channel} channel:")
+    };
+    println!("{}", format_list(&heading, &components, json));
 
     Ok(())
 }
 
-/// Verify coherence between local mirror and local crates.io-index.
-/// This function is bale to fix mirror by downloading missing crates.
-/// Users can alter the actual downloaded file at run time.
+/// Verify the crates of a mirror against its index, downloading missing or mismatched crates.
+///
+/// Verification only reads the local index and crate files. Only repairing the mirror needs
+/// network access, so `offline` requires `dry_run`, and fails if any crate needs repairing.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn verify(
     path: PathBuf,
     dry_run: bool,
+    offline: bool,
     assume_yes: bool,
+    checksum: bool,
+    refresh_older_than: Option<u64>,
     vendor_path: Option<PathBuf>,
-    cargo_lock_filepath: Option<PathBuf>,
+    cargo_lock_paths: Vec<PathBuf>,
+    allow_high_concurrency: bool,
+    events: Option<EventSink>,
 ) -> Result<(), MirrorError> {
+    if offline && !dry_run {
+        return Err(MirrorError::CmdLine(
+            "--offline can't repair the mirror, which needs network access. Add --dry-run to only report problems.".to_string(),
+        ));
+    }
     if !path.join("mirror.toml").exists() {
         eprintln!(
             "Mirror base not found! Run panamax init {} first.",
@@ -361,13 +1579,20 @@ pub(crate) async fn verify(
         );
         return Ok(());
     }
-    let config = load_mirror_toml(&path)?;
+    let mut config = load_mirror_toml(&path)?;
+    // Repairs download crates like a sync does.
+    if let Some(crates) = config.crates.as_mut() {
+        limit_download_threads(crates, allow_high_concurrency);
+    }
 
     // Fail if use_new_crates_format is not true, and old format is detected.
     // If use_new_crates_format is true and new format is detected, warn the user.
     // If use_new_crates_format is true, ignore the format and assume it's new.
     if let Some(config) = &config.crates {
-        if config.sync && !is_new_crates_format(&path.join("crates"))? {
+        if config.sync
+            && config.use_new_crates_format != Some(true)
+            && !is_new_crates_format(&path.join("crates"))?
+        {
             eprintln!("Your crates directory is using the old 0.2 format, however");
             eprintln!("Panamax 0.3+ has deprecated this format for a new one.");
             eprintln!("Please delete crates/ from your mirror directory to continue.");
@@ -375,22 +1600,60 @@ pub(crate) async fn verify(
         }
     }
 
+    if config
+        .crates
+        .as_ref()
+        .is_some_and(|crates| crates.download_crates == Some(false))
+    {
+        eprintln!("Crates downloads are disabled (download_crates = false), nothing to verify.");
+        return Ok(());
+    }
+
+    if config
+        .crates
+        .as_ref()
+        .is_some_and(|crates| crates.storage.as_deref() == Some("s3"))
+    {
+        eprintln!("Crates are stored in S3 (storage = \"s3\"), which verify does not support.");
+        return Ok(());
+    }
+
     eprintln!("{}", style("Verifying mirror state...").bold());
 
     // Getting crates.sync config state
     let crates_config = config.crates.as_ref();
-    let sync = crates_config.map_or(false, |crate_config| crate_config.sync);
+    let sync = crates_config.is_some_and(|crate_config| crate_config.sync);
 
     // Determining number of steps
     let steps = if dry_run || !sync { 1 } else { 2 };
     let mut current_step = 1;
 
+    let floor = crates_config
+        .map(VersionFloor::from_config)
+        .transpose()
+        .map_err(|e| MirrorError::Config(e.to_string()))?
+        .unwrap_or_default();
+
+    let refresh_older_than = refresh_older_than
+        .map(|days| {
+            days.checked_mul(24 * 60 * 60)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    MirrorError::CmdLine(format!("--refresh-older-than {days} is too large"))
+                })
+        })
+        .transpose()?;
+
     if let Some(mut missing_crates) = verify::verify_mirror(
         path.clone(),
         &mut current_step,
         steps,
+        checksum,
+        refresh_older_than,
         vendor_path,
-        cargo_lock_filepath,
+        cargo_lock_paths,
+        &floor,
+        events.as_ref(),
     )
     .await?
     {
@@ -401,6 +1664,9 @@ pub(crate) async fn verify(
             missing_crates.iter().for_each(|c| {
                 println!("Missing crate: {} - version {}", c.get_name(), c.get_vers());
             });
+            if offline {
+                return Err(MirrorError::Unverified(missing_crates.len()));
+            }
             return Ok(());
         }
 
@@ -424,9 +1690,307 @@ pub(crate) async fn verify(
             missing_crates,
             &mut current_step,
             steps,
+            events.as_ref(),
         )
         .await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        default_mirror_toml, default_user_agent, format_list, init_mirror, limit_download_threads,
+        load_mirror_toml, migrate_config, run_post_sync_hook, user_agent, write_mirror_toml,
+        Config, ConfigCrates, ConfigMirror, ConfigRustup, ConfigServe, Contact, InitStatus,
+        MirrorStatus, CONFIG_VERSION, MAX_CRATES_DOWNLOAD_THREADS,
+    };
+    use crate::download::FileSuffixes;
+    use std::collections::HashMap;
+
+    #[test]
+    fn mirror_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        assert_eq!(MirrorStatus::load(path), MirrorStatus::default());
+        assert!(MirrorStatus::default().age().is_none());
+
+        let status = MirrorStatus {
+            last_sync: Some(1_000_000_000),
+        };
+        status.save(path, &FileSuffixes::default()).unwrap();
+        assert_eq!(MirrorStatus::load(path), status);
+        assert!(status.age().unwrap().as_secs() > 365 * 86400);
+    }
+
+    #[test]
+    fn user_agents() {
+        let agent = |contact: &str| {
+            let mirror: ConfigMirror =
+                toml_edit::easy::from_str(&format!("retries = 1\ncontact = {contact}")).unwrap();
+            user_agent(mirror.contact.as_ref())
+        };
+        let version = default_user_agent();
+        assert_eq!(agent(r#""your@email.com""#), version);
+        assert_eq!(
+            agent(r#"" ops@example.com ""#),
+            format!("{version} (ops@example.com)")
+        );
+        assert_eq!(
+            agent(r#"["ops@example.com", "", "oncall@example.com"]"#),
+            format!("{version} (ops@example.com; oncall@example.com)")
+        );
+        assert_eq!(
+            agent(r#"{ email = "ops@example.com", url = "https://status.example.com" }"#),
+            format!("{version} (ops@example.com; https://status.example.com)")
+        );
+        assert_eq!(
+            agent(r#"{ url = "https://status.example.com" }"#),
+            format!("{version} (https://status.example.com)")
+        );
+        assert_eq!(user_agent(Some(&Contact::List(Vec::new()))), version);
+        assert_eq!(user_agent(None), version);
+    }
+
+    #[test]
+    fn download_threads_limit() {
+        let config: Config =
+            toml_edit::easy::from_str(include_str!("mirror.default.toml")).unwrap();
+        let mut crates = config.crates.unwrap();
+        limit_download_threads(&mut crates, false);
+        assert_eq!(crates.download_threads, 64);
+
+        crates.download_threads = 256;
+        limit_download_threads(&mut crates, true);
+        assert_eq!(crates.download_threads, 256);
+        limit_download_threads(&mut crates, false);
+        assert_eq!(crates.download_threads, MAX_CRATES_DOWNLOAD_THREADS);
+    }
+
+    #[test]
+    fn config_migration() {
+        let mut config = "[mirror]\nretries = 5\n"
+            .parse::<toml_edit::Document>()
+            .unwrap();
+        assert!(migrate_config(&mut config).is_empty());
+        assert_eq!(
+            config["mirror"]["config_version"].as_integer(),
+            Some(CONFIG_VERSION)
+        );
+
+        // Newer versions are left alone.
+        let mut config = "[mirror]\nconfig_version = 1000\n"
+            .parse::<toml_edit::Document>()
+            .unwrap();
+        assert!(migrate_config(&mut config).is_empty());
+        assert_eq!(config["mirror"]["config_version"].as_integer(), Some(1000));
+    }
+
+    /// Every field of the config structs must be documented in mirror.default.toml,
+    /// either set or commented out, so `init` exposes all available options.
+    #[test]
+    fn list_output() {
+        let names = vec![
+            "x86_64-unknown-linux-gnu".to_string(),
+            "aarch64-apple-darwin".to_string(),
+        ];
+        assert_eq!(
+            format_list("Platforms:", &names, true),
+            r#"["x86_64-unknown-linux-gnu","aarch64-apple-darwin"]"#
+        );
+        let parsed: Vec<String> =
+            serde_json::from_str(&format_list("Platforms:", &names, true)).unwrap();
+        assert_eq!(parsed, names);
+        assert_eq!(format_list("Platforms:", &[], true), "[]");
+        assert_eq!(
+            format_list("Platforms:", &names, false),
+            "Platforms:\n  x86_64-unknown-linux-gnu\n  aarch64-apple-darwin"
+        );
+    }
+
+    #[test]
+    fn default_toml_documents_all_fields() {
+        // Struct literals are used on purpose: adding a config field breaks this test
+        // until the field is added here, and therefore checked against the template.
+        let config = Config {
+            mirror: ConfigMirror {
+                config_version: Some(0),
+                retries: 0,
+                contact: Some(Contact::One(String::new())),
+                fsync: Some(false),
+                post_sync_hook: Some(String::new()),
+                file_mode: Some(String::new()),
+                dir_mode: Some(String::new()),
+                file_uid: Some(0),
+                file_gid: Some(0),
+                sync_interval: Some(0),
+                parallel_chunks: Some(0),
+                hardlink: Some(false),
+                part_suffix: Some(String::new()),
+                notfound_suffix: Some(String::new()),
+                badsha256_suffix: Some(String::new()),
+            },
+            rustup: Some(ConfigRustup {
+                sync: true,
+                download_threads: 0,
+                source: String::new(),
+                download_dev: Some(false),
+                dev_channels: Some(vec![]),
+                download_gz: Some(false),
+                download_xz: Some(false),
+                download_zst: Some(false),
+                formats: Some(HashMap::new()),
+                platforms_unix: Some(vec![]),
+                platforms_windows: Some(vec![]),
+                extra_std_targets: Some(vec![]),
+                keep_latest_stables: Some(0),
+                keep_latest_betas: Some(0),
+                keep_latest_nightlies: Some(0),
+                keep_beta_dates: Some(vec![]),
+                keep_nightly_dates: Some(vec![]),
+                pinned_rust_versions: Some(vec![]),
+                dist_path_template: Some(String::new()),
+                download_signatures: Some(false),
+            }),
+            crates: Some(ConfigCrates {
+                sync: true,
+                download_threads: 0,
+                source: String::new(),
+                source_index: String::new(),
+                use_new_crates_format: Some(false),
+                base_url: Some(String::new()),
+                max_total_bytes: Some(0),
+                index_snapshot_url: Some(String::new()),
+                use_cdn: Some(false),
+                download_url_template: Some(String::new()),
+                write_sha256: Some(true),
+                download_crates: Some(false),
+                commit_author_name: Some(String::new()),
+                commit_author_email: Some(String::new()),
+                pinned: Some(vec![]),
+                min_version: Some(String::new()),
+                min_versions: Some(HashMap::new()),
+                popularity_file: Some(String::new()),
+                skip_report: Some(String::new()),
+                auth_token: Some(String::new()),
+                netrc_file: Some(String::new()),
+                index_parse_threads: Some(0),
+                index_username: Some(String::new()),
+                index_password: Some(String::new()),
+                index_ssh_key: Some(String::new()),
+                storage: Some(String::new()),
+                s3_endpoint: Some(String::new()),
+                s3_bucket: Some(String::new()),
+                s3_region: Some(String::new()),
+                s3_prefix: Some(String::new()),
+                s3_access_key_id: Some(String::new()),
+                s3_secret_access_key: Some(String::new()),
+                s3_redirect: Some(false),
+                s3_presign_expiry: Some(0),
+            }),
+            serve: Some(ConfigServe {
+                allow_cidrs: Some(vec![]),
+                deny_cidrs: Some(vec![]),
+                http2_max_concurrent_streams: Some(0),
+                http2_keep_alive_interval: Some(0),
+                http1_keep_alive: Some(true),
+                crate_redirect_base: Some(String::new()),
+                git_backend: Some(String::new()),
+                max_concurrent_requests: Some(0),
+                max_concurrent_git: Some(0),
+                max_staleness: Some(0),
+                git_timeout_secs: Some(0),
+            }),
+        };
+
+        let template = include_str!("mirror.default.toml");
+        let documented: Vec<&str> = template
+            .lines()
+            .filter_map(|line| line.trim_start_matches(['#', ' ']).split_once(" ="))
+            .map(|(key, _)| key)
+            .collect();
+
+        let serialized = toml_edit::easy::Value::try_from(&config).unwrap();
+        for (section, fields) in serialized.as_table().unwrap() {
+            for field in fields.as_table().unwrap().keys() {
+                assert!(
+                    documented.contains(&field.as_str()),
+                    "[{section}] {field} is missing from mirror.default.toml"
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn post_sync_hook_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("hook.out");
+        let hook = format!(
+            "echo \"$PANAMAX_SYNC_RESULT $PANAMAX_FAILED_STEPS/$PANAMAX_TOTAL_STEPS\" > '{}'",
+            out.display()
+        );
+        run_post_sync_hook(&hook, dir.path(), "partial", 1, 4).await;
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "partial 1/4\n");
+    }
+
+    #[test]
+    fn init_flags_disable_sync() {
+        for (ignore_rustup, ignore_crates) in [(false, false), (true, false), (false, true)] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path();
+
+            let config = default_mirror_toml(ignore_rustup, ignore_crates).unwrap();
+            assert!(write_mirror_toml(path, &config).unwrap());
+            let config = load_mirror_toml(path).unwrap();
+            assert_eq!(config.rustup.unwrap().sync, !ignore_rustup);
+            assert_eq!(config.crates.unwrap().sync, !ignore_crates);
+
+            // An existing mirror.toml is never overwritten.
+            assert!(!write_mirror_toml(path, "").unwrap());
+        }
+    }
+
+    #[test]
+    fn init_full_config() {
+        // Every option is written, commented out unless it is set.
+        let template = include_str!("mirror.default.toml");
+        assert_eq!(default_mirror_toml(false, false).unwrap(), template);
+        let config = default_mirror_toml(true, true).unwrap();
+        assert!(config.contains("# post_sync_hook = "));
+        assert!(config.contains("[serve]"));
+        assert_eq!(config.lines().count(), template.lines().count());
+    }
+
+    #[test]
+    fn init_mirrors_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let config = default_mirror_toml(true, false).unwrap();
+
+        let statuses: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let (path, config) = (base.join(format!("mirror-{i}")), &config);
+                    s.spawn(move || init_mirror(&path, config, true, false).unwrap())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(statuses, [InitStatus::Created; 4]);
+        assert!(base.join("mirror-3/crates.io-index").is_dir());
+        assert!(
+            !load_mirror_toml(&base.join("mirror-3"))
+                .unwrap()
+                .rustup
+                .unwrap()
+                .sync
+        );
+
+        assert_eq!(
+            init_mirror(&base.join("mirror-0"), &config, true, false).unwrap(),
+            InitStatus::AlreadyExists
+        );
+    }
+}
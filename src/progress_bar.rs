@@ -1,4 +1,18 @@
+use crate::download::{ByteProgress, DownloadStatus};
 use console::{pad_str, style};
+use indicatif::style::ProgressTracker;
+use indicatif::{
+    HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressState,
+    ProgressStyle,
+};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinHandle;
 
 pub fn current_step_prefix(step: usize, steps: usize) -> String {
     style(format!("[{step}/{steps}]")).bold().to_string()
@@ -13,3 +27,392 @@ pub fn padded_prefix_message(step: usize, steps: usize, msg: &str) -> String {
     )
     .to_string()
 }
+
+/// Set once progress events are printed, so that no progress bars are drawn.
+static PROGRESS_BARS_HIDDEN: AtomicBool = AtomicBool::new(false);
+
+/// Stop drawing the progress bars created from now on.
+pub fn hide_progress_bars() {
+    PROGRESS_BARS_HIDDEN.store(true, Ordering::Relaxed);
+}
+
+fn draw_target() -> ProgressDrawTarget {
+    if PROGRESS_BARS_HIDDEN.load(Ordering::Relaxed) {
+        ProgressDrawTarget::hidden()
+    } else {
+        ProgressDrawTarget::stderr()
+    }
+}
+
+/// A progress bar of `len` steps, drawn unless progress bars are hidden.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    ProgressBar::with_draw_target(Some(len), draw_target())
+}
+
+/// A spinner, drawn unless progress bars are hidden.
+pub fn progress_spinner() -> ProgressBar {
+    ProgressBar::with_draw_target(None, draw_target())
+}
+
+/// A progress bar counting files.
+pub fn panamax_progress_bar(size: usize, prefix: String) -> ProgressBar {
+    progress_bar(size as u64)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
+                )
+                .expect("template is correct")
+                .progress_chars("█▉▊▋▌▍▎▏  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix)
+}
+
+/// Counts of the files of a download phase.
+#[derive(Debug, Default)]
+struct DownloadStats {
+    fetched: u64,
+    skipped: u64,
+    bytes: u64,
+}
+
+impl DownloadStats {
+    /// Estimate the time left to go through `remaining` files, from the rate bytes were downloaded at.
+    ///
+    /// Remaining files are assumed to be already present in the same proportion as the files done
+    /// so far, since skipping those is quick compared to downloading.
+    fn eta(&self, remaining: u64, elapsed: Duration) -> Option<Duration> {
+        if self.fetched == 0 || self.bytes == 0 || elapsed.is_zero() {
+            return None;
+        }
+        let done = (self.fetched + self.skipped) as f64;
+        let bytes_per_fetch = self.bytes as f64 / self.fetched as f64;
+        let remaining_bytes = remaining as f64 * (self.fetched as f64 / done) * bytes_per_fetch;
+        let rate = self.bytes as f64 / elapsed.as_secs_f64();
+        Some(Duration::from_secs_f64(remaining_bytes / rate))
+    }
+
+    fn message(&self, remaining: u64, elapsed: Duration) -> String {
+        let mut msg = format!(
+            "{} fetched, {} present, {}",
+            self.fetched,
+            self.skipped,
+            HumanBytes(self.bytes)
+        );
+        if let Some(eta) = self.eta(remaining, elapsed) {
+            msg.push_str(&format!(", ETA {}", HumanDuration(eta)));
+        }
+        msg
+    }
+}
+
+/// How far back the download rate is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The recent download rate, from the bytes counted by a `DownloadProgress`.
+///
+/// This replaces indicatif's own `{binary_bytes_per_sec}`, which goes by the position of the
+/// bar, i.e. files rather than bytes. The bar's state is locked while this is ticked, so the
+/// byte count is atomic rather than behind the stats' lock.
+#[derive(Clone)]
+struct ByteRate {
+    bytes: Arc<AtomicU64>,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl ByteRate {
+    fn record(&mut self, now: Instant, bytes: u64) {
+        self.samples.push_back((now, bytes));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the samples in the window.
+    fn rate(&self) -> u64 {
+        let (Some((start, first)), Some((end, last))) = (self.samples.front(), self.samples.back())
+        else {
+            return 0;
+        };
+        let elapsed = end.duration_since(*start).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0;
+        }
+        (last.saturating_sub(*first) as f64 / elapsed) as u64
+    }
+}
+
+impl ProgressTracker for ByteRate {
+    fn clone_box(&self) -> Box<dyn ProgressTracker> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, _: &ProgressState, now: Instant) {
+        self.record(now, self.bytes.load(Ordering::Relaxed));
+    }
+
+    fn reset(&mut self, _: &ProgressState, _: Instant) {
+        self.samples.clear();
+    }
+
+    fn write(&self, _: &ProgressState, w: &mut dyn fmt::Write) {
+        let _ = write!(w, "{}/s", HumanBytes(self.rate()));
+    }
+}
+
+/// A progress bar for a download phase, which tells files that were downloaded apart from
+/// files that were already present, and estimates the time left from the download rate.
+#[derive(Clone)]
+pub struct DownloadProgress {
+    pb: ProgressBar,
+    stats: Arc<Mutex<DownloadStats>>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl DownloadProgress {
+    pub fn new(len: usize, prefix: String) -> Self {
+        let bytes: Arc<AtomicU64> = Arc::default();
+        let rate = ByteRate {
+            bytes: bytes.clone(),
+            samples: VecDeque::new(),
+        };
+        let pb = progress_bar(len as u64)
+            .with_style(
+                ProgressStyle::default_bar()
+                    .with_key("binary_bytes_per_sec", rate)
+                    .template(
+                        "{prefix} {wide_bar} {pos}/{len} {binary_bytes_per_sec} {msg} [{elapsed_precise}]",
+                    )
+                    .expect("template is correct")
+                    .progress_chars("█▉▊▋▌▍▎▏  "),
+            )
+            .with_finish(ProgressFinish::AndLeave)
+            .with_prefix(prefix);
+        Self {
+            pb,
+            stats: Arc::default(),
+            bytes,
+        }
+    }
+
+    pub fn bar(&self) -> &ProgressBar {
+        &self.pb
+    }
+
+    /// A callback to pass as `DownloadOptions::on_bytes`.
+    pub fn on_bytes(&self) -> ByteProgress {
+        let progress = self.clone();
+        ByteProgress::new(move |n| {
+            progress.bytes.fetch_add(n, Ordering::Relaxed);
+            progress.update(|stats| stats.bytes += n)
+        })
+    }
+
+    /// Record a file as done, with the status of its download if it succeeded.
+    pub fn finish_file(&self, status: Option<DownloadStatus>) {
+        self.update(|stats| match status {
+            Some(DownloadStatus::Fetched) => stats.fetched += 1,
+            Some(DownloadStatus::Skipped) => stats.skipped += 1,
+            None => {}
+        });
+        self.pb.inc(1);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut DownloadStats)) {
+        let Ok(mut stats) = self.stats.lock() else {
+            return;
+        };
+        f(&mut stats);
+        let remaining = self
+            .pb
+            .length()
+            .unwrap_or(0)
+            .saturating_sub(self.pb.position());
+        self.pb
+            .set_message(stats.message(remaining, self.pb.elapsed()));
+    }
+}
+
+/// A progress event, for programs driving a sync rather than watching the progress bars.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A phase started, with the number of items it will go through if known.
+    PhaseStarted {
+        phase: String,
+        items: Option<usize>,
+    },
+    ItemCompleted {
+        phase: String,
+        item: String,
+    },
+    ItemFailed {
+        phase: String,
+        item: String,
+        error: String,
+    },
+    PhaseFinished {
+        phase: String,
+    },
+}
+
+/// Where progress events are sent. Events are dropped once the receiver is gone.
+#[derive(Debug, Clone)]
+pub struct EventSink(UnboundedSender<ProgressEvent>);
+
+impl EventSink {
+    pub fn new(sender: UnboundedSender<ProgressEvent>) -> Self {
+        Self(sender)
+    }
+
+    fn send(&self, event: ProgressEvent) {
+        let _ = self.0.send(event);
+    }
+}
+
+/// Print events to stdout as JSON lines, until every sender of the returned sink is dropped.
+///
+/// Progress bars are hidden from then on, as the events replace them.
+pub fn print_events() -> (EventSink, JoinHandle<()>) {
+    hide_progress_bars();
+    let (sender, mut receiver) = unbounded_channel();
+    let printer = tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            if let Ok(line) = serde_json::to_string(&event) {
+                println!("{line}");
+            }
+        }
+    });
+    (EventSink::new(sender), printer)
+}
+
+/// The events of one phase, which do nothing without a sink.
+///
+/// The phase is started on creation, and finished when this is dropped.
+pub struct PhaseEvents {
+    sink: Option<EventSink>,
+    phase: String,
+}
+
+impl PhaseEvents {
+    pub fn start(sink: Option<&EventSink>, phase: &str, items: Option<usize>) -> Self {
+        let phase = phase.to_string();
+        if let Some(sink) = sink {
+            sink.send(ProgressEvent::PhaseStarted {
+                phase: phase.clone(),
+                items,
+            });
+        }
+        Self {
+            sink: sink.cloned(),
+            phase,
+        }
+    }
+
+    pub fn completed(&self, item: &str) {
+        if let Some(sink) = &self.sink {
+            sink.send(ProgressEvent::ItemCompleted {
+                phase: self.phase.clone(),
+                item: item.to_string(),
+            });
+        }
+    }
+
+    pub fn failed(&self, item: &str, error: &dyn Display) {
+        if let Some(sink) = &self.sink {
+            sink.send(ProgressEvent::ItemFailed {
+                phase: self.phase.clone(),
+                item: item.to_string(),
+                error: error.to_string(),
+            });
+        }
+    }
+}
+
+impl Drop for PhaseEvents {
+    fn drop(&mut self) {
+        if let Some(sink) = &self.sink {
+            sink.send(ProgressEvent::PhaseFinished {
+                phase: std::mem::take(&mut self.phase),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ByteRate, DownloadStats, EventSink, PhaseEvents};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn download_eta() {
+        let mut stats = DownloadStats::default();
+        assert_eq!(stats.eta(100, Duration::from_secs(10)), None);
+
+        // 10 crates of 1000 bytes were downloaded in 10s, and 90 were already present.
+        stats.fetched = 10;
+        stats.skipped = 90;
+        stats.bytes = 10_000;
+        // A tenth of the 1000 remaining crates are expected to be downloaded, at 1000 bytes/s.
+        assert_eq!(
+            stats.eta(1000, Duration::from_secs(10)),
+            Some(Duration::from_secs(100))
+        );
+        assert_eq!(
+            stats.message(1000, Duration::from_secs(10)),
+            "10 fetched, 90 present, 9.77 KiB, ETA 2 minutes"
+        );
+    }
+
+    #[test]
+    fn byte_rate() {
+        let mut rate = ByteRate {
+            bytes: Default::default(),
+            samples: Default::default(),
+        };
+        assert_eq!(rate.rate(), 0);
+
+        let start = Instant::now();
+        rate.record(start, 0);
+        assert_eq!(rate.rate(), 0);
+        rate.record(start + Duration::from_secs(2), 4096);
+        assert_eq!(rate.rate(), 2048);
+        // Only the last seconds count, so a stalled download shows up quickly.
+        rate.record(start + Duration::from_secs(6), 4096);
+        rate.record(start + Duration::from_secs(8), 4096);
+        assert_eq!(rate.rate(), 0);
+    }
+
+    #[test]
+    fn phase_events() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let sink = EventSink::new(sender);
+        {
+            let phase = PhaseEvents::start(Some(&sink), "crates", Some(2));
+            phase.completed("serde@1.0.0");
+            phase.failed("rand@0.8.0", &"not found");
+        }
+        // Without a sink, nothing is sent.
+        PhaseEvents::start(None, "verify", None).completed("serde@1.0.0");
+        drop(sink);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.blocking_recv() {
+            events.push(serde_json::to_string(&event).unwrap());
+        }
+        assert_eq!(
+            events,
+            [
+                r#"{"event":"phase_started","phase":"crates","items":2}"#,
+                r#"{"event":"item_completed","phase":"crates","item":"serde@1.0.0"}"#,
+                r#"{"event":"item_failed","phase":"crates","item":"rand@0.8.0","error":"not found"}"#,
+                r#"{"event":"phase_finished","phase":"crates"}"#,
+            ]
+        );
+    }
+}
@@ -1,13 +1,15 @@
 use crate::download::{
     append_to_path, copy_file_create_dir_with_sha256, download, download_string,
     download_with_sha256_file, move_if_exists, move_if_exists_with_sha256, write_file_create_dir,
-    DownloadError,
+    DownloadError, DownloadOptions, DownloadStatus, DryRunSummary,
+};
+use crate::mirror::{ConfigRustup, MirrorError};
+use crate::progress_bar::{
+    current_step_prefix, padded_prefix_message, panamax_progress_bar, DownloadProgress, PhaseEvents,
 };
-use crate::mirror::{ConfigMirror, ConfigRustup, MirrorError};
-use crate::progress_bar::{current_step_prefix, padded_prefix_message};
 use console::style;
 use futures::StreamExt;
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use indicatif::ProgressBar;
 use reqwest::header::HeaderValue;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -49,6 +51,9 @@ pub enum SyncError {
 
     #[error("Failed {count} downloads")]
     FailedDownloads { count: usize },
+
+    #[error("Interrupted")]
+    Interrupted,
 }
 
 #[derive(Deserialize, Debug)]
@@ -57,26 +62,114 @@ pub struct TargetUrls {
     pub hash: String,
     pub xz_url: String,
     pub xz_hash: String,
+    pub zst_url: Option<String>,
+    pub zst_hash: Option<String>,
+}
+
+impl TargetUrls {
+    /// The (url, hash) pairs of every format that is both wanted and available.
+    fn selected(self, formats: DistFormats) -> Vec<(String, String)> {
+        [
+            (formats.gz, Some(self.url), Some(self.hash)),
+            (formats.xz, Some(self.xz_url), Some(self.xz_hash)),
+            (formats.zst, self.zst_url, self.zst_hash),
+        ]
+        .into_iter()
+        .filter_map(|(wanted, url, hash)| Some((url?, hash?)).filter(|_| wanted))
+        .collect()
+    }
+}
+
+/// Compression formats of rustup components to download.
+#[derive(Debug, Clone, Copy)]
+pub struct DistFormats {
+    pub gz: bool,
+    pub xz: bool,
+    pub zst: bool,
+}
+
+impl DistFormats {
+    pub fn from_config(rustup: &ConfigRustup) -> Self {
+        Self {
+            gz: rustup.download_gz.unwrap_or(false),
+            xz: rustup.download_xz.unwrap_or(true),
+            zst: rustup.download_zst.unwrap_or(false),
+        }
+    }
+
+    /// Parse a list of formats, such as `["xz", "zst"]`.
+    fn parse(formats: &[String]) -> Result<Self, MirrorError> {
+        let mut selected = Self {
+            gz: false,
+            xz: false,
+            zst: false,
+        };
+        for format in formats {
+            match format.as_str() {
+                "gz" => selected.gz = true,
+                "xz" => selected.xz = true,
+                "zst" => selected.zst = true,
+                _ => {
+                    return Err(MirrorError::Config(format!(
+                        "unknown rustup format \"{format}\", expected gz, xz or zst"
+                    )))
+                }
+            }
+        }
+        Ok(selected)
+    }
+}
+
+/// Formats to download, from `download_gz`, `download_xz` and `download_zst`, overridden
+/// for specific platforms or channels by `[rustup.formats]`.
+#[derive(Debug, Clone)]
+pub struct FormatRules {
+    default: DistFormats,
+    rules: HashMap<String, DistFormats>,
+}
+
+impl FormatRules {
+    pub fn from_config(rustup: &ConfigRustup) -> Result<Self, MirrorError> {
+        Ok(Self {
+            default: DistFormats::from_config(rustup),
+            rules: rustup
+                .formats
+                .iter()
+                .flatten()
+                .map(|(key, formats)| Ok((key.clone(), DistFormats::parse(formats)?)))
+                .collect::<Result<_, MirrorError>>()?,
+        })
+    }
+
+    /// The formats of a platform in a channel. A rule for the platform takes precedence
+    /// over a rule for the channel, which matches its pinned versions too.
+    pub fn get(&self, channel: &str, platform: &str) -> DistFormats {
+        self.rules
+            .get(platform)
+            .or_else(|| {
+                self.rules
+                    .iter()
+                    .find(|(c, _)| channel_matches(channel, c))
+                    .map(|(_, formats)| formats)
+            })
+            .copied()
+            .unwrap_or(self.default)
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Target {
-    pub available: bool,
-
     #[serde(flatten)]
     pub target_urls: Option<TargetUrls>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Pkg {
-    pub version: String,
     pub target: HashMap<String, Target>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Channel {
-    #[serde(alias = "manifest-version")]
-    pub manifest_version: String,
     pub date: String,
     pub pkg: HashMap<String, Pkg>,
 }
@@ -90,6 +183,8 @@ struct Release {
 pub struct Platforms {
     unix: Vec<String>,
     windows: Vec<String>,
+    /// Targets to only download rust-std for, from `extra_std_targets`.
+    std_only: Vec<String>,
 }
 
 impl Platforms {
@@ -105,17 +200,72 @@ impl Platforms {
     pub fn len(&self) -> usize {
         self.unix.len() + self.windows.len()
     }
+
+    /// Whether to download the `pkg_name` package for `platform`.
+    fn includes(&self, pkg_name: &str, platform: &String) -> bool {
+        // The * platform contains rust-src, always download
+        self.contains(platform)
+            || platform == "*"
+            || (pkg_name == "rust-std" && self.std_only.contains(platform))
+    }
 }
 
-pub async fn download_platform_list(
+/// Download the channel file of `channel` from `source`.
+pub async fn download_channel_file(
     source: &str,
+    dist_path_template: &str,
     channel: &str,
-) -> Result<Vec<String>, MirrorError> {
-    let channel_url = format!("{source}/dist/channel-rust-{channel}.toml");
+) -> Result<String, MirrorError> {
+    let channel_url = format!(
+        "{source}/{}",
+        channel_manifest_path(dist_path_template, channel, None)
+    );
     let user_agent = HeaderValue::from_str(&format!("Panamax/{}", env!("CARGO_PKG_VERSION")))
         .expect("Hardcoded user agent string should never fail.");
-    let channel_str = download_string(&channel_url, &user_agent).await?;
-    let channel_data: Channel = toml_edit::easy::from_str(&channel_str)?;
+    Ok(download_string(&channel_url, &user_agent).await?)
+}
+
+pub async fn download_platform_list(
+    source: &str,
+    dist_path_template: &str,
+    channel: &str,
+) -> Result<Vec<String>, MirrorError> {
+    platforms_from_channel(&download_channel_file(source, dist_path_template, channel).await?)
+}
+
+/// Read the channel file of `channel` from a mirror.
+pub fn local_channel_file(path: &Path, channel: &str) -> Result<String, MirrorError> {
+    let (channel_name, date) = split_channel_date(channel);
+    let channel_path = path.join(channel_manifest_path(
+        DEFAULT_DIST_PATH_TEMPLATE,
+        channel_name,
+        date,
+    ));
+    let channel_str = fs::read_to_string(&channel_path).map_err(|e| {
+        MirrorError::CmdLine(format!(
+            "could not read {}: {e}. Has the {channel} channel been synced?",
+            channel_path.display()
+        ))
+    })?;
+    Ok(channel_str)
+}
+
+/// Read the list of platforms from a channel file that has already been mirrored.
+pub fn local_platform_list(path: &Path, channel: &str) -> Result<Vec<String>, MirrorError> {
+    platforms_from_channel(&local_channel_file(path, channel)?)
+}
+
+/// Get the sorted list of components, i.e. packages, from the contents of a channel file.
+pub fn components_from_channel(channel_str: &str) -> Result<Vec<String>, MirrorError> {
+    let channel_data: Channel = toml_edit::easy::from_str(channel_str)?;
+    let mut components: Vec<String> = channel_data.pkg.into_keys().collect();
+    components.sort();
+    Ok(components)
+}
+
+/// Get the sorted list of platforms from the contents of a channel file.
+fn platforms_from_channel(channel_str: &str) -> Result<Vec<String>, MirrorError> {
+    let channel_data: Channel = toml_edit::easy::from_str(channel_str)?;
 
     let mut targets = HashSet::new();
 
@@ -134,11 +284,79 @@ pub async fn download_platform_list(
     Ok(targets)
 }
 
-pub async fn get_platforms(rustup: &ConfigRustup) -> Result<Platforms, MirrorError> {
-    let all = download_platform_list(&rustup.source, "nightly").await?;
+/// The platforms to sync: `only` if it isn't empty, e.g. from `sync --platform`,
+/// or the configured ones otherwise.
+///
+/// Platforms are checked against today's nightly and the channels already in the mirror at
+/// `path`. Unknown configured platforms are skipped with a warning, rather than failing the
+/// whole sync.
+pub async fn get_platforms(
+    path: &Path,
+    rustup: &ConfigRustup,
+    only: &[String],
+) -> Result<Platforms, MirrorError> {
+    let dist_path_template = rustup
+        .dist_path_template
+        .as_deref()
+        .unwrap_or(DEFAULT_DIST_PATH_TEMPLATE);
+    let all = download_platform_list(&rustup.source, dist_path_template, "nightly").await?;
+
+    // Older mirrored channels may have targets that nightly has since dropped.
+    let mut available = all.clone();
+    let mirrored = ["stable", "beta", "nightly"].iter().copied().chain(
+        rustup
+            .pinned_rust_versions
+            .iter()
+            .flatten()
+            .map(String::as_str),
+    );
+    for channel in mirrored {
+        if let Ok(platforms) = local_platform_list(path, channel) {
+            available.extend(platforms);
+        }
+    }
+
+    if !only.is_empty() {
+        let unknown = unknown_platforms(only, &available);
+        if !unknown.is_empty() {
+            return Err(MirrorError::CmdLine(format!(
+                "unknown platforms {}, run `panamax list-platforms` to see the available ones",
+                unknown.join(", ")
+            )));
+        }
+        let (windows, unix) = only
+            .iter()
+            .cloned()
+            .partition(|p| PLATFORMS_WINDOWS.contains(&p.as_str()));
+        return Ok(Platforms {
+            unix,
+            windows,
+            std_only: Vec::new(),
+        });
+    }
+
+    // A typo would otherwise leave the mirror quietly missing a platform.
+    let configured = [
+        &rustup.platforms_unix,
+        &rustup.platforms_windows,
+        &rustup.extra_std_targets,
+    ];
+    let unknown = unknown_platforms(configured.into_iter().flatten().flatten(), &available);
+    if !unknown.is_empty() {
+        eprintln!(
+            "{}",
+            style(format!(
+                "Warning: skipping unknown platforms in [rustup]: {}. Run `panamax list-platforms` to see the available ones.",
+                unknown.join(", ")
+            ))
+            .yellow()
+            .bold()
+        );
+    }
+    let known = |p: &&String| !unknown.contains(&p.as_str());
 
-    let unix = match &rustup.platforms_unix {
-        Some(p) => p.clone(),
+    let unix: Vec<String> = match &rustup.platforms_unix {
+        Some(p) => p.iter().filter(known).cloned().collect(),
         None => all
             .iter()
             .filter(|x| !PLATFORMS_WINDOWS.contains(&x.as_str()))
@@ -146,11 +364,36 @@ pub async fn get_platforms(rustup: &ConfigRustup) -> Result<Platforms, MirrorErr
             .collect(),
     };
 
-    let windows = match &rustup.platforms_windows {
-        Some(p) => p.clone(),
+    let windows: Vec<String> = match &rustup.platforms_windows {
+        Some(p) => p.iter().filter(known).cloned().collect(),
         None => PLATFORMS_WINDOWS.iter().map(|x| x.to_string()).collect(),
     };
-    Ok(Platforms { unix, windows })
+    // Targets that are already full platforms get their full toolchain anyway.
+    let std_only = rustup
+        .extra_std_targets
+        .iter()
+        .flatten()
+        .filter(known)
+        .filter(|t| !unix.contains(t) && !windows.contains(t))
+        .cloned()
+        .collect();
+    Ok(Platforms {
+        unix,
+        windows,
+        std_only,
+    })
+}
+
+/// The `platforms` that aren't in the `available` list.
+fn unknown_platforms<'a>(
+    platforms: impl IntoIterator<Item = &'a String>,
+    available: &[String],
+) -> Vec<&'a str> {
+    platforms
+        .into_iter()
+        .filter(|p| !available.contains(p))
+        .map(String::as_str)
+        .collect()
 }
 
 /// Synchronize one rustup-init file.
@@ -162,7 +405,7 @@ pub async fn sync_one_init(
     platform: &str,
     is_exe: bool,
     rustup_version: &str,
-    retries: usize,
+    options: &DownloadOptions,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
     let local_path = path
@@ -188,26 +431,12 @@ pub async fn sync_one_init(
         format!("{source}/rustup/dist/{platform}/rustup-init")
     };
 
-    download_with_sha256_file(client, &source_url, &local_path, retries, false, user_agent).await?;
-    copy_file_create_dir_with_sha256(&local_path, &archive_path)?;
+    download_with_sha256_file(client, &source_url, &local_path, options, false, user_agent).await?;
+    copy_file_create_dir_with_sha256(&local_path, &archive_path, options)?;
 
     Ok(())
 }
 
-fn panamax_progress_bar(size: usize, prefix: String) -> ProgressBar {
-    ProgressBar::new(size as u64)
-        .with_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
-                )
-                .expect("template is correct")
-                .progress_chars("█▉▊▋▌▍▎▏  "),
-        )
-        .with_finish(ProgressFinish::AndLeave)
-        .with_prefix(prefix)
-}
-
 #[allow(clippy::too_many_arguments)]
 async fn create_sync_tasks(
     platforms: &[String],
@@ -215,13 +444,14 @@ async fn create_sync_tasks(
     rustup_version: &str,
     path: &Path,
     source: &str,
-    retries: usize,
+    options: &DownloadOptions,
     user_agent: &HeaderValue,
     threads: usize,
     pb: &ProgressBar,
-) -> Vec<Result<Result<(), DownloadError>, JoinError>> {
+) -> Vec<Result<(String, Result<(), DownloadError>), JoinError>> {
     let client = Client::new();
     futures::stream::iter(platforms.iter())
+        .take_while(|_| futures::future::ready(!options.cancel.is_cancelled()))
         .map(|platform| {
             let client = client.clone();
             let rustup_version = rustup_version.to_string();
@@ -229,6 +459,7 @@ async fn create_sync_tasks(
             let source = source.to_string();
             let user_agent = user_agent.clone();
             let platform = platform.clone();
+            let options = options.clone();
             let pb = pb.clone();
 
             tokio::spawn(async move {
@@ -239,14 +470,14 @@ async fn create_sync_tasks(
                     platform.as_str(),
                     is_exe,
                     &rustup_version,
-                    retries,
+                    &options,
                     &user_agent,
                 )
                 .await;
 
                 pb.inc(1);
 
-                out
+                (platform, out)
             })
         })
         .buffer_unordered(threads)
@@ -260,7 +491,7 @@ pub async fn sync_rustup_init(
     threads: usize,
     source: &str,
     prefix: String,
-    retries: usize,
+    options: &DownloadOptions,
     user_agent: &HeaderValue,
     platforms: &Platforms,
 ) -> Result<(), SyncError> {
@@ -271,14 +502,14 @@ pub async fn sync_rustup_init(
     // Download rustup release file
     let release_url = format!("{source}/rustup/release-stable.toml");
     let release_path = path.join("rustup/release-stable.toml");
-    let release_part_path = append_to_path(&release_path, ".part");
+    let release_part_path = append_to_path(&release_path, &options.suffixes.part);
 
     download(
         &client,
         &release_url,
         &release_part_path,
         None,
-        retries,
+        options,
         false,
         user_agent,
     )
@@ -290,6 +521,11 @@ pub async fn sync_rustup_init(
 
     let pb = panamax_progress_bar(platforms.len(), prefix);
     pb.enable_steady_tick(Duration::from_millis(10));
+    let events = PhaseEvents::start(
+        options.events.as_ref(),
+        "rustup-init",
+        Some(platforms.len()),
+    );
 
     let unix_tasks = create_sync_tasks(
         &platforms.unix,
@@ -297,7 +533,7 @@ pub async fn sync_rustup_init(
         &rustup_version,
         path,
         source,
-        retries,
+        options,
         user_agent,
         threads,
         &pb,
@@ -310,7 +546,7 @@ pub async fn sync_rustup_init(
         &rustup_version,
         path,
         source,
-        retries,
+        options,
         user_agent,
         threads,
         &pb,
@@ -319,12 +555,13 @@ pub async fn sync_rustup_init(
 
     for res in unix_tasks.into_iter().chain(win_tasks) {
         // Unwrap the join result.
-        let res = res.unwrap();
+        let (platform, res) = res.unwrap();
 
-        if let Err(e) = res {
-            match e {
-                DownloadError::NotFound { .. } => {}
-                _ => {
+        match res {
+            Ok(()) => events.completed(&platform),
+            Err(e) => {
+                events.failed(&platform, &e);
+                if !matches!(e, DownloadError::NotFound { .. }) {
                     errors_occurred += 1;
                     eprintln!("Download failed: {e:?}");
                 }
@@ -332,25 +569,44 @@ pub async fn sync_rustup_init(
         }
     }
 
-    if errors_occurred == 0 {
-        Ok(())
-    } else {
+    if errors_occurred > 0 {
         Err(SyncError::FailedDownloads {
             count: errors_occurred,
         })
+    } else if options.cancel.is_cancelled() {
+        Err(SyncError::Interrupted)
+    } else {
+        Ok(())
     }
 }
 
-/// Get the rustup file downloads, in pairs of URLs and sha256 hashes.
+/// Get the rustup file downloads of `channel`, in pairs of URLs and sha256 hashes.
 pub fn rustup_download_list(
     path: &Path,
+    channel: &str,
     download_dev: bool,
-    download_gz: bool,
-    download_xz: bool,
+    formats: &FormatRules,
     platforms: &Platforms,
 ) -> Result<(String, Vec<(String, String)>), SyncError> {
     let channel_str = fs::read_to_string(path).map_err(DownloadError::Io)?;
-    let channel: Channel = toml_edit::easy::from_str(&channel_str)?;
+    Ok(download_list_from_str(
+        &channel_str,
+        channel,
+        download_dev,
+        formats,
+        platforms,
+    )?)
+}
+
+/// Like `rustup_download_list`, from the contents of a channel file.
+fn download_list_from_str(
+    channel_str: &str,
+    channel_name: &str,
+    download_dev: bool,
+    formats: &FormatRules,
+    platforms: &Platforms,
+) -> Result<(String, Vec<(String, String)>), toml_edit::de::Error> {
+    let channel: Channel = toml_edit::easy::from_str(channel_str)?;
 
     Ok((
         channel.date,
@@ -358,26 +614,14 @@ pub fn rustup_download_list(
             .pkg
             .into_iter()
             .filter(|(pkg_name, _)| download_dev || pkg_name != "rustc-dev")
-            .flat_map(|(_, pkg)| {
+            .flat_map(|(pkg_name, pkg)| {
                 pkg.target
                     .into_iter()
-                    .filter(
-                        |(name, _)| platforms.contains(name) || name == "*", // The * platform contains rust-src, always download
-                    )
-                    .flat_map(|(_, target)| -> Vec<(String, String)> {
+                    .filter(move |(name, _)| platforms.includes(&pkg_name, name))
+                    .flat_map(|(name, target)| -> Vec<(String, String)> {
                         target
                             .target_urls
-                            .map(|urls| {
-                                let mut v = Vec::new();
-                                if download_gz {
-                                    v.push((urls.url, urls.hash));
-                                }
-                                if download_xz {
-                                    v.push((urls.xz_url, urls.xz_hash));
-                                }
-
-                                v
-                            })
+                            .map(|urls| urls.selected(formats.get(channel_name, &name)))
                             .into_iter()
                             .flatten()
                             .map(|(url, hash)| {
@@ -390,15 +634,17 @@ pub fn rustup_download_list(
     ))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn sync_one_rustup_target(
     client: &Client,
     path: &Path,
     source: &str,
     url: &str,
     hash: &str,
-    retries: usize,
+    options: &DownloadOptions,
+    force_download: bool,
     user_agent: &HeaderValue,
-) -> Result<(), DownloadError> {
+) -> Result<DownloadStatus, DownloadError> {
     // Chop off the source portion of the URL, to mimic the rest of the path
     //let target_url = path.join(url[source.len()..].trim_start_matches("/"));
     let target_url = format!("{source}/{url}");
@@ -411,8 +657,8 @@ pub async fn sync_one_rustup_target(
         &target_url,
         &target_path,
         Some(hash),
-        retries,
-        false,
+        options,
+        force_download,
         user_agent,
     )
     .await
@@ -421,6 +667,9 @@ pub async fn sync_one_rustup_target(
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChannelHistoryFile {
     pub versions: HashMap<String, Vec<String>>,
+    /// Files already downloaded for a date whose sync hasn't fully completed yet.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub partial: HashMap<String, Vec<String>>,
 }
 
 pub fn latest_dates_from_channel_history(
@@ -438,12 +687,25 @@ pub fn latest_dates_from_channel_history(
     dates
 }
 
+/// Add the files of a partially synced date to the set of files to keep,
+/// so the next sync can resume it.
+fn keep_partial_files(history: &ChannelHistoryFile, files_to_keep: &mut HashSet<PathBuf>) {
+    for t in history.partial.values().flatten() {
+        files_to_keep.insert(t.split('/').collect());
+    }
+}
+
+/// Delete the files of dated releases that are no longer kept from `dist`.
+///
+/// `dist` itself may be a symlink, e.g. to another volume, but symlinks inside it are
+/// neither followed nor removed, so cleanup never deletes files elsewhere through them.
 pub fn clean_old_files(
     path: &Path,
     keep_stables: Option<usize>,
     keep_betas: Option<usize>,
     keep_nightlies: Option<usize>,
     pinned_rust_versions: Option<&Vec<String>>,
+    kept_dates: &[(&str, &[String])],
     prefix: String,
 ) -> Result<(), SyncError> {
     let versions = [
@@ -460,6 +722,7 @@ pub fn clean_old_files(
                 Ok(c) => c,
                 Err(_) => continue,
             };
+            keep_partial_files(&history, &mut files_to_keep);
             let latest_dates = latest_dates_from_channel_history(&history, s);
             for date in latest_dates {
                 if let Some(t) = history.versions.get_mut(&date) {
@@ -479,6 +742,7 @@ pub fn clean_old_files(
                 Ok(c) => c,
                 Err(_) => continue,
             };
+            keep_partial_files(&pinned, &mut files_to_keep);
             let latest_dates = latest_dates_from_channel_history(&pinned, 1);
             for date in latest_dates {
                 if let Some(t) = pinned.versions.get_mut(&date) {
@@ -493,15 +757,33 @@ pub fn clean_old_files(
         }
     }
 
+    // Specific dates of a channel, kept regardless of how many latest versions are kept.
+    for (channel, dates) in kept_dates {
+        let Ok(history) = get_channel_history(path, channel) else {
+            continue;
+        };
+        for date in dates.iter() {
+            if let Some(t) = history.versions.get(date) {
+                files_to_keep.extend(t.iter().map(|t| t.split('/').collect::<PathBuf>()));
+            }
+        }
+    }
+
     let dist_path = path.join("dist");
     let mut files_to_delete = Vec::new();
 
     for dir in fs::read_dir(dist_path)? {
-        let dir = dir?.path();
-        let dir = dir.as_path();
-        if dir.is_dir() {
+        let dir = dir?;
+        // DirEntry::file_type doesn't follow symlinks, unlike Path::is_dir.
+        if dir.file_type()?.is_dir() {
+            let dir = dir.path();
+            let dir = dir.as_path();
             for full_path in fs::read_dir(dir)? {
-                let full_path = full_path?.path();
+                let full_path = full_path?;
+                if full_path.file_type()?.is_symlink() {
+                    continue;
+                }
+                let full_path = full_path.path();
                 let file_path = full_path.strip_prefix(path)?;
 
                 if !files_to_keep.contains(file_path) {
@@ -541,26 +823,72 @@ pub fn add_to_channel_history(
     date: &str,
     files: &[(String, String)],
     extra_files: &[String],
+    options: &DownloadOptions,
 ) -> Result<(), SyncError> {
     let mut channel_history = match get_channel_history(path, channel) {
         Ok(c) => c,
         Err(SyncError::Io(_)) => ChannelHistoryFile {
             versions: HashMap::new(),
+            partial: HashMap::new(),
         },
         Err(e) => return Err(e),
     };
 
+    // Files recorded by an earlier sync of the same date are kept, such as those of the
+    // platforms left out by `sync --platform`, so cleaning doesn't delete them.
+    let mut date_files = channel_history.versions.remove(date).unwrap_or_default();
+    let mut recorded: HashSet<String> = date_files.iter().cloned().collect();
     let files = files.iter().map(|(f, _)| f.to_string());
     let extra_files = extra_files.iter().map(|ef| ef.to_string());
+    date_files.extend(
+        files
+            .chain(extra_files)
+            .filter(|f| recorded.insert(f.clone())),
+    );
 
-    let files = files.chain(extra_files).collect();
+    channel_history
+        .versions
+        .insert(date.to_string(), date_files);
+    // The date is complete, and supersedes any earlier partial sync.
+    channel_history.partial.clear();
 
-    channel_history.versions.insert(date.to_string(), files);
+    write_channel_history(path, channel, &channel_history, options)
+}
 
-    let ch_data = toml_edit::ser::to_string(&channel_history)?;
+/// Record the files downloaded so far for a date that failed to fully sync.
+///
+/// Only the latest partial date of a channel is kept.
+pub fn add_partial_to_channel_history(
+    path: &Path,
+    channel: &str,
+    date: &str,
+    files: Vec<String>,
+    options: &DownloadOptions,
+) -> Result<(), SyncError> {
+    let mut channel_history = match get_channel_history(path, channel) {
+        Ok(c) => c,
+        Err(SyncError::Io(_)) => ChannelHistoryFile {
+            versions: HashMap::new(),
+            partial: HashMap::new(),
+        },
+        Err(e) => return Err(e),
+    };
+
+    channel_history.partial = HashMap::from([(date.to_string(), files)]);
+
+    write_channel_history(path, channel, &channel_history, options)
+}
+
+fn write_channel_history(
+    path: &Path,
+    channel: &str,
+    channel_history: &ChannelHistoryFile,
+    options: &DownloadOptions,
+) -> Result<(), SyncError> {
+    let ch_data = toml_edit::ser::to_string(channel_history)?;
 
     let channel_history_path = path.join(format!("mirror-{channel}-history.toml"));
-    write_file_create_dir(&channel_history_path, &ch_data)?;
+    write_file_create_dir(&channel_history_path, &ch_data, options)?;
 
     Ok(())
 }
@@ -571,63 +899,169 @@ pub fn get_rustup_version(path: &Path) -> Result<String, SyncError> {
     Ok(release_data.version)
 }
 
+/// Layout of channel manifests in a mirror, and the default `dist_path_template`.
+pub const DEFAULT_DIST_PATH_TEMPLATE: &str = "dist/{date}/channel-rust-{channel}.toml";
+
+/// Fill in a `dist_path_template`. Without a date, the `{date}/` directory is dropped.
+pub fn channel_manifest_path(template: &str, channel: &str, date: Option<&str>) -> String {
+    let path = match date {
+        Some(date) => template.replace("{date}", date),
+        None => template.replace("{date}/", "").replace("{date}", ""),
+    };
+    path.replace("{channel}", channel)
+}
+
 /// Synchronize a rustup channel (stable, beta, or nightly).
+///
+/// With `force_download`, every file of the channel is downloaded again, even if it exists.
 #[allow(clippy::too_many_arguments)]
 pub async fn sync_rustup_channel(
     path: &Path,
     source: &str,
+    dist_path_template: &str,
     threads: usize,
     prefix: String,
     channel: &str,
-    retries: usize,
+    options: &DownloadOptions,
     user_agent: &HeaderValue,
     download_dev: bool,
-    download_gz: bool,
-    download_xz: bool,
+    formats: &FormatRules,
     platforms: &Platforms,
+    download_signatures: bool,
+    force_download: bool,
 ) -> Result<(), SyncError> {
     // Download channel file
-    let (channel_url, channel_path, extra_files) =
-        if let Some(inner_channel) = channel.strip_prefix("nightly-") {
-            let url = format!("{source}/dist/{inner_channel}/channel-rust-nightly.toml");
-            let path_chunk = format!("dist/{inner_channel}/channel-rust-nightly.toml");
-            let path = path.join(&path_chunk);
-            // Make sure the cleanup step doesn't delete the channel toml
-            let extra_files = vec![path_chunk.clone(), format!("{path_chunk}.sha256")];
-            (url, path, extra_files)
-        } else {
-            let url = format!("{source}/dist/channel-rust-{channel}.toml");
-            let path = path.join(format!("dist/channel-rust-{channel}.toml"));
-            (url, path, Vec::new())
-        };
-    let channel_part_path = append_to_path(&channel_path, ".part");
+    let (channel_name, date) = split_channel_date(channel);
+    let channel_url = format!(
+        "{source}/{}",
+        channel_manifest_path(dist_path_template, channel_name, date)
+    );
+    let path_chunk = channel_manifest_path(DEFAULT_DIST_PATH_TEMPLATE, channel_name, date);
+    let channel_path = path.join(&path_chunk);
+    // Make sure the cleanup step doesn't delete a dated channel toml
+    let mut extra_files = if date.is_some() {
+        vec![path_chunk.clone(), format!("{path_chunk}.sha256")]
+    } else {
+        Vec::new()
+    };
+    if date.is_some() && download_signatures {
+        extra_files.push(format!("{path_chunk}.asc"));
+    }
+    let channel_part_path = append_to_path(&channel_path, &options.suffixes.part);
     let client = Client::new();
     download_with_sha256_file(
         &client,
         &channel_url,
         &channel_part_path,
-        retries,
+        options,
         true,
         user_agent,
     )
     .await?;
 
+    // Not every source signs its manifests, so a missing signature isn't an error.
+    if download_signatures {
+        let signature_url = format!("{channel_url}.asc");
+        match download(
+            &client,
+            &signature_url,
+            &append_to_path(&channel_path, ".asc"),
+            None,
+            options,
+            true,
+            user_agent,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(DownloadError::NotFound { .. }) => {
+                eprintln!("No signature found at {signature_url}, skipping it.");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
     // Open toml file, find all files to download
     let (date, files) = rustup_download_list(
         &channel_part_path,
+        channel,
         download_dev,
-        download_gz,
-        download_xz,
+        formats,
         platforms,
     )?;
     move_if_exists_with_sha256(&channel_part_path, &channel_path)?;
 
-    let pb = panamax_progress_bar(files.len(), prefix);
-    pb.enable_steady_tick(Duration::from_millis(10));
+    download_channel_files(
+        path,
+        source,
+        threads,
+        prefix,
+        channel,
+        &date,
+        &files,
+        &extra_files,
+        options,
+        user_agent,
+        force_download,
+    )
+    .await
+}
+
+/// Split a channel like `nightly-2024-01-15` into its name and date.
+fn split_channel_date(channel: &str) -> (&str, Option<&str>) {
+    match channel.strip_prefix("nightly-") {
+        Some(date) => ("nightly", Some(date)),
+        None => (channel, None),
+    }
+}
+
+/// Download the `files` of one date of a channel, and record them in its history.
+#[allow(clippy::too_many_arguments)]
+async fn download_channel_files(
+    path: &Path,
+    source: &str,
+    threads: usize,
+    prefix: String,
+    channel: &str,
+    date: &str,
+    files: &[(String, String)],
+    extra_files: &[String],
+    options: &DownloadOptions,
+    user_agent: &HeaderValue,
+    force_download: bool,
+) -> Result<(), SyncError> {
+    let client = Client::new();
+
+    // Files already downloaded by an earlier, partial sync of this date
+    // don't need to be checked again, unless they are being refreshed.
+    let mut done_files: HashSet<String> = get_channel_history(path, channel)
+        .ok()
+        .and_then(|mut history| history.partial.remove(date))
+        .filter(|_| !force_download)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let remaining_files: Vec<&(String, String)> = files
+        .iter()
+        .filter(|(url, _)| !done_files.contains(url))
+        .collect();
+
+    let progress = DownloadProgress::new(remaining_files.len(), prefix);
+    progress.bar().enable_steady_tick(Duration::from_millis(10));
+    let events = PhaseEvents::start(
+        options.events.as_ref(),
+        &format!("rustup {channel}"),
+        Some(remaining_files.len()),
+    );
+    let options = &DownloadOptions {
+        on_bytes: Some(progress.on_bytes()),
+        ..options.clone()
+    };
 
     let mut errors_occurred = 0usize;
 
-    let tasks = futures::stream::iter(files.iter())
+    let tasks = futures::stream::iter(remaining_files)
+        .take_while(|_| futures::future::ready(!options.cancel.is_cancelled()))
         .map(|(url, hash)| {
             // Clone the variables that will be moved into the tokio task.
             let client = client.clone();
@@ -636,7 +1070,8 @@ pub async fn sync_rustup_channel(
             let user_agent = user_agent.clone();
             let url = url.clone();
             let hash = hash.clone();
-            let pb = pb.clone();
+            let options = options.clone();
+            let progress = progress.clone();
 
             tokio::spawn(async move {
                 let out = sync_one_rustup_target(
@@ -645,14 +1080,15 @@ pub async fn sync_rustup_channel(
                     &source,
                     &url,
                     &hash,
-                    retries,
+                    &options,
+                    force_download,
                     &user_agent,
                 )
                 .await;
 
-                pb.inc(1);
+                progress.finish_file(out.as_ref().ok().copied());
 
-                out
+                (url, out)
             })
         })
         .buffer_unordered(threads)
@@ -661,12 +1097,16 @@ pub async fn sync_rustup_channel(
 
     for res in tasks {
         // Unwrap the join result.
-        let res = res.unwrap();
+        let (url, res) = res.unwrap();
 
-        if let Err(e) = res {
-            match e {
-                DownloadError::NotFound { .. } => {}
-                _ => {
+        match res {
+            Ok(_) => {
+                events.completed(&url);
+                done_files.insert(url);
+            }
+            Err(e) => {
+                events.failed(&url, &e);
+                if !matches!(e, DownloadError::NotFound { .. }) {
                     errors_occurred += 1;
                     eprintln!("Download failed: {e:?}");
                 }
@@ -674,176 +1114,494 @@ pub async fn sync_rustup_channel(
         }
     }
 
-    if errors_occurred == 0 {
+    if errors_occurred == 0 && !options.cancel.is_cancelled() {
         // Write channel history file
-        add_to_channel_history(path, channel, &date, &files, &extra_files)?;
+        add_to_channel_history(path, channel, date, files, extra_files, options)?;
         Ok(())
     } else {
-        Err(SyncError::FailedDownloads {
-            count: errors_occurred,
-        })
+        // Remember what did succeed, so the next sync can resume from here.
+        let done_files = done_files
+            .into_iter()
+            .chain(extra_files.iter().cloned())
+            .collect();
+        add_partial_to_channel_history(path, channel, date, done_files, options)?;
+        if errors_occurred > 0 {
+            Err(SyncError::FailedDownloads {
+                count: errors_occurred,
+            })
+        } else {
+            Err(SyncError::Interrupted)
+        }
     }
 }
 
-/// Synchronize rustup.
-pub async fn sync(
+/// Download every file of the given channels again, e.g. `nightly-2024-01-15`, whether
+/// they exist or not, so a corrupted date can be repaired without re-syncing everything.
+///
+/// Returns the number of channels that failed.
+pub async fn refresh_channels(
     path: &Path,
-    mirror: &ConfigMirror,
+    options: &DownloadOptions,
     rustup: &ConfigRustup,
+    channels: &[String],
+    only_platforms: &[String],
     user_agent: &HeaderValue,
-) -> Result<(), MirrorError> {
-    let platforms = get_platforms(rustup).await?;
+) -> Result<usize, MirrorError> {
+    let dist_path_template = rustup
+        .dist_path_template
+        .as_deref()
+        .unwrap_or(DEFAULT_DIST_PATH_TEMPLATE);
+    let platforms = get_platforms(path, rustup, only_platforms).await?;
+    let formats = FormatRules::from_config(rustup)?;
+
+    let mut failed = 0;
+    for (i, channel) in channels.iter().enumerate() {
+        options.check_cancelled()?;
+        let prefix =
+            padded_prefix_message(i + 1, channels.len(), &format!("Refreshing rust {channel}"));
+        if let Err(e) = sync_rustup_channel(
+            path,
+            &rustup.source,
+            dist_path_template,
+            rustup.download_threads,
+            prefix,
+            channel,
+            options,
+            user_agent,
+            download_dev(rustup, channel),
+            &formats,
+            &platforms,
+            rustup.download_signatures.unwrap_or(false),
+            true,
+        )
+        .await
+        {
+            failed += 1;
+            eprintln!("Refreshing rust {channel} failed: {e:?}");
+        }
+    }
+    Ok(failed)
+}
+
+/// Download the files of `only_platforms` for the channels already in the mirror, e.g. for
+/// `sync --platform`.
+///
+/// The mirrored channel files are kept as they are, so the rest of the mirror stays
+/// consistent with them. Channels that haven't been mirrored yet are skipped.
+///
+/// Returns the number of channels that failed.
+pub async fn backfill_platforms(
+    path: &Path,
+    options: &DownloadOptions,
+    rustup: &ConfigRustup,
+    only_platforms: &[String],
+    user_agent: &HeaderValue,
+) -> Result<usize, MirrorError> {
+    let platforms = get_platforms(path, rustup, only_platforms).await?;
+    let formats = FormatRules::from_config(rustup)?;
+
+    let keep = [
+        ("stable", rustup.keep_latest_stables),
+        ("beta", rustup.keep_latest_betas),
+        ("nightly", rustup.keep_latest_nightlies),
+    ];
+    let channels: Vec<&str> = keep
+        .into_iter()
+        .filter(|(_, keep)| *keep != Some(0))
+        .map(|(channel, _)| channel)
+        .chain(
+            rustup
+                .pinned_rust_versions
+                .iter()
+                .flatten()
+                .map(String::as_str),
+        )
+        .collect();
+
+    let mut failed = 0;
+    let num_steps = channels.len() + 1;
+    let prefix = padded_prefix_message(1, num_steps, "Syncing rustup-init files");
+    if let Err(e) = sync_rustup_init(
+        path,
+        rustup.download_threads,
+        &rustup.source,
+        prefix,
+        options,
+        user_agent,
+        &platforms,
+    )
+    .await
+    {
+        failed += 1;
+        eprintln!("Downloading rustup init files failed: {e:?}");
+    }
+
+    for (i, channel) in channels.into_iter().enumerate() {
+        options.check_cancelled()?;
+        let (channel_name, date) = split_channel_date(channel);
+        let channel_path = path.join(channel_manifest_path(
+            DEFAULT_DIST_PATH_TEMPLATE,
+            channel_name,
+            date,
+        ));
+        if !channel_path.exists() {
+            eprintln!("Rust {channel} isn't mirrored yet, skipping it.");
+            continue;
+        }
+        let prefix =
+            padded_prefix_message(i + 2, num_steps, &format!("Backfilling rust {channel}"));
+        let res = match rustup_download_list(
+            &channel_path,
+            channel,
+            download_dev(rustup, channel),
+            &formats,
+            &platforms,
+        ) {
+            Ok((date, files)) => {
+                download_channel_files(
+                    path,
+                    &rustup.source,
+                    rustup.download_threads,
+                    prefix,
+                    channel,
+                    &date,
+                    &files,
+                    &[],
+                    options,
+                    user_agent,
+                    false,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            failed += 1;
+            eprintln!("Backfilling rust {channel} failed: {e:?}");
+        }
+    }
+    Ok(failed)
+}
+
+/// Check if `channel` is `name`, or one of its `<name>-<date>` pins.
+fn channel_matches(channel: &str, name: &str) -> bool {
+    channel == name
+        || channel
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with('-'))
+}
+
+/// Whether to download the rustc-dev component for a channel.
+///
+/// `dev_channels` enables it for specific channels (including any `<channel>-<date>` pins),
+/// while `download_dev` enables it for all of them.
+fn download_dev(rustup: &ConfigRustup, channel: &str) -> bool {
+    let in_dev_channels = rustup
+        .dev_channels
+        .as_ref()
+        .is_some_and(|channels| channels.iter().any(|c| channel_matches(channel, c)));
+
     // Default to not downloading rustc-dev
-    let download_dev = rustup.download_dev.unwrap_or(false);
+    in_dev_channels || rustup.download_dev.unwrap_or(false)
+}
+
+/// Find what syncing rustup would download, without changing the mirror.
+///
+/// Files are only checked for existence, not re-hashed.
+pub async fn dry_run(
+    path: &Path,
+    rustup: &ConfigRustup,
+    only_platforms: &[String],
+    user_agent: &HeaderValue,
+) -> Result<DryRunSummary, MirrorError> {
+    let dist_path_template = rustup
+        .dist_path_template
+        .as_deref()
+        .unwrap_or(DEFAULT_DIST_PATH_TEMPLATE);
+    let platforms = get_platforms(path, rustup, only_platforms).await?;
+    let formats = FormatRules::from_config(rustup)?;
+    let source = &rustup.source;
+
+    let mut files = 0;
+    let mut missing_urls = Vec::new();
+
+    let release_url = format!("{source}/rustup/release-stable.toml");
+    let release: Release =
+        toml_edit::easy::from_str(&download_string(&release_url, user_agent).await?)?;
+    for (platforms, is_exe) in [(&platforms.unix, false), (&platforms.windows, true)] {
+        let file_name = if is_exe {
+            "rustup-init.exe"
+        } else {
+            "rustup-init"
+        };
+        for platform in platforms {
+            files += 1;
+            let local_path = path
+                .join("rustup/archive")
+                .join(&release.version)
+                .join(platform)
+                .join(file_name);
+            if !local_path.exists() {
+                missing_urls.push(format!("{source}/rustup/dist/{platform}/{file_name}"));
+            }
+        }
+    }
 
-    let download_gz = rustup.download_gz.unwrap_or(false);
-    let download_xz = rustup.download_xz.unwrap_or(true);
+    let mut channels = Vec::new();
+    for (channel, keep) in [
+        ("stable", rustup.keep_latest_stables),
+        ("beta", rustup.keep_latest_betas),
+        ("nightly", rustup.keep_latest_nightlies),
+    ] {
+        if keep != Some(0) {
+            channels.push(channel.to_string());
+        }
+    }
+    channels.extend(rustup.pinned_rust_versions.iter().flatten().cloned());
 
+    for channel in &channels {
+        let (channel_name, date) = match channel.strip_prefix("nightly-") {
+            Some(date) => ("nightly", Some(date)),
+            None => (channel.as_str(), None),
+        };
+        let channel_url = format!(
+            "{source}/{}",
+            channel_manifest_path(dist_path_template, channel_name, date)
+        );
+        let channel_str = download_string(&channel_url, user_agent).await?;
+        if rustup.download_signatures == Some(true) {
+            files += 1;
+            let signature_path = format!(
+                "{}.asc",
+                channel_manifest_path(DEFAULT_DIST_PATH_TEMPLATE, channel_name, date)
+            );
+            if !path.join(signature_path).exists() {
+                missing_urls.push(format!("{channel_url}.asc"));
+            }
+        }
+        let (_, channel_files) = download_list_from_str(
+            &channel_str,
+            channel,
+            download_dev(rustup, channel),
+            &formats,
+            &platforms,
+        )?;
+        for (url, _) in channel_files {
+            files += 1;
+            if !url
+                .split('/')
+                .fold(path.to_path_buf(), |p, c| p.join(c))
+                .exists()
+            {
+                missing_urls.push(format!("{source}/{url}"));
+            }
+        }
+    }
+
+    let mut summary = DryRunSummary::default();
+    summary
+        .add(files, missing_urls, rustup.download_threads, user_agent)
+        .await;
+    Ok(summary)
+}
+
+/// Number of progress steps taken by a rustup sync.
+pub fn num_steps(rustup: &ConfigRustup) -> usize {
     let num_pinned_versions = rustup.pinned_rust_versions.as_ref().map_or(0, |v| v.len());
-    let num_steps = 1 + // sync rustup-init
-                    1 + 1 + 1 + // sync latest stable, beta, nightly
-                    num_pinned_versions + // sync pinned rust versions
-                    1; // clean old files
-    let mut step = 0;
+    1 + // sync rustup-init
+    1 + 1 + 1 + // sync latest stable, beta, nightly
+    num_pinned_versions + // sync pinned rust versions
+    1 // clean old files
+}
+
+/// Synchronize rustup, returning the number of steps that failed.
+///
+/// `step` is the last step completed before this phase, and is advanced once per rustup step.
+/// `num_steps` is the total number of steps for the whole sync.
+pub async fn sync(
+    path: &Path,
+    options: &DownloadOptions,
+    rustup: &ConfigRustup,
+    only_platforms: &[String],
+    user_agent: &HeaderValue,
+    step: &mut usize,
+    num_steps: usize,
+) -> Result<usize, MirrorError> {
+    let dist_path_template = rustup
+        .dist_path_template
+        .as_deref()
+        .unwrap_or(DEFAULT_DIST_PATH_TEMPLATE);
+    if !dist_path_template.contains("{channel}") {
+        return Err(MirrorError::Config(
+            "dist_path_template must contain {channel}".to_string(),
+        ));
+    }
+
+    let platforms = get_platforms(path, rustup, only_platforms).await?;
+
+    let formats = FormatRules::from_config(rustup)?;
+    let download_signatures = rustup.download_signatures.unwrap_or(false);
 
     eprintln!("{}", style("Syncing Rustup repositories...").bold());
 
+    let mut failed_steps = 0;
+
     // Mirror rustup-init
-    step += 1;
-    let prefix = padded_prefix_message(step, num_steps, "Syncing rustup-init files");
+    *step += 1;
+    let prefix = padded_prefix_message(*step, num_steps, "Syncing rustup-init files");
     if let Err(e) = sync_rustup_init(
         path,
         rustup.download_threads,
         &rustup.source,
         prefix,
-        mirror.retries,
+        options,
         user_agent,
         &platforms,
     )
     .await
     {
+        failed_steps += 1;
         eprintln!("Downloading rustup init files failed: {e:?}");
         eprintln!("You will need to sync again to finish this download.");
     }
 
     let mut failures = false;
 
+    options.check_cancelled()?;
+
     // Mirror stable
-    step += 1;
+    *step += 1;
     if rustup.keep_latest_stables != Some(0) {
-        let prefix = padded_prefix_message(step, num_steps, "Syncing latest stable");
+        let prefix = padded_prefix_message(*step, num_steps, "Syncing latest stable");
         if let Err(e) = sync_rustup_channel(
             path,
             &rustup.source,
+            dist_path_template,
             rustup.download_threads,
             prefix,
             "stable",
-            mirror.retries,
+            options,
             user_agent,
-            download_dev,
-            download_gz,
-            download_xz,
+            download_dev(rustup, "stable"),
+            &formats,
             &platforms,
+            download_signatures,
+            false,
         )
         .await
         {
             failures = true;
+            failed_steps += 1;
             eprintln!("Downloading stable release failed: {e:?}");
             eprintln!("You will need to sync again to finish this download.");
         }
     } else {
         eprintln!(
             "{} Skipping syncing stable.",
-            current_step_prefix(step, num_steps)
+            current_step_prefix(*step, num_steps)
         );
     }
 
+    options.check_cancelled()?;
+
     // Mirror beta
-    step += 1;
+    *step += 1;
     if rustup.keep_latest_betas != Some(0) {
-        let prefix = padded_prefix_message(step, num_steps, "Syncing latest beta");
+        let prefix = padded_prefix_message(*step, num_steps, "Syncing latest beta");
         if let Err(e) = sync_rustup_channel(
             path,
             &rustup.source,
+            dist_path_template,
             rustup.download_threads,
             prefix,
             "beta",
-            mirror.retries,
+            options,
             user_agent,
-            download_dev,
-            download_gz,
-            download_xz,
+            download_dev(rustup, "beta"),
+            &formats,
             &platforms,
+            download_signatures,
+            false,
         )
         .await
         {
             failures = true;
+            failed_steps += 1;
             eprintln!("Downloading beta release failed: {e:?}");
             eprintln!("You will need to sync again to finish this download.");
         }
     } else {
         eprintln!(
             "{} Skipping syncing beta.",
-            current_step_prefix(step, num_steps)
+            current_step_prefix(*step, num_steps)
         );
     }
 
+    options.check_cancelled()?;
+
     // Mirror nightly
-    step += 1;
+    *step += 1;
     if rustup.keep_latest_nightlies != Some(0) {
-        let prefix = padded_prefix_message(step, num_steps, "Syncing latest nightly");
+        let prefix = padded_prefix_message(*step, num_steps, "Syncing latest nightly");
         if let Err(e) = sync_rustup_channel(
             path,
             &rustup.source,
+            dist_path_template,
             rustup.download_threads,
             prefix,
             "nightly",
-            mirror.retries,
+            options,
             user_agent,
-            download_dev,
-            download_gz,
-            download_xz,
+            download_dev(rustup, "nightly"),
+            &formats,
             &platforms,
+            download_signatures,
+            false,
         )
         .await
         {
             failures = true;
+            failed_steps += 1;
             eprintln!("Downloading nightly release failed: {e:?}");
             eprintln!("You will need to sync again to finish this download.");
         }
     } else {
         eprintln!(
             "{} Skipping syncing nightly.",
-            current_step_prefix(step, num_steps)
+            current_step_prefix(*step, num_steps)
         );
     }
 
     // Mirror pinned rust versions
     if let Some(pinned_versions) = &rustup.pinned_rust_versions {
         for version in pinned_versions {
-            step += 1;
+            options.check_cancelled()?;
+            *step += 1;
             let prefix =
-                padded_prefix_message(step, num_steps, &format!("Syncing pinned rust {version}"));
+                padded_prefix_message(*step, num_steps, &format!("Syncing pinned rust {version}"));
             if let Err(e) = sync_rustup_channel(
                 path,
                 &rustup.source,
+                dist_path_template,
                 rustup.download_threads,
                 prefix,
                 version,
-                mirror.retries,
+                options,
                 user_agent,
-                download_dev,
-                download_gz,
-                download_xz,
+                download_dev(rustup, version),
+                &formats,
                 &platforms,
+                download_signatures,
+                false,
             )
             .await
             {
                 failures = true;
+                failed_steps += 1;
                 if let SyncError::Download(DownloadError::NotFound { .. }) = e {
                     eprintln!(
                         "{} Pinned rust version {} could not be found.",
-                        current_step_prefix(step, num_steps),
+                        current_step_prefix(*step, num_steps),
                         version
                     );
                     return Err(MirrorError::Config(format!(
@@ -857,31 +1615,44 @@ pub async fn sync(
         }
     }
 
+    options.check_cancelled()?;
+
     // If all succeeds, clean files
-    step += 1;
+    *step += 1;
     if rustup.keep_latest_stables.is_none()
         && rustup.keep_latest_betas.is_none()
         && rustup.keep_latest_nightlies.is_none()
     {
         eprintln!(
             "{} Skipping cleaning files.",
-            current_step_prefix(step, num_steps)
+            current_step_prefix(*step, num_steps)
         );
     } else if failures {
         eprintln!(
             "{} Skipping cleaning files due to download failures.",
-            current_step_prefix(step, num_steps)
+            current_step_prefix(*step, num_steps)
         );
     } else {
-        let prefix = padded_prefix_message(step, num_steps, "Cleaning old files");
+        let prefix = padded_prefix_message(*step, num_steps, "Cleaning old files");
         if let Err(e) = clean_old_files(
             path,
             rustup.keep_latest_stables,
             rustup.keep_latest_betas,
             rustup.keep_latest_nightlies,
             rustup.pinned_rust_versions.as_ref(),
+            &[
+                (
+                    "beta",
+                    rustup.keep_beta_dates.as_deref().unwrap_or_default(),
+                ),
+                (
+                    "nightly",
+                    rustup.keep_nightly_dates.as_deref().unwrap_or_default(),
+                ),
+            ],
             prefix,
         ) {
+            failed_steps += 1;
             eprintln!("Cleaning old files failed: {e:?}");
             eprintln!("You may need to sync again to clean these files.");
         }
@@ -889,5 +1660,297 @@ pub async fn sync(
 
     eprintln!("{}", style("Syncing Rustup repositories complete!").bold());
 
-    Ok(())
+    Ok(failed_steps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clean_keeps_listed_dates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let mut history = String::from("[versions]\n");
+        for date in ["2023-05-01", "2023-06-01", "2023-07-01"] {
+            let file = format!("dist/{date}/rustc-nightly.tar.xz");
+            fs::create_dir_all(path.join("dist").join(date)).unwrap();
+            fs::write(path.join(&file), []).unwrap();
+            history.push_str(&format!("\"{date}\" = [\"{file}\"]\n"));
+        }
+        fs::write(path.join("mirror-nightly-history.toml"), history).unwrap();
+
+        let kept = ["2023-05-01".to_string()];
+        clean_old_files(
+            path,
+            None,
+            None,
+            Some(1),
+            None,
+            &[("nightly", &kept)],
+            String::new(),
+        )
+        .unwrap();
+        assert!(path.join("dist/2023-05-01/rustc-nightly.tar.xz").exists());
+        assert!(!path.join("dist/2023-06-01/rustc-nightly.tar.xz").exists());
+        assert!(path.join("dist/2023-07-01/rustc-nightly.tar.xz").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clean_skips_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirror");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(outside.join("2023-05-01")).unwrap();
+        fs::write(outside.join("2023-05-01/rustc-nightly.tar.xz"), []).unwrap();
+        fs::write(outside.join("rustc-beta.tar.xz"), []).unwrap();
+
+        // A symlinked date directory, and a symlinked file in a date directory.
+        fs::create_dir_all(path.join("dist/2023-06-01")).unwrap();
+        symlink(outside.join("2023-05-01"), path.join("dist/2023-05-01")).unwrap();
+        symlink(
+            outside.join("rustc-beta.tar.xz"),
+            path.join("dist/2023-06-01/rustc-beta.tar.xz"),
+        )
+        .unwrap();
+        fs::write(path.join("dist/2023-06-01/rustc-nightly.tar.xz"), []).unwrap();
+        fs::write(
+            path.join("mirror-nightly-history.toml"),
+            "[versions]\n\"2023-07-01\" = []\n",
+        )
+        .unwrap();
+
+        clean_old_files(&path, None, None, Some(1), None, &[], String::new()).unwrap();
+        assert!(!path.join("dist/2023-06-01/rustc-nightly.tar.xz").exists());
+        assert!(path.join("dist/2023-06-01/rustc-beta.tar.xz").exists());
+        assert!(path.join("dist/2023-05-01").exists());
+        assert!(outside.join("2023-05-01/rustc-nightly.tar.xz").exists());
+        assert!(outside.join("rustc-beta.tar.xz").exists());
+    }
+
+    #[test]
+    fn dist_path_templates() {
+        let default = DEFAULT_DIST_PATH_TEMPLATE;
+        assert_eq!(
+            channel_manifest_path(default, "stable", None),
+            "dist/channel-rust-stable.toml"
+        );
+        assert_eq!(
+            channel_manifest_path(default, "nightly", Some("2014-12-18")),
+            "dist/2014-12-18/channel-rust-nightly.toml"
+        );
+        assert_eq!(
+            channel_manifest_path("rust/{channel}/{date}/manifest.toml", "beta", None),
+            "rust/beta/manifest.toml"
+        );
+    }
+
+    #[test]
+    fn channel_components() {
+        let channel = r#"
+            manifest-version = "2"
+            date = "2023-06-01"
+            [pkg.rustc]
+            version = "1.70.0"
+            [pkg.rustc.target]
+            [pkg.cargo]
+            version = "1.70.0"
+            [pkg.cargo.target]
+            [pkg.rust-docs]
+            version = "1.70.0"
+            [pkg.rust-docs.target]
+        "#;
+        assert_eq!(
+            components_from_channel(channel).unwrap(),
+            ["cargo", "rust-docs", "rustc"]
+        );
+    }
+
+    #[test]
+    fn format_rules() {
+        let formats = |gz, xz, zst| DistFormats { gz, xz, zst };
+        let rules = FormatRules {
+            default: formats(false, true, false),
+            rules: [
+                (
+                    "x86_64-pc-windows-msvc".to_string(),
+                    DistFormats::parse(&["gz".to_string()]).unwrap(),
+                ),
+                ("nightly".to_string(), formats(false, false, true)),
+            ]
+            .into(),
+        };
+        let get = |channel, platform| {
+            let f = rules.get(channel, platform);
+            (f.gz, f.xz, f.zst)
+        };
+        assert_eq!(
+            get("stable", "x86_64-unknown-linux-gnu"),
+            (false, true, false)
+        );
+        assert_eq!(
+            get("stable", "x86_64-pc-windows-msvc"),
+            (true, false, false)
+        );
+        assert_eq!(get("nightly-2023-05-01", "*"), (false, false, true));
+        assert_eq!(
+            get("nightly", "x86_64-pc-windows-msvc"),
+            (true, false, false)
+        );
+        assert!(DistFormats::parse(&["bz2".to_string()]).is_err());
+    }
+
+    #[test]
+    fn channel_history_without_partial() {
+        let history: ChannelHistoryFile =
+            toml_edit::easy::from_str("[versions]\n\"2024-01-01\" = [\"dist/a\"]\n").unwrap();
+        assert!(history.partial.is_empty());
+
+        let data = toml_edit::ser::to_string(&history).unwrap();
+        assert!(!data.contains("partial"));
+    }
+
+    #[test]
+    fn channel_history_keeps_date_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let options = DownloadOptions::default();
+        let files = |names: &[&str]| -> Vec<(String, String)> {
+            names
+                .iter()
+                .map(|n| (n.to_string(), String::new()))
+                .collect()
+        };
+
+        // A sync of one platform doesn't forget the files of the others.
+        add_to_channel_history(
+            path,
+            "nightly",
+            "2024-01-01",
+            &files(&["a", "b"]),
+            &[],
+            &options,
+        )
+        .unwrap();
+        add_to_channel_history(
+            path,
+            "nightly",
+            "2024-01-01",
+            &files(&["b", "c"]),
+            &[],
+            &options,
+        )
+        .unwrap();
+        let history = get_channel_history(path, "nightly").unwrap();
+        assert_eq!(history.versions["2024-01-01"], ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn unknown_configured_platforms() {
+        let available = [
+            "x86_64-unknown-linux-gnu".to_string(),
+            "x86_64-pc-windows-msvc".to_string(),
+        ];
+        let configured = [
+            "x86_64-unkown-linux-gnu".to_string(),
+            "x86_64-pc-windows-msvc".to_string(),
+        ];
+        assert_eq!(
+            unknown_platforms(&configured, &available),
+            ["x86_64-unkown-linux-gnu"]
+        );
+        assert!(unknown_platforms(&available, &available).is_empty());
+    }
+
+    #[test]
+    fn std_only_targets() {
+        let channel = r#"
+            manifest-version = "2"
+            date = "2023-06-01"
+            [pkg.rustc]
+            version = "1.70.0"
+            [pkg.rustc.target.x86_64-unknown-linux-gnu]
+            available = true
+            url = "https://static.rust-lang.org/dist/2023-06-01/rustc-x86_64-unknown-linux-gnu.tar.gz"
+            hash = "1"
+            xz_url = "https://static.rust-lang.org/dist/2023-06-01/rustc-x86_64-unknown-linux-gnu.tar.xz"
+            xz_hash = "2"
+            [pkg.rustc.target.thumbv7em-none-eabi]
+            available = false
+            [pkg.rust-std]
+            version = "1.70.0"
+            [pkg.rust-std.target.thumbv7em-none-eabi]
+            available = true
+            url = "https://static.rust-lang.org/dist/2023-06-01/rust-std-thumbv7em-none-eabi.tar.gz"
+            hash = "3"
+            xz_url = "https://static.rust-lang.org/dist/2023-06-01/rust-std-thumbv7em-none-eabi.tar.xz"
+            xz_hash = "4"
+            [pkg.rust-std.target.aarch64-unknown-linux-gnu]
+            available = true
+            url = "https://static.rust-lang.org/dist/2023-06-01/rust-std-aarch64-unknown-linux-gnu.tar.gz"
+            hash = "5"
+            xz_url = "https://static.rust-lang.org/dist/2023-06-01/rust-std-aarch64-unknown-linux-gnu.tar.xz"
+            xz_hash = "6"
+        "#;
+        let platforms = Platforms {
+            unix: vec!["x86_64-unknown-linux-gnu".to_string()],
+            windows: vec![],
+            std_only: vec!["thumbv7em-none-eabi".to_string()],
+        };
+        let formats = FormatRules {
+            default: DistFormats {
+                gz: false,
+                xz: true,
+                zst: false,
+            },
+            rules: HashMap::new(),
+        };
+        let (_, files) =
+            download_list_from_str(channel, "stable", false, &formats, &platforms).unwrap();
+        let mut urls: Vec<_> = files.into_iter().map(|(url, _)| url).collect();
+        urls.sort();
+        assert_eq!(
+            urls,
+            [
+                "dist/2023-06-01/rust-std-thumbv7em-none-eabi.tar.xz",
+                "dist/2023-06-01/rustc-x86_64-unknown-linux-gnu.tar.xz"
+            ]
+        );
+    }
+
+    #[test]
+    fn selected_formats() {
+        let urls = || TargetUrls {
+            url: "a.tar.gz".to_string(),
+            hash: "1".to_string(),
+            xz_url: "a.tar.xz".to_string(),
+            xz_hash: "2".to_string(),
+            zst_url: None,
+            zst_hash: None,
+        };
+        let all = DistFormats {
+            gz: true,
+            xz: true,
+            zst: true,
+        };
+        assert_eq!(urls().selected(all).len(), 2);
+
+        let zst_urls = TargetUrls {
+            zst_url: Some("a.tar.zst".to_string()),
+            zst_hash: Some("3".to_string()),
+            ..urls()
+        };
+        let zst_only = DistFormats {
+            gz: false,
+            xz: false,
+            zst: true,
+        };
+        assert_eq!(
+            zst_urls.selected(zst_only),
+            vec![("a.tar.zst".to_string(), "3".to_string())]
+        );
+    }
 }
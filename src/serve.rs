@@ -1,27 +1,56 @@
-use std::{collections::HashMap, io, net::SocketAddr, path::PathBuf, process::Stdio};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::{self, Seek, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 use askama::Template;
 use bytes::BytesMut;
 use futures_util::stream::TryStreamExt;
+use git2::Repository;
 use include_dir::{include_dir, Dir};
+use ipnet::IpNet;
+use serde::Serialize;
 use thiserror::Error;
 use tokio::{
     fs::File,
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    process::{ChildStdout, Command},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    process::Command,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
 };
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{BytesCodec, FramedRead};
+use url::{Host, Url};
 use warp::{
     host::Authority,
     http,
-    hyper::{body::Sender, Body, Response},
+    hyper::{
+        body::Sender,
+        server::conn::Http,
+        service::{service_fn, Service},
+        Body, Response,
+    },
     path::Tail,
     reject::Reject,
     Filter, Rejection, Stream,
 };
 
-use crate::crates::get_crate_path;
+use crate::crate_store::{CrateStore, StoredCrate};
+use crate::crates::read_index_entry;
+use crate::crates_index::{ConfigJson, INDEX_LOCK};
+use crate::mirror::{ConfigServe, MirrorError, MirrorStatus, STATUS_FILE};
+use crate::upload_pack::{advertise_refs, upload_pack, UploadPackError};
 
 pub struct TlsConfig {
     pub cert_path: PathBuf,
@@ -41,6 +70,10 @@ struct IndexTemplate {
     host: String,
 }
 
+#[derive(Template)]
+#[template(path = "not_found.html")]
+struct NotFoundTemplate;
+
 const STATIC_DIR: Dir = include_dir!("static");
 
 #[derive(Error, Debug)]
@@ -51,16 +84,380 @@ pub enum ServeError {
     Hyper(#[from] warp::hyper::Error),
     #[error("Warp HTTP error: {0}")]
     Warp(#[from] warp::http::Error),
-    #[error("{0}")]
-    Other(String),
+    #[error("Could not retrieve rustup platforms.")]
+    PlatformsUnavailable,
+    #[error("Crate {name} version {version} was not found.")]
+    CrateNotFound { name: String, version: String },
+    #[error("git http-backend failed: {0}")]
+    GitBackendFailed(io::Error),
+    #[error("git http-backend timed out.")]
+    GitBackendTimeout,
+    #[error("Forbidden.")]
+    Forbidden,
+    #[error("Fetching the crate from storage failed: {0}")]
+    Upstream(reqwest::Error),
+    #[error("Bad git request: {0}")]
+    GitRequest(String),
+    #[error("Serving the git index failed: {0}")]
+    UploadPack(#[from] UploadPackError),
+    #[error("The mirror is busy, try again later.")]
+    Overloaded,
 }
 
 impl Reject for ServeError {}
 
-pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<TlsConfig>) {
+impl ServeError {
+    /// The HTTP status to respond with for this error.
+    fn status(&self) -> http::StatusCode {
+        match self {
+            ServeError::CrateNotFound { .. } => http::StatusCode::NOT_FOUND,
+            ServeError::Forbidden => http::StatusCode::FORBIDDEN,
+            ServeError::GitRequest(_) | ServeError::UploadPack(UploadPackError::BadRequest(_)) => {
+                http::StatusCode::BAD_REQUEST
+            }
+            ServeError::GitBackendFailed(_) | ServeError::Upstream(_) => {
+                http::StatusCode::BAD_GATEWAY
+            }
+            ServeError::GitBackendTimeout => http::StatusCode::GATEWAY_TIMEOUT,
+            ServeError::Overloaded => http::StatusCode::SERVICE_UNAVAILABLE,
+            ServeError::PlatformsUnavailable
+            | ServeError::UploadPack(_)
+            | ServeError::Io(_)
+            | ServeError::Hyper(_)
+            | ServeError::Warp(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Which client addresses are allowed to access the mirror.
+#[derive(Debug, Default, Clone)]
+pub struct AccessControl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl TryFrom<&ConfigServe> for AccessControl {
+    type Error = MirrorError;
+
+    fn try_from(serve: &ConfigServe) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allow: parse_cidrs(serve.allow_cidrs.as_deref())?,
+            deny: parse_cidrs(serve.deny_cidrs.as_deref())?,
+        })
+    }
+}
+
+impl AccessControl {
+    /// Check if a client is allowed. Denied networks take precedence over allowed ones.
+    fn allows(&self, remote: Option<SocketAddr>) -> bool {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return true;
+        }
+
+        // Listening on :: gives IPv4 clients as IPv4-mapped IPv6 addresses.
+        let Some(ip) = remote.map(|r| r.ip().to_canonical()) else {
+            return false;
+        };
+
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+/// Default time a `git http-backend` process may run for, before it is killed.
+const DEFAULT_GIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How git clients fetching the index are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitBackend {
+    /// Run `git http-backend` for each request, killing it after `timeout`.
+    Cgi { timeout: Duration },
+    /// Serve fetches and clones with libgit2, without the git binary, giving up on
+    /// responses still being sent after `timeout`.
+    Native { timeout: Duration },
+}
+
+impl Default for GitBackend {
+    fn default() -> Self {
+        GitBackend::Cgi {
+            timeout: DEFAULT_GIT_TIMEOUT,
+        }
+    }
+}
+
+impl TryFrom<&ConfigServe> for GitBackend {
+    type Error = MirrorError;
+
+    fn try_from(serve: &ConfigServe) -> Result<Self, Self::Error> {
+        let timeout = serve
+            .git_timeout_secs
+            .map_or(DEFAULT_GIT_TIMEOUT, Duration::from_secs);
+        match serve.git_backend.as_deref() {
+            None | Some("cgi") => Ok(GitBackend::Cgi { timeout }),
+            Some("native") => Ok(GitBackend::Native { timeout }),
+            Some(other) => Err(MirrorError::Config(format!(
+                "unknown git_backend \"{other}\", expected \"cgi\" or \"native\""
+            ))),
+        }
+    }
+}
+
+/// Limits on how many requests are handled at once.
+///
+/// Requests over a limit are turned away with a 503 rather than queued, so a stampede of
+/// clients can't exhaust the host's processes or file descriptors.
+#[derive(Debug, Default, Clone)]
+pub struct RequestLimits {
+    requests: Option<Arc<Semaphore>>,
+    git: Option<Arc<Semaphore>>,
+}
+
+impl TryFrom<&ConfigServe> for RequestLimits {
+    type Error = MirrorError;
+
+    fn try_from(serve: &ConfigServe) -> Result<Self, Self::Error> {
+        // A limit of 0 would turn every request away.
+        let semaphore = |name: &str, max: Option<usize>| match max {
+            Some(0) => Err(MirrorError::Config(format!("{name} must be at least 1"))),
+            max => Ok(max.map(|max| Arc::new(Semaphore::new(max)))),
+        };
+        Ok(Self {
+            requests: semaphore("max_concurrent_requests", serve.max_concurrent_requests)?,
+            git: semaphore("max_concurrent_git", serve.max_concurrent_git)?,
+        })
+    }
+}
+
+impl RequestLimits {
+    /// Take a slot for any request, or `None` if requests are unlimited.
+    fn acquire_request(&self) -> Result<Option<OwnedSemaphorePermit>, ServeError> {
+        try_acquire(self.requests.as_ref())
+    }
+
+    /// Take a slot for a git request, or `None` if git requests are unlimited.
+    fn acquire_git(&self) -> Result<Option<OwnedSemaphorePermit>, ServeError> {
+        try_acquire(self.git.as_ref())
+    }
+}
+
+/// Hold `permit` until the body of `response` has been sent, or the client has gone away.
+fn hold_until_sent(response: Response<Body>, permit: OwnedSemaphorePermit) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let body = body.map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+fn try_acquire(
+    semaphore: Option<&Arc<Semaphore>>,
+) -> Result<Option<OwnedSemaphorePermit>, ServeError> {
+    semaphore
+        .map(|s| s.clone().try_acquire_owned())
+        .transpose()
+        .map_err(|_| ServeError::Overloaded)
+}
+
+/// Seconds clients are asked to wait before retrying when the mirror is busy.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Time a client has to finish the TLS handshake, and to send the headers of an HTTP/1
+/// request, so idle connections can't hold on to file descriptors.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP connection settings for the server.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerOptions {
+    http2_max_concurrent_streams: Option<u32>,
+    http2_keep_alive_interval: Option<Duration>,
+    http1_keep_alive: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            http2_max_concurrent_streams: None,
+            http2_keep_alive_interval: None,
+            http1_keep_alive: true,
+        }
+    }
+}
+
+impl From<&ConfigServe> for ServerOptions {
+    fn from(serve: &ConfigServe) -> Self {
+        Self {
+            http2_max_concurrent_streams: serve.http2_max_concurrent_streams,
+            http2_keep_alive_interval: serve.http2_keep_alive_interval.map(Duration::from_secs),
+            http1_keep_alive: serve.http1_keep_alive.unwrap_or(true),
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Connection builder serving both HTTP/1 and HTTP/2, including HTTP/2 without TLS (h2c).
+    fn http(&self) -> Http {
+        let mut http = Http::new();
+        http.http1_keep_alive(self.http1_keep_alive)
+            .http1_header_read_timeout(HANDSHAKE_TIMEOUT)
+            .http2_keep_alive_interval(self.http2_keep_alive_interval);
+        if let Some(max_streams) = self.http2_max_concurrent_streams {
+            http.http2_max_concurrent_streams(max_streams);
+        }
+        http
+    }
+}
+
+/// Load the certificate and key for TLS, offering HTTP/2 through ALPN.
+fn load_tls_config(tls: &TlsConfig) -> Result<ServerConfig, io::Error> {
+    let mut cert_file = std::io::BufReader::new(std::fs::File::open(&tls.cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_file).collect::<Result<Vec<_>, _>>()?;
+    let mut key_file = std::io::BufReader::new(std::fs::File::open(&tls.key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_file)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The client's address, added to each request by the connection it came in on.
+#[derive(Debug, Clone, Copy)]
+struct ClientAddr(SocketAddr);
+
+/// Like `warp::addr::remote`, for connections accepted by `serve` rather than by warp.
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Copy {
+    warp::ext::optional::<ClientAddr>()
+        .and(warp::addr::remote())
+        .map(|client: Option<ClientAddr>, remote: Option<SocketAddr>| {
+            client.map(|c| c.0).or(remote)
+        })
+}
+
+/// Parse a list of CIDRs, such as "10.0.0.0/8". Single addresses are also accepted.
+fn parse_cidrs(cidrs: Option<&[String]>) -> Result<Vec<IpNet>, MirrorError> {
+    cidrs
+        .unwrap_or_default()
+        .iter()
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| MirrorError::Config(format!("invalid CIDR \"{cidr}\"")))
+        })
+        .collect()
+}
+
+/// Turn rejections into meaningful responses.
+///
+/// `ServeError`s get their matching status, with a body in the registry API's error format
+/// so cargo can show the message. Anything not found gets a 404 page, and other
+/// rejections are left to warp.
+async fn handle_rejection(rejection: Rejection) -> Result<Response<Body>, Rejection> {
+    if let Some(e) = rejection.find::<ServeError>() {
+        let status = e.status();
+        // Being busy is expected under load, and would only flood the output.
+        if status.is_server_error() && !matches!(e, ServeError::Overloaded) {
+            eprintln!("Error while serving a request: {e}");
+        }
+
+        let body = serde_json::json!({ "errors": [{ "detail": e.to_string() }] });
+        let mut resp = error_response(status, "application/json", body.to_string());
+        if matches!(e, ServeError::Overloaded) {
+            resp.headers_mut()
+                .insert(http::header::RETRY_AFTER, RETRY_AFTER_SECS.into());
+        }
+        Ok(resp)
+    } else if rejection.is_not_found() {
+        let (content_type, body) = match NotFoundTemplate.render() {
+            Ok(page) => ("text/html; charset=utf-8", page),
+            Err(_) => ("text/plain; charset=utf-8", "Not found.".to_string()),
+        };
+        Ok(error_response(
+            http::StatusCode::NOT_FOUND,
+            content_type,
+            body,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+fn error_response(status: http::StatusCode, content_type: &str, body: String) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    if let Ok(content_type) = http::HeaderValue::from_str(content_type) {
+        resp.headers_mut()
+            .insert(http::header::CONTENT_TYPE, content_type);
+    }
+    resp
+}
+
+/// How often the age of the mirror is checked against `max_staleness`.
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `Warning` header added to every response while the mirror is stale.
+const STALE_WARNING: &str = "110 panamax \"Mirror is stale\"";
+
+/// Check if a mirror whose last successful sync was `age` ago is older than `max_staleness`.
+/// A mirror with no recorded sync is stale.
+fn is_stale(age: Option<Duration>, max_staleness: Duration) -> bool {
+    age.is_none_or(|age| age > max_staleness)
+}
+
+/// Watch the age of the mirror while it is served, which `panamax run` keeps syncing.
+///
+/// A warning is logged whenever the mirror becomes stale, and the returned flag is set
+/// for as long as it is.
+fn watch_staleness(path: PathBuf, max_staleness: Duration) -> Arc<AtomicBool> {
+    let stale = Arc::new(AtomicBool::new(false));
+    let flag = stale.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STALENESS_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let age = MirrorStatus::load(&path).age();
+            let is_stale = is_stale(age, max_staleness);
+            if is_stale && !flag.swap(true, Ordering::Relaxed) {
+                match age {
+                    Some(age) => eprintln!(
+                        "WARNING: the mirror was last synced {} hours ago, longer than max_staleness ({} hours).",
+                        age.as_secs() / 3600,
+                        max_staleness.as_secs() / 3600
+                    ),
+                    None => eprintln!(
+                        "WARNING: max_staleness is set, but no successful sync is recorded in {STATUS_FILE}."
+                    ),
+                }
+                eprintln!("Check that syncs are running and succeeding.");
+            } else if !is_stale && flag.swap(false, Ordering::Relaxed) {
+                eprintln!("The mirror has been synced, and is no longer stale.");
+            }
+        }
+    });
+    stale
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    path: PathBuf,
+    socket_addr: SocketAddr,
+    tls_paths: Option<TlsConfig>,
+    store: Arc<dyn CrateStore>,
+    access: AccessControl,
+    server: ServerOptions,
+    git_backend: GitBackend,
+    limits: RequestLimits,
+    max_staleness: Option<Duration>,
+) -> Result<(), MirrorError> {
     let index_path = path.clone();
     let is_tls = tls_paths.is_some();
 
+    check_index_config(&path, socket_addr, is_tls);
+    let stale = max_staleness.map(|max| watch_staleness(path.clone(), max));
+
     // Handle the homepage
     let index = warp::path::end().and(warp::host::optional()).and_then(
         move |authority: Option<Authority>| {
@@ -75,11 +472,7 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
                             .map(|a| format!("{}{}", protocol, a.as_str()))
                             .unwrap_or_else(|| "http://panamax.internal".to_string()),
                     })
-                    .map_err(|_| {
-                        warp::reject::custom(ServeError::Other(
-                            "Could not retrieve rustup platforms.".to_string(),
-                        ))
-                    })
+                    .map_err(|_| warp::reject::custom(ServeError::PlatformsUnavailable))
             }
         },
     );
@@ -100,85 +493,81 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
 
     // Handle crates requests in the format of "/crates/ripgrep/0.1.0/download"
     // This format is the default for cargo, and will be used if an external process rewrites config.json in crates.io-index
-    let crates_mirror_path = path.clone();
-    let crates_dir_native_format = warp::path!("crates" / String / String / "download").and_then(
-        move |name: String, version: String| {
-            let mirror_path = crates_mirror_path.clone();
-            async move { get_crate_file(mirror_path, &name, &version).await }
-        },
-    );
+    // Crates from remote stores are streamed through this client.
+    let client = reqwest::Client::new();
+    let crates_store = store.clone();
+    let crates_client = client.clone();
+    let crates_dir_native_format = warp::path!("crates" / String / String / "download")
+        .and(crate_method())
+        .and_then(move |name: String, version: String, is_head: bool| {
+            let store = crates_store.clone();
+            let client = crates_client.clone();
+            async move { get_crate_file(store, client, &name, &version, is_head).await }
+        });
 
-    // Handle crates requests in the format of either :
-    // - "/crates/1/u/0.2.0/u-0.2.0.crate"
-    // - "/crates/2/bm/0.11.0/bm-0.11.0.crate"
-    // - "/crates/3/c/cde/0.1.1/cde-0.1.1.crate"
-    // - "/crates/se/rd/serde/1.0.130/serde-1.0.130.crate"
-    // This format is used by Panamax, and/or is used if config.json contains "/crates/{prefix}/{crate}/{version}/{crate}-{version}.crate"
-    let crates_mirror_path_2 = path.clone();
-    let crates_dir_condensed_format_1 = warp::path!("crates" / "1" / String / String / String)
-        .map(|name: String, version: String, crate_file: String| (name, version, crate_file))
-        .untuple_one();
-    let crates_dir_condensed_format_2 = warp::path!("crates" / "2" / String / String / String)
-        .map(|name: String, version: String, crate_file: String| (name, version, crate_file))
-        .untuple_one();
-    let crates_dir_condensed_format_3 =
-        warp::path!("crates" / "3" / String / String / String / String)
-            .map(
-                |_: String, name: String, version: String, crate_file: String| {
-                    (name, version, crate_file)
-                },
-            )
-            .untuple_one();
-    let crates_dir_condensed_format_full =
-        warp::path!("crates" / String / String / String / String / String)
-            .map(
-                |_: String, _: String, name: String, version: String, crate_file: String| {
-                    (name, version, crate_file)
-                },
-            )
-            .untuple_one();
+    let crates_dir_condensed_format = crates_dir_condensed_format(store.clone(), client);
 
-    let crates_dir_condensed_format = crates_dir_condensed_format_1
-        .or(crates_dir_condensed_format_2)
-        .unify()
-        .or(crates_dir_condensed_format_3)
-        .unify()
-        .or(crates_dir_condensed_format_full)
-        .unify()
-        .and_then(move |name: String, version: String, crate_file: String| {
-            let mirror_path = crates_mirror_path_2.clone();
-            async move {
-                if !crate_file.ends_with(".crate") || !crate_file.starts_with(&name) {
-                    return Err(warp::reject::not_found());
-                }
-                get_crate_file(mirror_path, &name, &version).await
-            }
+    // Handle crate metadata requests, e.g. "/api/v1/crates/ripgrep/0.1.0"
+    let metadata_store = store.clone();
+    let metadata_index_path = path.join("crates.io-index");
+    let crate_metadata = warp::path!("api" / "v1" / "crates" / String / String)
+        .and(warp::get())
+        .and_then(move |name: String, version: String| {
+            get_crate_metadata(
+                metadata_store.clone(),
+                metadata_index_path.clone(),
+                name,
+                version,
+            )
         });
 
     // Handle git client requests to /git/crates.io-index
     let path_for_git = path.clone();
+    let git_limits = limits.clone();
     let git = warp::path("git")
         .and(warp::path("crates.io-index"))
         .and(warp::path::tail())
         .and(warp::method())
         .and(warp::header::optional::<String>("Content-Type"))
-        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("Content-Encoding"))
+        .and(remote_addr())
         .and(warp::body::stream())
         .and(warp::query::raw().or_else(|_| async { Ok::<(String,), Rejection>((String::new(),)) }))
         .and_then(
-            move |path_tail, method, content_type, remote, body, query| {
+            move |path_tail, method, content_type, content_encoding, remote, body, query| {
                 let mirror_path = path_for_git.clone();
+                let permit = git_limits.acquire_git();
                 async move {
-                    handle_git(
-                        mirror_path,
-                        path_tail,
-                        method,
-                        content_type,
-                        remote,
-                        body,
-                        query,
-                    )
-                    .await
+                    let permit = permit?;
+                    match git_backend {
+                        GitBackend::Cgi { timeout } => {
+                            handle_git(
+                                mirror_path,
+                                path_tail,
+                                method,
+                                content_type,
+                                remote,
+                                body,
+                                query,
+                                permit,
+                                timeout,
+                            )
+                            .await
+                        }
+                        GitBackend::Native { timeout } => {
+                            handle_git_native(
+                                mirror_path,
+                                path_tail,
+                                method,
+                                content_encoding,
+                                body,
+                                query,
+                                permit,
+                                timeout,
+                            )
+                            .await
+                        }
+                    }
                 }
             },
         );
@@ -192,27 +581,185 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
         .or(rustup_dir)
         .or(crates_dir_native_format)
         .or(crates_dir_condensed_format)
+        .or(crate_metadata)
         .or(sparse_index)
         .or(git);
 
-    match tls_paths {
-        Some(TlsConfig {
-            cert_path,
-            key_path,
-        }) => {
-            println!("Running TLS on {socket_addr}");
-            warp::serve(routes)
-                .tls()
-                .cert_path(cert_path)
-                .key_path(key_path)
-                .run(socket_addr)
-                .await;
+    // Check the client against the allowed and denied networks before any route.
+    let access_check = remote_addr()
+        .and_then(move |remote: Option<SocketAddr>| {
+            let allowed = access.allows(remote);
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(ServeError::Forbidden))
+                }
+            }
+        })
+        .untuple_one();
+    // Then take a slot for the request, held until its response has been sent.
+    let request_limit = warp::any().and_then(move || {
+        let permit = limits.acquire_request();
+        async move { permit.map_err(warp::reject::custom) }
+    });
+    let routes = access_check
+        .and(request_limit)
+        .and(routes)
+        .map(|permit: Option<OwnedSemaphorePermit>, reply| {
+            let response = warp::Reply::into_response(reply);
+            match permit {
+                Some(permit) => hold_until_sent(response, permit),
+                None => response,
+            }
+        })
+        .recover(handle_rejection)
+        .map(move |reply| {
+            let mut response = warp::Reply::into_response(reply);
+            if stale.as_ref().is_some_and(|s| s.load(Ordering::Relaxed)) {
+                response.headers_mut().insert(
+                    http::header::WARNING,
+                    http::HeaderValue::from_static(STALE_WARNING),
+                );
+            }
+            response
+        });
+
+    let tls_acceptor = match tls_paths.as_ref().map(load_tls_config) {
+        Some(Ok(config)) => Some(TlsAcceptor::from(Arc::new(config))),
+        Some(Err(e)) => return Err(MirrorError::Tls(e)),
+        None => None,
+    };
+
+    let listener = TcpListener::bind(socket_addr)
+        .await
+        .map_err(|source| MirrorError::Listen {
+            addr: socket_addr,
+            source,
+        })?;
+    if is_tls {
+        println!("Running TLS on {socket_addr}");
+    } else {
+        println!("Running HTTP on {socket_addr}");
+    }
+
+    // Connections are accepted here instead of by warp, so the HTTP settings can be tuned.
+    let http = server.http();
+    let service = warp::service(routes);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Most likely out of file descriptors, so give connections time to close.
+                eprintln!("Failed to accept a connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+
+        let http = http.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let service = service.clone();
+        let service = service_fn(move |mut req| {
+            req.extensions_mut().insert(ClientAddr(remote));
+            service.clone().call(req)
+        });
+        tokio::spawn(async move {
+            // Failed handshakes and dropped connections are up to the client, so they're ignored.
+            match tls_acceptor {
+                Some(acceptor) => {
+                    let handshake =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                    if let Ok(Ok(stream)) = handshake.await {
+                        let _ = http.serve_connection(stream, service).await;
+                    }
+                }
+                None => {
+                    let _ = http.serve_connection(stream, service).await;
+                }
+            }
+        });
+    }
+}
+
+/// Warn if the crates.io-index config.json doesn't look like it points at this server.
+///
+/// A mismatch between `rewrite` and `serve` makes cargo fail in confusing ways,
+/// so this is checked once at startup.
+fn check_index_config(path: &Path, socket_addr: SocketAddr, is_tls: bool) {
+    let config_path = path.join("crates.io-index").join("config.json");
+    let Ok(data) = std::fs::read_to_string(&config_path) else {
+        // No crates.io-index, so there is nothing to check.
+        return;
+    };
+    let config: ConfigJson = match serde_json::from_str(&data) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Warning: could not parse {}: {e}", config_path.display());
+            return;
         }
-        None => {
-            println!("Running HTTP on {socket_addr}");
-            warp::serve(routes).run(socket_addr).await;
+    };
+
+    let mut warned = false;
+    for (key, value) in [("dl", &config.dl), ("api", &config.api)] {
+        for warning in config_url_warnings(value, socket_addr, is_tls) {
+            eprintln!("Warning: config.json `{key}` is \"{value}\", {warning}.");
+            warned = true;
         }
     }
+    if warned {
+        eprintln!("These warnings can be ignored if the mirror is served behind a reverse proxy.");
+    }
+}
+
+/// Check one config.json URL against the address and TLS setting this server uses.
+fn config_url_warnings(value: &str, socket_addr: SocketAddr, is_tls: bool) -> Vec<String> {
+    let url = match Url::parse(value) {
+        Ok(url) => url,
+        Err(_) => return vec!["which is not a valid URL".to_string()],
+    };
+
+    let mut warnings = Vec::new();
+
+    if url
+        .host_str()
+        .is_some_and(|h| h == "crates.io" || h.ends_with(".crates.io"))
+    {
+        warnings.push(
+            "which points to crates.io rather than this mirror. Run `panamax rewrite` to fix this"
+                .to_string(),
+        );
+        return warnings;
+    }
+
+    match url.scheme() {
+        "https" if !is_tls => warnings.push("but TLS is not enabled".to_string()),
+        "http" if is_tls => warnings.push("but this server only accepts https".to_string()),
+        _ => {}
+    }
+
+    if let Some(port) = url.port_or_known_default() {
+        if port != socket_addr.port() {
+            warnings.push(format!(
+                "but the server listens on port {}",
+                socket_addr.port()
+            ));
+        }
+    }
+
+    let host_ip = match url.host() {
+        Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        _ => None,
+    };
+    if let Some(ip) = host_ip {
+        if !socket_addr.ip().is_unspecified() && ip != socket_addr.ip() {
+            warnings.push(format!("but the server listens on {}", socket_addr.ip()));
+        }
+    }
+
+    warnings
 }
 
 /// Get all rustup platforms available on the mirror.
@@ -250,34 +797,244 @@ async fn get_rustup_platforms(path: PathBuf) -> io::Result<Vec<Platform>> {
     Ok(output)
 }
 
+/// The names to look a crate up with, starting with `name` itself.
+///
+/// Clients don't always agree on `-` and `_` in crate names, so on a miss,
+/// the crate is looked for with the other separator.
+fn crate_name_variants(name: &str) -> Vec<String> {
+    let mut names = vec![name.to_string()];
+    names.extend(
+        [name.replace('-', "_"), name.replace('_', "-")]
+            .into_iter()
+            .filter(|n| n != name),
+    );
+    names
+}
+
+/// Find a crate in the store.
+async fn locate_crate(
+    store: &dyn CrateStore,
+    name: &str,
+    version: &str,
+) -> Result<StoredCrate, ServeError> {
+    // This serves the file under the name the crate was actually published with.
+    for n in &crate_name_variants(name) {
+        if let Some(stored) = store.locate(n, version).await? {
+            return Ok(stored);
+        }
+    }
+
+    Err(ServeError::CrateNotFound {
+        name: name.to_string(),
+        version: version.to_string(),
+    })
+}
+
+/// Provenance of a crate in the mirror, served at /api/v1/crates/{name}/{version}.
+#[derive(Debug, Serialize)]
+struct CrateMetadata {
+    name: String,
+    version: String,
+    /// Size of the crate file, unless it is stored behind a redirect.
+    size: Option<u64>,
+    /// Checksum of the crate, from the index.
+    sha256: Option<String>,
+    /// When the mirror downloaded the crate, in seconds since the Unix epoch.
+    /// Only known for crates stored on the local disk.
+    downloaded_at: Option<u64>,
+}
+
+/// Return the metadata of a crate as JSON.
+async fn get_crate_metadata(
+    store: Arc<dyn CrateStore>,
+    index_path: PathBuf,
+    name: String,
+    version: String,
+) -> Result<Response<Body>, Rejection> {
+    let (size, modified) = match locate_crate(store.as_ref(), &name, &version)
+        .await
+        .map_err(warp::reject::custom)?
+    {
+        StoredCrate::File(full_path) => {
+            let meta = tokio::fs::metadata(full_path)
+                .await
+                .map_err(|e| warp::reject::custom(ServeError::Io(e)))?;
+            (Some(meta.len()), meta.modified().ok())
+        }
+        StoredCrate::Redirect(_) => (None, None),
+        StoredCrate::Remote { len, .. } => (Some(len), None),
+    };
+
+    let names = crate_name_variants(&name);
+    let index_version = version.clone();
+    let entry = tokio::task::spawn_blocking(move || {
+        names
+            .iter()
+            .find_map(|n| read_index_entry(&index_path, n, &index_version))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(ServeError::Io(e.into())))?;
+
+    let metadata = CrateMetadata {
+        name: entry.as_ref().map_or(name, |e| e.get_name().to_string()),
+        version,
+        size,
+        sha256: entry.and_then(|e| e.get_cksum().map(str::to_string)),
+        downloaded_at: modified
+            .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+    };
+    let body = serde_json::to_vec(&metadata)
+        .map_err(|e| warp::reject::custom(ServeError::Io(e.into())))?;
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .map_err(|e| warp::reject::custom(ServeError::from(e)))
+}
+
+/// Crate files can be requested with GET, or with HEAD to only get their headers.
+/// The filter extracts true for HEAD requests.
+fn crate_method() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::get()
+        .map(|| false)
+        .or(warp::head().map(|| true))
+        .unify()
+}
+
+/// Handle crates requests in the format of either :
+/// - "/crates/1/u/0.2.0/u-0.2.0.crate"
+/// - "/crates/2/bm/0.11.0/bm-0.11.0.crate"
+/// - "/crates/3/c/cde/0.1.1/cde-0.1.1.crate"
+/// - "/crates/se/rd/serde/1.0.130/serde-1.0.130.crate"
+///
+/// This format is used by Panamax, and/or is used if config.json contains
+/// "/crates/{lowerprefix}/{crate}/{version}/{crate}-{version}.crate". The directories are
+/// lowercased, unlike the file name of mixed-case crates, so names are compared ignoring case.
+fn crates_dir_condensed_format(
+    store: Arc<dyn CrateStore>,
+    client: reqwest::Client,
+) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    let crates_dir_condensed_format_1 = warp::path!("crates" / "1" / String / String / String)
+        .map(|name: String, version: String, crate_file: String| (name, version, crate_file))
+        .untuple_one();
+    let crates_dir_condensed_format_2 = warp::path!("crates" / "2" / String / String / String)
+        .map(|name: String, version: String, crate_file: String| (name, version, crate_file))
+        .untuple_one();
+    let crates_dir_condensed_format_3 =
+        warp::path!("crates" / "3" / String / String / String / String)
+            .map(
+                |_: String, name: String, version: String, crate_file: String| {
+                    (name, version, crate_file)
+                },
+            )
+            .untuple_one();
+    let crates_dir_condensed_format_full =
+        warp::path!("crates" / String / String / String / String / String)
+            .map(
+                |_: String, _: String, name: String, version: String, crate_file: String| {
+                    (name, version, crate_file)
+                },
+            )
+            .untuple_one();
+
+    crates_dir_condensed_format_1
+        .or(crates_dir_condensed_format_2)
+        .unify()
+        .or(crates_dir_condensed_format_3)
+        .unify()
+        .or(crates_dir_condensed_format_full)
+        .unify()
+        .and(crate_method())
+        .and_then(
+            move |name: String, version: String, crate_file: String, is_head: bool| {
+                let store = store.clone();
+                let client = client.clone();
+                async move {
+                    let is_crate_file = crate_file.ends_with(".crate")
+                        && crate_file.to_lowercase().starts_with(&name.to_lowercase());
+                    if !is_crate_file {
+                        return Err(warp::reject::not_found());
+                    }
+                    get_crate_file(store, client, &name, &version, is_head).await
+                }
+            },
+        )
+}
+
 /// Return a crate file as an HTTP response.
+/// If `is_head` is true, only the headers are returned.
 async fn get_crate_file(
-    mirror_path: PathBuf,
+    store: Arc<dyn CrateStore>,
+    client: reqwest::Client,
     name: &str,
     version: &str,
+    is_head: bool,
 ) -> Result<Response<Body>, Rejection> {
-    let full_path =
-        get_crate_path(&mirror_path, name, version).ok_or_else(warp::reject::not_found)?;
+    let not_found = || {
+        warp::reject::custom(ServeError::CrateNotFound {
+            name: name.to_string(),
+            version: version.to_string(),
+        })
+    };
 
-    let file = File::open(full_path)
+    let mut last_modified = None;
+    let (body, len) = match locate_crate(store.as_ref(), name, version)
         .await
-        .map_err(|_| warp::reject::not_found())?;
-    let meta = file
-        .metadata()
-        .await
-        .map_err(|_| warp::reject::not_found())?;
-    let stream = FramedRead::new(file, BytesCodec::new()).map_ok(BytesMut::freeze);
-
-    let body = Body::wrap_stream(stream);
+        .map_err(warp::reject::custom)?
+    {
+        StoredCrate::File(full_path) => {
+            let file = File::open(full_path).await.map_err(|_| not_found())?;
+            let meta = file.metadata().await.map_err(|_| not_found())?;
+            let body = if is_head {
+                Body::empty()
+            } else {
+                Body::wrap_stream(FramedRead::new(file, BytesCodec::new()).map_ok(BytesMut::freeze))
+            };
+            // The modification time is when the mirror downloaded the crate.
+            last_modified = meta.modified().ok();
+            (body, meta.len())
+        }
+        StoredCrate::Redirect(url) => {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = http::StatusCode::FOUND;
+            let location = http::HeaderValue::try_from(url)
+                .map_err(|e| warp::reject::custom(ServeError::Warp(e.into())))?;
+            resp.headers_mut().insert(http::header::LOCATION, location);
+            return Ok(resp);
+        }
+        StoredCrate::Remote { url, len } => {
+            if is_head {
+                (Body::empty(), len)
+            } else {
+                let remote = client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| warp::reject::custom(ServeError::Upstream(e)))?;
+                (Body::wrap_stream(remote.bytes_stream()), len)
+            }
+        }
+    };
 
     let mut resp = Response::new(body);
     resp.headers_mut()
-        .insert(http::header::CONTENT_LENGTH, meta.len().into());
+        .insert(http::header::CONTENT_LENGTH, len.into());
+    if let Some(modified) = last_modified {
+        let value = http::HeaderValue::try_from(httpdate::fmt_http_date(modified))
+            .map_err(|e| warp::reject::custom(ServeError::Warp(e.into())))?;
+        resp.headers_mut()
+            .insert(http::header::LAST_MODIFIED, value);
+    }
 
     Ok(resp)
 }
 
 /// Handle a request from a git client.
+///
+/// The output of `git http-backend` is spooled into a temporary file, so the index is only
+/// locked while git is running. `permit` is held until the response has been sent.
+#[allow(clippy::too_many_arguments)]
 async fn handle_git<S, B>(
     mirror_path: PathBuf,
     path_tail: Tail,
@@ -286,11 +1043,14 @@ async fn handle_git<S, B>(
     remote: Option<SocketAddr>,
     mut body: S,
     query: String,
+    permit: Option<OwnedSemaphorePermit>,
+    timeout: Duration,
 ) -> Result<Response<Body>, Rejection>
 where
     S: Stream<Item = Result<B, warp::Error>> + Send + Unpin + 'static,
     B: bytes::Buf + Sized,
 {
+    let deadline = Instant::now() + timeout;
     let remote = remote
         .map(|r| r.ip().to_string())
         .unwrap_or_else(|| "127.0.0.1".to_string());
@@ -318,37 +1078,52 @@ where
     cmd.stderr(Stdio::inherit());
     cmd.stdout(Stdio::piped());
     cmd.stdin(Stdio::piped());
+    // Returning early, e.g. on a timeout, drops the child, which then gets killed and reaped.
+    cmd.kill_on_drop(true);
 
-    let p = cmd.spawn().map_err(ServeError::from)?;
-
-    // Handle sending git client body to http-backend, if any
-    let mut git_input = p.stdin.expect("Process should always have stdin");
-    while let Some(Ok(mut buf)) = body.next().await {
-        git_input
-            .write_all_buf(&mut buf)
-            .await
-            .map_err(ServeError::from)?;
-    }
+    // Keep the index from being fast-forwarded by a sync while it is being served.
+    let index_guard = INDEX_LOCK.read().await;
 
-    // Collect headers from git CGI output
-    let mut git_output = BufReader::new(p.stdout.expect("Process should always have stdout"));
-    let mut headers = HashMap::new();
-    loop {
-        let mut line = String::new();
-        git_output
-            .read_line(&mut line)
-            .await
-            .map_err(ServeError::from)?;
+    let mut child = cmd.spawn().map_err(ServeError::GitBackendFailed)?;
+    let mut git_input = child
+        .stdin
+        .take()
+        .expect("Process should always have stdin");
+    let mut git_output = BufReader::new(
+        child
+            .stdout
+            .take()
+            .expect("Process should always have stdout"),
+    );
 
-        let line = line.trim_end();
-        if line.is_empty() {
-            break;
+    let cgi_headers = async {
+        // Handle sending git client body to http-backend, if any
+        while let Some(Ok(mut buf)) = body.next().await {
+            git_input.write_all_buf(&mut buf).await?;
         }
+        drop(git_input);
+
+        // Collect headers from git CGI output
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            git_output.read_line(&mut line).await?;
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
 
-        if let Some((key, value)) = line.split_once(": ") {
-            headers.insert(key.to_string(), value.to_string());
+            if let Some((key, value)) = line.split_once(": ") {
+                headers.insert(key.to_string(), value.to_string());
+            }
         }
-    }
+        Ok(headers)
+    };
+    let headers = tokio::time::timeout_at(deadline, cgi_headers)
+        .await
+        .map_err(|_| ServeError::GitBackendTimeout)?
+        .map_err(ServeError::GitBackendFailed)?;
 
     // Add headers to response (except for Status, which is the "200 OK" line)
     let mut resp = Response::builder();
@@ -360,27 +1135,401 @@ where
         }
     }
 
+    // Spool the rest of the output, rather than holding the index lock for as long
+    // as a slow client takes to download it.
+    let spool = async {
+        let mut spool = File::from_std(tempfile::tempfile()?);
+        tokio::io::copy(&mut git_output, &mut spool).await?;
+        child.wait().await?;
+        spool.flush().await?;
+        spool.rewind().await?;
+        Ok(spool)
+    };
+    let spool = match tokio::time::timeout_at(deadline, spool).await {
+        Ok(spool) => spool.map_err(ServeError::GitBackendFailed)?,
+        Err(_) => {
+            eprintln!("git http-backend timed out after {timeout:?}, killing it.");
+            return Err(warp::reject::custom(ServeError::GitBackendTimeout));
+        }
+    };
+    drop(index_guard);
+
     // Create channel, so data can be streamed without being fully loaded
     // into memory. Requires a separate future to be spawned.
     let (sender, body) = Body::channel();
-    tokio::spawn(send_git(sender, git_output));
+    tokio::spawn(async move {
+        if tokio::time::timeout_at(deadline, send_git(sender, spool))
+            .await
+            .is_err()
+        {
+            eprintln!("git fetch timed out after {timeout:?}, giving up on it.");
+        }
+        drop(permit);
+    });
 
     let resp = resp.body(body).map_err(ServeError::from)?;
     Ok(resp)
 }
 
+/// Write the response to an upload-pack `request` to an anonymous temporary file, rewound to
+/// its start. The file is deleted once it's closed.
+fn spool_upload_pack(repo_path: &Path, request: &[u8]) -> Result<std::fs::File, ServeError> {
+    let repo = Repository::open(repo_path).map_err(UploadPackError::from)?;
+    let mut out = io::BufWriter::new(tempfile::tempfile()?);
+    let mut write_error = None;
+    upload_pack(&repo, request, &mut |chunk| match out.write_all(chunk) {
+        Ok(()) => true,
+        Err(e) => {
+            write_error = Some(e);
+            false
+        }
+    })?;
+    if let Some(e) = write_error {
+        return Err(e.into());
+    }
+    let mut pack = out.into_inner().map_err(|e| e.into_error())?;
+    pack.rewind()?;
+    Ok(pack)
+}
+
+/// Largest upload-pack request accepted by the native git backend.
+const MAX_GIT_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// Handle a request from a git client with the native backend, without running git.
+///
+/// Only fetches and clones through the smart HTTP protocol are supported.
+/// `permit` is held while the response is being sent, which is given up on after `timeout`.
+#[allow(clippy::too_many_arguments)]
+async fn handle_git_native<S, B>(
+    mirror_path: PathBuf,
+    path_tail: Tail,
+    method: http::Method,
+    content_encoding: Option<String>,
+    mut body: S,
+    query: String,
+    permit: Option<OwnedSemaphorePermit>,
+    timeout: Duration,
+) -> Result<Response<Body>, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Unpin + 'static,
+    B: bytes::Buf + Sized,
+{
+    let deadline = Instant::now() + timeout;
+    let repo_path = mirror_path.join("crates.io-index");
+    let bad_request = |e: String| warp::reject::custom(ServeError::GitRequest(e));
+
+    match (&method, path_tail.as_str()) {
+        (&http::Method::GET, "info/refs") if query == "service=git-upload-pack" => {
+            let index_guard = INDEX_LOCK.read().await;
+            let refs = tokio::task::spawn_blocking(move || {
+                let _index_guard = index_guard;
+                advertise_refs(&Repository::open(repo_path)?)
+            })
+            .await
+            .map_err(|e| ServeError::Io(e.into()))?
+            .map_err(|e| warp::reject::custom(ServeError::from(e)))?;
+
+            Response::builder()
+                .header(
+                    http::header::CONTENT_TYPE,
+                    "application/x-git-upload-pack-advertisement",
+                )
+                .header(http::header::CACHE_CONTROL, "no-cache")
+                .body(Body::from(refs))
+                .map_err(|e| warp::reject::custom(ServeError::from(e)))
+        }
+        (&http::Method::POST, "git-upload-pack") => {
+            let mut request = Vec::new();
+            while let Some(chunk) = body.next().await {
+                let mut chunk = chunk.map_err(|e| bad_request(e.to_string()))?;
+                while chunk.has_remaining() {
+                    request.extend_from_slice(chunk.chunk());
+                    chunk.advance(chunk.chunk().len());
+                }
+                if request.len() > MAX_GIT_REQUEST_SIZE {
+                    return Err(bad_request("request too large".to_string()));
+                }
+            }
+            // Git compresses larger requests.
+            if content_encoding.as_deref() == Some("gzip") {
+                request = gunzip(&request).map_err(|e| bad_request(e.to_string()))?;
+            }
+
+            // The pack is built into a temporary file on a blocking thread, so the index is
+            // only locked while it's being read, not while a slow client downloads it.
+            let index_guard = INDEX_LOCK.read().await;
+            let pack = tokio::task::spawn_blocking(move || {
+                let _index_guard = index_guard;
+                spool_upload_pack(&repo_path, &request)
+            })
+            .await
+            .map_err(|e| ServeError::Io(e.into()))?
+            .map_err(|e| {
+                eprintln!("Error while serving a git fetch: {e}");
+                warp::reject::custom(e)
+            })?;
+
+            let (sender, body) = Body::channel();
+            tokio::spawn(async move {
+                let pack = File::from_std(pack);
+                if tokio::time::timeout_at(deadline, send_git(sender, pack))
+                    .await
+                    .is_err()
+                {
+                    eprintln!("git fetch timed out after {timeout:?}, giving up on it.");
+                }
+                drop(permit);
+            });
+
+            Response::builder()
+                .header(
+                    http::header::CONTENT_TYPE,
+                    "application/x-git-upload-pack-result",
+                )
+                .header(http::header::CACHE_CONTROL, "no-cache")
+                .body(body)
+                .map_err(|e| warp::reject::custom(ServeError::from(e)))
+        }
+        (_, "git-receive-pack") => Err(warp::reject::custom(ServeError::Forbidden)),
+        (_, "info/refs") if query == "service=git-receive-pack" => {
+            Err(warp::reject::custom(ServeError::Forbidden))
+        }
+        _ => Err(warp::reject::not_found()),
+    }
+}
+
+/// Decompress a gzip request body, failing if it is larger than `MAX_GIT_REQUEST_SIZE`.
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    use std::io::Read;
+
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .take(MAX_GIT_REQUEST_SIZE as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > MAX_GIT_REQUEST_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the decompressed request is too large",
+        ));
+    }
+    Ok(decoded)
+}
+
+/// Maximum size of each chunk sent from the git CGI process to the client.
+const GIT_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Send data from git CGI process to hyper Sender, until there is no more
 /// data left.
+///
+/// Each read is bounded to `GIT_CHUNK_SIZE`, and the buffer's allocation is reused
+/// once hyper is done with previously sent chunks. Awaiting `send_data` waits for
+/// the client to be ready, so a slow client can't make the output pile up in memory.
 async fn send_git(
     mut sender: Sender,
-    mut git_output: BufReader<ChildStdout>,
+    mut git_output: impl AsyncRead + Unpin,
 ) -> Result<(), ServeError> {
+    let mut buf = BytesMut::with_capacity(GIT_CHUNK_SIZE);
     loop {
-        let mut bytes_out = BytesMut::new();
-        git_output.read_buf(&mut bytes_out).await?;
-        if bytes_out.is_empty() {
+        buf.reserve(GIT_CHUNK_SIZE);
+        let n = (&mut git_output)
+            .take(GIT_CHUNK_SIZE as u64)
+            .read_buf(&mut buf)
+            .await?;
+        if n == 0 {
             return Ok(());
         }
-        sender.send_data(bytes_out.freeze()).await?;
+        sender.send_data(buf.split().freeze()).await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        config_url_warnings, crates_dir_condensed_format, gunzip, handle_rejection,
+        hold_until_sent, is_stale, AccessControl, RequestLimits, ServeError, MAX_GIT_REQUEST_SIZE,
+    };
+    use crate::{crate_store::FsCrateStore, crates::get_crate_path, mirror::ConfigServe};
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::Semaphore;
+    use warp::{
+        http::StatusCode,
+        hyper::{body, Body, Response},
+    };
+
+    #[tokio::test]
+    async fn mixed_case_crate_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_path = get_crate_path(dir.path(), "Inflector", "0.11.4").unwrap();
+        std::fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+        std::fs::write(&crate_path, b"crate").unwrap();
+
+        let route = crates_dir_condensed_format(
+            Arc::new(FsCrateStore::new(dir.path())),
+            reqwest::Client::new(),
+        );
+        for path in [
+            "/crates/in/fl/inflector/0.11.4/Inflector-0.11.4.crate",
+            "/crates/in/fl/Inflector/0.11.4/Inflector-0.11.4.crate",
+        ] {
+            let res = warp::test::request().path(path).reply(&route).await;
+            assert_eq!(res.status(), StatusCode::OK, "{path}");
+            assert_eq!(res.body().as_ref(), b"crate");
+        }
+
+        let res = warp::test::request()
+            .path("/crates/in/fl/inflector/0.11.4/other-0.11.4.crate")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn access_control() {
+        let access = AccessControl::try_from(&ConfigServe {
+            allow_cidrs: Some(vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string()]),
+            deny_cidrs: Some(vec!["10.1.0.0/16".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let allows = |addr: &str| access.allows(Some(addr.parse().unwrap()));
+        assert!(allows("10.0.0.1:1234"));
+        assert!(allows("[::ffff:10.0.0.1]:1234"));
+        assert!(allows("[fd00::1]:1234"));
+        assert!(!allows("10.1.0.1:1234"));
+        assert!(!allows("192.168.0.1:1234"));
+        assert!(!access.allows(None));
+
+        assert!(AccessControl::default().allows(None));
+    }
+
+    #[test]
+    fn request_limits() {
+        let limits = RequestLimits::try_from(&ConfigServe {
+            max_concurrent_git: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let permit = limits.acquire_git().unwrap();
+        assert!(permit.is_some());
+        assert!(matches!(limits.acquire_git(), Err(ServeError::Overloaded)));
+        drop(permit);
+        assert!(limits.acquire_git().unwrap().is_some());
+
+        // Other requests aren't limited.
+        let permits: Vec<_> = (0..100)
+            .map(|_| limits.acquire_request().unwrap())
+            .collect();
+        assert!(permits.iter().all(Option::is_none));
+
+        assert!(RequestLimits::try_from(&ConfigServe {
+            max_concurrent_requests: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn gunzip_limit() {
+        use std::io::Write;
+
+        let gzip = |len: usize| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&vec![0; len]).unwrap();
+            encoder.finish().unwrap()
+        };
+        assert_eq!(gunzip(&gzip(100)).unwrap().len(), 100);
+        assert_eq!(
+            gunzip(&gzip(MAX_GIT_REQUEST_SIZE)).unwrap().len(),
+            MAX_GIT_REQUEST_SIZE
+        );
+        assert!(gunzip(&gzip(MAX_GIT_REQUEST_SIZE + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn permit_held_while_sending() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.clone().try_acquire_owned().unwrap();
+        let response = hold_until_sent(Response::new(Body::from("body")), permit);
+        assert_eq!(semaphore.available_permits(), 0);
+
+        let sent = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(sent, "body");
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn config_url_checks() {
+        let addr = "0.0.0.0:8080".parse().unwrap();
+        let warnings = |url| config_url_warnings(url, addr, false).len();
+
+        assert_eq!(warnings("http://panamax.internal:8080/crates"), 0);
+        assert_eq!(warnings("http://10.0.0.1:8080/crates"), 0);
+        // The default port for http is 80.
+        assert_eq!(warnings("http://panamax.internal/crates"), 1);
+        assert_eq!(warnings("https://panamax.internal:8080/crates"), 1);
+        assert_eq!(warnings("https://static.crates.io/crates"), 1);
+        assert_eq!(warnings("not a url"), 1);
+
+        let addr = "10.0.0.1:8443".parse().unwrap();
+        assert_eq!(
+            config_url_warnings("https://10.0.0.2:8443/crates", addr, true).len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rejection_statuses() {
+        let status = |e| async {
+            handle_rejection(warp::reject::custom(e))
+                .await
+                .unwrap()
+                .status()
+        };
+        let not_found = ServeError::CrateNotFound {
+            name: "serde".to_string(),
+            version: "1.0.0".to_string(),
+        };
+        assert_eq!(status(not_found).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(ServeError::Forbidden).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(ServeError::PlatformsUnavailable).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            status(ServeError::GitBackendTimeout).await,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+
+        let busy = handle_rejection(warp::reject::custom(ServeError::Overloaded))
+            .await
+            .unwrap();
+        assert_eq!(busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(busy.headers()["retry-after"], "5");
+
+        assert_eq!(
+            handle_rejection(warp::reject::not_found())
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // Other rejections are left to warp.
+        #[derive(Debug)]
+        struct OtherRejection;
+        impl warp::reject::Reject for OtherRejection {}
+        assert!(handle_rejection(warp::reject::custom(OtherRejection))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn staleness() {
+        let day = Duration::from_secs(86400);
+        assert!(!is_stale(Some(Duration::from_secs(3600)), day));
+        assert!(is_stale(Some(day * 2), day));
+        assert!(is_stale(None, day));
     }
 }
@@ -0,0 +1,318 @@
+//! Staged syncs, which update a copy of the mirror and swap it in once the sync succeeds,
+//! so that the mirror is never served half-updated.
+//!
+//! The staging copy is `<mirror>.staging`, next to the mirror. Files that are only ever
+//! replaced, never rewritten in place, are hardlinked from the mirror instead of copied.
+//! If the mirror path is a symlink, e.g. `current -> current.1700000000`, the staging copy
+//! is next to the symlink's target instead, and the swap points the symlink at the synced
+//! copy atomically. Otherwise the mirror directory is renamed out of the way and the copy
+//! renamed into its place, leaving a short window without a mirror.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Path of the staging copy of the mirror at `path`.
+pub fn staging_path(path: &Path) -> Result<PathBuf, io::Error> {
+    generation_path(path, "staging")
+}
+
+/// `<name>.<generation>` for the mirror at `path`, next to the symlink's target if `path`
+/// is a symlink, so that it can be renamed into place on the same file system.
+fn generation_path(path: &Path, generation: &str) -> Result<PathBuf, io::Error> {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(format!(".{generation}"));
+    if path.symlink_metadata()?.file_type().is_symlink() {
+        let target = fs::canonicalize(path)?;
+        Ok(target.parent().unwrap_or(Path::new("")).join(name))
+    } else {
+        Ok(path.with_file_name(name))
+    }
+}
+
+/// Whether `dir` is a generation `promote` created for the mirror symlink at `path`.
+fn is_generation(path: &Path, dir: &Path) -> bool {
+    let (Some(name), Some(dir_name)) = (path.file_name(), dir.file_name()) else {
+        return false;
+    };
+    let (name, dir_name) = (name.to_string_lossy(), dir_name.to_string_lossy());
+    dir_name
+        .strip_prefix(&*name)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .is_some_and(|secs| !secs.is_empty() && secs.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Whether a file in the mirror can be shared between the mirror and its staging copy.
+///
+/// Downloads are written next to their destination and renamed into place, and git only
+/// adds objects, so those files are never modified. Checksum files, the checked out
+/// crates.io-index and the files at the top of the mirror may be rewritten in place.
+fn is_linkable(relative: &Path) -> bool {
+    let mut components = relative.components().map(|c| c.as_os_str());
+    let is_sha256 = relative.extension().is_some_and(|ext| ext == "sha256");
+    match (components.next(), components.next(), components.next()) {
+        (_, None, _) => false,
+        (Some(index), Some(git), Some(objects)) if index == "crates.io-index" => {
+            git == ".git" && objects == "objects"
+        }
+        (Some(index), _, _) if index == "crates.io-index" => false,
+        _ => !is_sha256,
+    }
+}
+
+/// Bring the staging copy at `staging` up to date with the mirror at `path`.
+///
+/// An existing staging copy, e.g. from an interrupted sync, keeps the files that are only
+/// ever replaced, so what the interrupted sync downloaded isn't lost. Everything else is
+/// copied from the mirror again, and the index files the mirror doesn't have are removed,
+/// so the staging copy never has an older config or index than the mirror.
+/// Symlinks are recreated as they are, so a symlinked directory is shared by both.
+pub fn prepare(path: &Path, staging: &Path) -> Result<(), io::Error> {
+    // The mirror itself may be a symlink, unlike anything below it.
+    fs::create_dir_all(staging)?;
+    for entry in walkdir::WalkDir::new(path).min_depth(1) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(path).map_err(io::Error::other)?;
+        let to = staging.join(relative);
+        let file_type = entry.file_type();
+        let staged = to.symlink_metadata().is_ok();
+        if file_type.is_dir() {
+            fs::create_dir_all(&to)?;
+        } else if file_type.is_symlink() {
+            if !staged {
+                symlink(&fs::read_link(entry.path())?, &to)?;
+            }
+        } else if is_linkable(relative) {
+            if !staged && fs::hard_link(entry.path(), &to).is_err() {
+                fs::copy(entry.path(), &to)?;
+            }
+        } else {
+            if staged {
+                fs::remove_file(&to)?;
+            }
+            fs::copy(entry.path(), &to)?;
+        }
+    }
+
+    let staged_index = staging.join("crates.io-index");
+    if staged_index.is_dir() {
+        for entry in walkdir::WalkDir::new(&staged_index) {
+            let entry = entry?;
+            let relative = entry
+                .path()
+                .strip_prefix(staging)
+                .map_err(io::Error::other)?;
+            let is_stale = !entry.file_type().is_dir()
+                && !is_linkable(relative)
+                && path.join(relative).symlink_metadata().is_err();
+            if is_stale {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the mirror at `path` with its synced staging copy at `staging`.
+///
+/// Returns the previous mirror directory if it was kept: a symlink's old target is only
+/// removed if it is a generation `promote` created.
+pub fn promote(path: &Path, staging: &Path) -> Result<Option<PathBuf>, io::Error> {
+    if path.symlink_metadata()?.file_type().is_symlink() {
+        // Point the symlink at the staging copy, renamed after the time it was promoted,
+        // by renaming a new symlink over it.
+        let old_target = fs::canonicalize(path)?;
+        let mut secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let generation = loop {
+            let generation = generation_path(path, &secs.to_string())?;
+            if generation.symlink_metadata().is_err() {
+                break generation;
+            }
+            secs += 1;
+        };
+        fs::rename(staging, &generation)?;
+        let link = sibling_path(path, ".link");
+        let _ = fs::remove_file(&link);
+        // A generation next to the symlink is linked relatively, so the two can be moved.
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let link_target = match generation.parent() {
+            Some(dir) if fs::canonicalize(dir).ok() == fs::canonicalize(parent).ok() => {
+                PathBuf::from(generation.file_name().unwrap_or_default())
+            }
+            _ => generation.clone(),
+        };
+        symlink(&link_target, &link)?;
+        fs::rename(&link, path)?;
+        if old_target == fs::canonicalize(path)? {
+            return Ok(None);
+        }
+        if !is_generation(path, &old_target) {
+            return Ok(Some(old_target));
+        }
+        fs::remove_dir_all(&old_target)?;
+    } else {
+        let old = sibling_path(path, ".old");
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        fs::rename(path, &old)?;
+        fs::rename(staging, path)?;
+        fs::remove_dir_all(&old)?;
+    }
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> Result<(), io::Error> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> Result<(), io::Error> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_generation, is_linkable, prepare, promote, staging_path};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn linkable_files() {
+        assert!(is_linkable(Path::new("dist/2023-05-01/cargo.tar.xz")));
+        assert!(is_linkable(Path::new("crates/se/rd/serde/1.0.0/download")));
+        assert!(is_linkable(Path::new(
+            "crates.io-index/.git/objects/ab/cdef"
+        )));
+        assert!(!is_linkable(Path::new(
+            "dist/2023-05-01/cargo.tar.xz.sha256"
+        )));
+        assert!(!is_linkable(Path::new(
+            "crates.io-index/.git/refs/heads/master"
+        )));
+        assert!(!is_linkable(Path::new("crates.io-index/se/rd/serde")));
+        assert!(!is_linkable(Path::new("mirror.toml")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn staged_sync() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let live = dir.join("mirror.1");
+        fs::create_dir_all(live.join("dist")).unwrap();
+        fs::write(live.join("mirror.toml"), "old").unwrap();
+        fs::write(live.join("dist/cargo.tar.xz"), "cargo").unwrap();
+        let path = dir.join("current");
+        symlink("mirror.1", &path).unwrap();
+
+        let staging = staging_path(&path).unwrap();
+        assert_eq!(
+            staging,
+            fs::canonicalize(dir).unwrap().join("current.staging")
+        );
+        prepare(&path, &staging).unwrap();
+        assert_eq!(
+            fs::metadata(staging.join("dist/cargo.tar.xz"))
+                .unwrap()
+                .nlink(),
+            2
+        );
+        // Rewriting a copied file in the staging copy leaves the mirror alone.
+        fs::write(staging.join("mirror.toml"), "new").unwrap();
+        assert_eq!(fs::read_to_string(path.join("mirror.toml")).unwrap(), "old");
+
+        // panamax didn't create the old target, so it is kept.
+        assert_eq!(
+            promote(&path, &staging).unwrap(),
+            Some(fs::canonicalize(&live).unwrap())
+        );
+        assert_eq!(fs::read_to_string(path.join("mirror.toml")).unwrap(), "new");
+        assert!(live.exists());
+        assert!(!staging.exists());
+
+        // A generation panamax created is removed once it is replaced.
+        let generation = fs::canonicalize(&path).unwrap();
+        assert!(is_generation(&path, &generation));
+        prepare(&path, &staging).unwrap();
+        assert_eq!(promote(&path, &staging).unwrap(), None);
+        assert!(!generation.exists());
+
+        // Without a symlink, the directory itself is swapped.
+        let path = fs::canonicalize(&path).unwrap();
+        prepare(&path, &staging).unwrap();
+        fs::write(staging.join("mirror.toml"), "newer").unwrap();
+        promote(&path, &staging).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("mirror.toml")).unwrap(),
+            "newer"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_staging_copy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("mirror");
+        fs::create_dir_all(path.join("crates.io-index/se/rd")).unwrap();
+        fs::write(path.join("mirror.toml"), "live").unwrap();
+        let staging = staging_path(&path).unwrap();
+
+        // A staging copy left behind by an interrupted sync, older than the mirror.
+        fs::create_dir_all(staging.join("crates.io-index/.git/objects")).unwrap();
+        fs::create_dir_all(staging.join("crates.io-index/se/rd")).unwrap();
+        fs::create_dir_all(staging.join("dist")).unwrap();
+        fs::write(staging.join("mirror.toml"), "stale").unwrap();
+        fs::write(staging.join("crates.io-index/se/rd/serde"), "stale").unwrap();
+        fs::write(staging.join("crates.io-index/.git/objects/ab"), "object").unwrap();
+        fs::write(staging.join("dist/cargo.tar.xz"), "cargo").unwrap();
+
+        prepare(&path, &staging).unwrap();
+        assert_eq!(
+            fs::read_to_string(staging.join("mirror.toml")).unwrap(),
+            "live"
+        );
+        assert!(!staging.join("crates.io-index/se/rd/serde").exists());
+        // What the interrupted sync downloaded is kept.
+        assert!(staging.join("crates.io-index/.git/objects/ab").exists());
+        assert!(staging.join("dist/cargo.tar.xz").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn generations_next_to_target() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(tmp.path()).unwrap();
+        let generations = dir.join("generations");
+        fs::create_dir_all(generations.join("mirror.1")).unwrap();
+        fs::create_dir_all(dir.join("serve")).unwrap();
+        let path = dir.join("serve/current");
+        std::os::unix::fs::symlink(generations.join("mirror.1"), &path).unwrap();
+
+        let staging = staging_path(&path).unwrap();
+        assert_eq!(staging, generations.join("current.staging"));
+        prepare(&path, &staging).unwrap();
+        promote(&path, &staging).unwrap();
+        let generation = fs::canonicalize(&path).unwrap();
+        assert_eq!(generation.parent(), Some(generations.as_path()));
+        assert!(is_generation(&path, &generation));
+        assert!(!is_generation(&path, &generations.join("mirror.1")));
+        assert!(!is_generation(&path, &generations.join("current.staging")));
+    }
+}
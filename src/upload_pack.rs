@@ -0,0 +1,362 @@
+//! A read-only implementation of git's smart HTTP protocol (version 0), serving fetches and
+//! clones of the index with libgit2 instead of `git http-backend`.
+//!
+//! See: https://git-scm.com/docs/http-protocol and https://git-scm.com/docs/pack-protocol
+
+use git2::{ObjectType, Oid, Repository};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum UploadPackError {
+    #[error("Git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error("Bad upload-pack request: {0}")]
+    BadRequest(String),
+}
+
+/// Capabilities sent along with the first advertised ref.
+fn capabilities() -> String {
+    format!(
+        "multi_ack multi_ack_detailed symref=HEAD:refs/heads/master agent=panamax/{}",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Encode data as a pkt-line.
+fn pkt_line(data: &[u8]) -> Vec<u8> {
+    let mut line = format!("{:04x}", data.len() + 4).into_bytes();
+    line.extend_from_slice(data);
+    line
+}
+
+const FLUSH_PKT: &[u8] = b"0000";
+
+/// The refs advertised to clients: HEAD, branches and tags.
+fn advertised_refs(repo: &Repository) -> Result<Vec<(Oid, String)>, UploadPackError> {
+    let mut refs = Vec::new();
+    if let Ok(head) = repo.head().and_then(|h| h.peel_to_commit()) {
+        refs.push((head.id(), "HEAD".to_string()));
+    }
+    for reference in repo.references()? {
+        let reference = reference?;
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        if !(name.starts_with("refs/heads/") || name.starts_with("refs/tags/")) {
+            continue;
+        }
+        let Some(target) = reference.target() else {
+            continue;
+        };
+        refs.push((target, name.to_string()));
+        // Annotated tags are followed by the object they point to.
+        if let Ok(tag) = repo.find_tag(target) {
+            refs.push((tag.target_id(), format!("{name}^{{}}")));
+        }
+    }
+    Ok(refs)
+}
+
+/// Body of a `GET info/refs?service=git-upload-pack` response, listing HEAD, branches and tags.
+pub fn advertise_refs(repo: &Repository) -> Result<Vec<u8>, UploadPackError> {
+    let refs = advertised_refs(repo)?;
+
+    let mut body = pkt_line(b"# service=git-upload-pack\n");
+    body.extend_from_slice(FLUSH_PKT);
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}\n", Oid::zero(), capabilities());
+        body.extend(pkt_line(line.as_bytes()));
+    }
+    for (i, (oid, name)) in refs.iter().enumerate() {
+        let line = if i == 0 {
+            format!("{oid} {name}\0{}\n", capabilities())
+        } else {
+            format!("{oid} {name}\n")
+        };
+        body.extend(pkt_line(line.as_bytes()));
+    }
+    body.extend_from_slice(FLUSH_PKT);
+    Ok(body)
+}
+
+/// Split a request body into pkt-lines. Flush packets are returned as `None`.
+fn read_pkt_lines(mut data: &[u8]) -> Result<Vec<Option<&[u8]>>, UploadPackError> {
+    let mut lines = Vec::new();
+    while !data.is_empty() {
+        let len = data
+            .get(..4)
+            .and_then(|len| std::str::from_utf8(len).ok())
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .ok_or_else(|| UploadPackError::BadRequest("invalid pkt-line length".to_string()))?;
+        match len {
+            0 => lines.push(None),
+            1..=3 => {
+                return Err(UploadPackError::BadRequest(format!(
+                    "invalid pkt-line length {len}"
+                )))
+            }
+            _ => {
+                let line = data
+                    .get(4..len)
+                    .ok_or_else(|| UploadPackError::BadRequest("truncated pkt-line".to_string()))?;
+                lines.push(Some(line));
+            }
+        }
+        data = &data[len.max(4)..];
+    }
+    Ok(lines)
+}
+
+fn parse_oid(line: &str, prefix: &str) -> Result<Oid, UploadPackError> {
+    let hex = line[prefix.len()..].split(' ').next().unwrap_or_default();
+    Oid::from_str(hex.trim_end())
+        .map_err(|_| UploadPackError::BadRequest(format!("invalid object id in \"{line}\"")))
+}
+
+/// How the client wants common objects to be acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MultiAck {
+    None,
+    Basic,
+    Detailed,
+}
+
+/// Check if the client has enough common objects for an efficient pack:
+/// every wanted commit must descend from one of them.
+fn ok_to_give_up(repo: &Repository, wants: &[Oid], common: &[Oid]) -> bool {
+    wants.iter().all(|want| {
+        common
+            .iter()
+            .any(|c| c == want || repo.graph_descendant_of(*want, *c).unwrap_or(false))
+    })
+}
+
+/// Answer a `POST git-upload-pack` request, writing the response through `out`.
+///
+/// Negotiation follows `git upload-pack --stateless-rpc`: common objects are acknowledged
+/// as the client sends them, and once the client is done, the objects it wants but doesn't
+/// have are sent as a pack. `out` can return false to stop early.
+pub fn upload_pack(
+    repo: &Repository,
+    request: &[u8],
+    out: &mut dyn FnMut(&[u8]) -> bool,
+) -> Result<(), UploadPackError> {
+    let mut lines = read_pkt_lines(request)?.into_iter();
+    // Like `git upload-pack` by default, only the tips of advertised refs can be fetched.
+    let tips: Vec<Oid> = advertised_refs(repo)?
+        .into_iter()
+        .map(|(oid, _)| oid)
+        .collect();
+
+    // The wants come first, up to a flush packet. The first one lists the client's capabilities.
+    let mut wants = Vec::new();
+    let mut multi_ack = MultiAck::None;
+    for line in lines.by_ref() {
+        let Some(line) = line else {
+            break;
+        };
+        let line = String::from_utf8_lossy(line);
+        if !line.starts_with("want ") {
+            return Err(UploadPackError::BadRequest(format!(
+                "unsupported request \"{}\"",
+                line.trim_end()
+            )));
+        }
+        if wants.is_empty() {
+            let caps: Vec<&str> = line.split_whitespace().skip(2).collect();
+            if caps.contains(&"multi_ack_detailed") {
+                multi_ack = MultiAck::Detailed;
+            } else if caps.contains(&"multi_ack") {
+                multi_ack = MultiAck::Basic;
+            }
+        }
+        let oid = parse_oid(&line, "want ")?;
+        let object = tips
+            .contains(&oid)
+            .then(|| repo.find_object(oid, None).ok())
+            .flatten()
+            .ok_or_else(|| UploadPackError::BadRequest(format!("not our ref {oid}")))?;
+        wants.push(object);
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+    let want_commits = wants
+        .iter()
+        .map(|w| w.peel_to_commit().map(|c| c.id()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Then the haves. A flush ends this round of negotiation, and "done" asks for the pack.
+    let mut common: Vec<Oid> = Vec::new();
+    let mut got_common = false;
+    let mut got_other = false;
+    let mut done = false;
+    for line in lines {
+        let mut write = |line: String| out(&pkt_line(line.as_bytes()));
+        let Some(line) = line else {
+            // A flush ends the request: the client sends its state again in the next one.
+            if let Some(last) = common.last() {
+                if multi_ack == MultiAck::Detailed
+                    && got_common
+                    && !got_other
+                    && ok_to_give_up(repo, &want_commits, &common)
+                    && !write(format!("ACK {last} ready\n"))
+                {
+                    return Ok(());
+                }
+            }
+            if common.is_empty() || multi_ack != MultiAck::None {
+                write("NAK\n".to_string());
+            }
+            return Ok(());
+        };
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with("have ") {
+            let oid = parse_oid(&line, "have ")?;
+            if repo.find_commit(oid).is_err() {
+                got_other = true;
+                continue;
+            }
+            got_common = true;
+            common.push(oid);
+            let ack = match multi_ack {
+                MultiAck::Detailed => Some(format!("ACK {oid} common\n")),
+                MultiAck::Basic => Some(format!("ACK {oid} continue\n")),
+                MultiAck::None if common.len() == 1 => Some(format!("ACK {oid}\n")),
+                MultiAck::None => None,
+            };
+            if ack.is_some_and(|ack| !write(ack)) {
+                return Ok(());
+            }
+        } else if line.trim_end() == "done" {
+            let last = match common.last() {
+                Some(last) if multi_ack != MultiAck::None => format!("ACK {last}\n"),
+                Some(_) => String::new(),
+                None => "NAK\n".to_string(),
+            };
+            if !last.is_empty() && !write(last) {
+                return Ok(());
+            }
+            done = true;
+            break;
+        }
+    }
+    if !done {
+        return Ok(());
+    }
+
+    let mut builder = repo.packbuilder()?;
+    let mut walk = repo.revwalk()?;
+    for (object, commit) in wants.iter().zip(&want_commits) {
+        walk.push(*commit)?;
+        // Annotated tags aren't part of the commit walk.
+        if object.kind() == Some(ObjectType::Tag) {
+            builder.insert_object(object.id(), None)?;
+        }
+    }
+    for oid in &common {
+        walk.hide(*oid)?;
+    }
+    builder.insert_walk(&mut walk)?;
+    builder.foreach(|chunk| out(chunk))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{advertise_refs, pkt_line, read_pkt_lines, upload_pack, UploadPackError};
+    use git2::{Repository, Signature};
+
+    #[test]
+    fn pkt_lines() {
+        assert_eq!(pkt_line(b"done\n"), b"0009done\n");
+        let lines = read_pkt_lines(b"0009done\n00000008NAK\n").unwrap();
+        assert_eq!(lines, [Some(&b"done\n"[..]), None, Some(&b"NAK\n"[..])]);
+        assert!(matches!(
+            read_pkt_lines(b"0009don"),
+            Err(UploadPackError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn negotiation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let repo = Repository::init(path).unwrap();
+        let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let first = repo
+            .commit(Some("HEAD"), &signature, &signature, "first", &tree, &[])
+            .unwrap();
+        let parent = repo.find_commit(first).unwrap();
+        let second = repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "second",
+                &tree,
+                &[&parent],
+            )
+            .unwrap();
+
+        let refs = String::from_utf8(advertise_refs(&repo).unwrap()).unwrap();
+        assert!(refs.starts_with("001e# service=git-upload-pack\n0000"));
+        assert!(refs.contains(&format!("{second} HEAD\0")));
+        assert!(refs.ends_with(&format!("{second} refs/heads/master\n0000")));
+
+        let request = |caps: &str, lines: &[String]| {
+            let mut body = pkt_line(format!("want {second}{caps}\n").as_bytes());
+            body.extend_from_slice(b"0000");
+            for line in lines {
+                // An empty line stands for a flush packet.
+                match line.as_str() {
+                    "" => body.extend_from_slice(b"0000"),
+                    line => body.extend(pkt_line(line.as_bytes())),
+                }
+            }
+            let mut out = Vec::new();
+            upload_pack(&repo, &body, &mut |chunk| {
+                out.extend_from_slice(chunk);
+                true
+            })
+            .unwrap();
+            String::from_utf8_lossy(&out).into_owned()
+        };
+        let have = format!("have {first}\n");
+        let done = "done\n".to_string();
+
+        // A fresh clone gets a NAK and the pack.
+        let out = request("", std::slice::from_ref(&done));
+        assert!(out.starts_with("0008NAK\nPACK"));
+
+        // Without multi_ack, the first common commit is acknowledged, and the pack follows once done.
+        let out = request("", &[have.clone(), String::new()]);
+        assert_eq!(out, format!("0031ACK {first}\n"));
+        let out = request("", &[have.clone(), done.clone()]);
+        assert!(out.starts_with(&format!("0031ACK {first}\nPACK")));
+
+        // With multi_ack_detailed, the client is told when it has enough common commits.
+        let caps = " multi_ack_detailed";
+        let out = request(caps, &[have.clone(), String::new()]);
+        assert_eq!(
+            out,
+            format!("0038ACK {first} common\n0037ACK {first} ready\n0008NAK\n")
+        );
+        let out = request(caps, &[have.clone(), done.clone()]);
+        assert!(out.starts_with(&format!("0038ACK {first} common\n0031ACK {first}\nPACK")));
+
+        // Only the tips of advertised refs can be fetched.
+        let body = [
+            pkt_line(format!("want {first}\n").as_bytes()),
+            b"0000".to_vec(),
+        ]
+        .concat();
+        assert!(matches!(
+            upload_pack(&repo, &body, &mut |_| true),
+            Err(UploadPackError::BadRequest(_))
+        ));
+    }
+}
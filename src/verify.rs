@@ -1,28 +1,31 @@
 use std::{
     cmp::Ordering,
     convert::Infallible,
+    fs,
     io::{BufRead, Cursor, Write},
     ops::RangeInclusive,
     path::{Path, PathBuf},
     str::FromStr,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use console::style;
-use futures::StreamExt;
 use git2::Repository;
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
-use reqwest::Client;
+use indicatif::{ProgressFinish, ProgressStyle};
 use warp::http::HeaderValue;
 
 use crate::{
+    crate_store::crate_store,
     crates::{
-        cargo_lock_to_mirror_entries, get_crate_path, sync_one_crate_entry,
-        vendor_path_to_mirror_entries, CrateEntry,
+        cargo_lock_to_mirror_entries, crate_checksum_matches, download_crate_entries,
+        find_crate_file, is_crate_index_path, is_index_path_of, vendor_path_to_mirror_entries,
+        CrateEntry, VersionFloor,
     },
-    download::DownloadError,
-    mirror::{default_user_agent, ConfigCrates, ConfigMirror, MirrorError},
-    progress_bar::padded_prefix_message,
+    credentials::Credentials,
+    download::{sha256_file, DownloadError, DownloadOptions, DownloadStatus},
+    mirror::{user_agent, ConfigCrates, ConfigMirror, MirrorError},
+    progress_bar::{padded_prefix_message, progress_bar, progress_spinner, EventSink, PhaseEvents},
 };
 
 ///
@@ -54,6 +57,11 @@ static CRATES_403: [(&str, &str); 23] = [
     ("gobject-2-0-sys", "0.2.0"),
 ];
 
+/// Whether a crate version is known to be forbidden by crates.io, so it can't be downloaded.
+pub fn is_known_403(name: &str, version: &str) -> bool {
+    CRATES_403.iter().any(|(n, v)| *n == name && *v == version)
+}
+
 /// Type used to represent user's input which will be used to indexed a `Vec`
 #[derive(Debug, PartialEq, Eq)]
 enum Input {
@@ -142,13 +150,23 @@ impl FromStr for Input {
     }
 }
 
+/// Look for crates missing from the mirror, or mismatching their checksum.
+///
+/// Only the crates found missing or mismatched are reported to `events`.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn verify_mirror(
     path: std::path::PathBuf,
     current_step: &mut usize,
     steps: usize,
+    checksum: bool,
+    refresh_older_than: Option<Duration>,
     vendor_path: Option<PathBuf>,
-    cargo_lock_filepath: Option<PathBuf>,
+    cargo_lock_paths: Vec<PathBuf>,
+    floor: &VersionFloor,
+    events: Option<&EventSink>,
 ) -> Result<Option<Vec<CrateEntry>>, MirrorError> {
+    let refresh_cutoff = refresh_older_than.and_then(|age| SystemTime::now().checked_sub(age));
+
     // Checking existence of local index
     let repo_path = path.join("crates.io-index");
 
@@ -162,7 +180,7 @@ pub(crate) async fn verify_mirror(
         "Comparing local crates.io and mirror coherence",
     );
 
-    let pb = ProgressBar::new_spinner()
+    let pb = progress_spinner()
         .with_style(
             ProgressStyle::default_bar()
                 .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
@@ -172,6 +190,7 @@ pub(crate) async fn verify_mirror(
         .with_prefix(prefix)
         .with_finish(ProgressFinish::AndLeave);
     pb.enable_steady_tick(Duration::from_millis(10));
+    let events = PhaseEvents::start(events, "verify", None);
 
     // Getting diff tree from local crates.io repository.
     let repo = Repository::open(repo_path)?;
@@ -181,20 +200,17 @@ pub(crate) async fn verify_mirror(
 
     let mut missing_crates = Vec::new();
 
-    let is_crate_whitelist_only = vendor_path.is_some() || cargo_lock_filepath.is_some();
+    let is_crate_whitelist_only = vendor_path.is_some() || !cargo_lock_paths.is_empty();
     // if a vendor_path, parse the filepath for Cargo.toml files for each crate, filling vendors
     let mut mirror_entries = vec![];
     vendor_path_to_mirror_entries(&mut mirror_entries, vendor_path.as_ref());
-    cargo_lock_to_mirror_entries(&mut mirror_entries, cargo_lock_filepath.as_ref());
+    cargo_lock_to_mirror_entries(&mut mirror_entries, &cargo_lock_paths);
 
     diff.foreach(
         &mut |delta, _| {
             let df = delta.new_file();
             let p = df.path().unwrap();
-            if p == Path::new("config.json") {
-                return true;
-            }
-            if p.starts_with(".github/") {
+            if !is_crate_index_path(p) {
                 return true;
             }
 
@@ -215,6 +231,11 @@ pub(crate) async fn verify_mirror(
                     }
                 };
 
+                // Sync skips entries that are in the wrong index file, so they're never missing.
+                if !is_index_path_of(p, crate_entry.get_name()) {
+                    continue;
+                }
+
                 // Checking only whitelisted crates if supplied
                 if is_crate_whitelist_only
                     && !mirror_entries.iter().any(|it| {
@@ -225,17 +246,42 @@ pub(crate) async fn verify_mirror(
                     continue;
                 }
 
-                // Building crates local path.
+                // Versions below the configured floor are never synced.
+                if !is_crate_whitelist_only
+                    && floor.excludes(crate_entry.get_name(), crate_entry.get_vers())
+                {
+                    continue;
+                }
+
+                // Looking for the crate locally.
                 let file_path =
-                    get_crate_path(&path, crate_entry.get_name(), crate_entry.get_vers()).unwrap();
+                    find_crate_file(&path, crate_entry.get_name(), crate_entry.get_vers());
 
-                // Checking if crate is missing.
-                if !CRATES_403
-                    .iter()
-                    .any(|it| it.0 == crate_entry.get_name() && it.1 == crate_entry.get_vers())
-                    && !file_path.exists()
-                {
-                    missing_crates.push(crate_entry);
+                if is_known_403(crate_entry.get_name(), crate_entry.get_vers()) {
+                    continue;
+                }
+
+                // Checking if crate is missing, or doesn't match its checksum.
+                let item = format!("{}@{}", crate_entry.get_name(), crate_entry.get_vers());
+                match (file_path, crate_entry.get_cksum()) {
+                    (None, _) => {
+                        events.failed(&item, &"missing");
+                        missing_crates.push(crate_entry);
+                    }
+                    (Some(file_path), Some(cksum))
+                        if is_mismatched(&file_path, cksum, checksum, refresh_cutoff) =>
+                    {
+                        pb.suspend(|| {
+                            eprintln!(
+                                "Checksum mismatch: {} - version {}",
+                                crate_entry.get_name(),
+                                crate_entry.get_vers()
+                            )
+                        });
+                        events.failed(&item, &"checksum mismatch");
+                        missing_crates.push(crate_entry);
+                    }
+                    _ => {}
                 }
             }
 
@@ -258,6 +304,42 @@ pub(crate) async fn verify_mirror(
     Ok(None)
 }
 
+/// Check whether a crate file doesn't match its checksum from the index.
+///
+/// Files last modified before `refresh_cutoff` are always re-hashed, as the crate may have
+/// been corrected upstream since. Those that still match are touched, so they aren't
+/// re-hashed again until they are old again. Other files are only checked if `checksum` is set.
+fn is_mismatched(
+    file_path: &Path,
+    cksum: &str,
+    checksum: bool,
+    refresh_cutoff: Option<SystemTime>,
+) -> bool {
+    let is_old = refresh_cutoff.is_some_and(|cutoff| {
+        fs::metadata(file_path)
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified < cutoff)
+    });
+
+    if is_old {
+        match sha256_file(file_path) {
+            Ok(hash) if hash == cksum => {
+                // Failing to record the check only means the file is re-hashed next time.
+                let _ = fs::File::options()
+                    .write(true)
+                    .open(file_path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                false
+            }
+            _ => true,
+        }
+    } else if checksum {
+        !crate_checksum_matches(file_path, cksum).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
 /// This method is giving choice to users whether to filter some crates or not before downloading.
 pub(crate) async fn handle_user_input(
     mut missing_crates: Vec<CrateEntry>,
@@ -318,10 +400,11 @@ pub(crate) async fn fix_mirror(
     crates_to_fetch: Vec<CrateEntry>,
     current_step: &mut usize,
     steps: usize,
+    events: Option<&EventSink>,
 ) -> Result<(), MirrorError> {
     let prefix = padded_prefix_message(*current_step, steps, "Repairing mirror");
 
-    let pb = ProgressBar::new(crates_to_fetch.len() as u64)
+    let pb = progress_bar(crates_to_fetch.len() as u64)
         .with_style(
             ProgressStyle::default_bar()
                 .template(
@@ -334,25 +417,10 @@ pub(crate) async fn fix_mirror(
         .with_finish(ProgressFinish::AndLeave);
     pb.enable_steady_tick(Duration::from_millis(10));
 
-    // Getting crates' source from config
-    let crates_source = if crates_config.source != "https://crates.io/api/v1/crates" {
-        Some(crates_config.source.as_str())
-    } else {
-        None
-    };
+    let store = crate_store(&path, Some(crates_config))?;
 
     // Handle the contact information
-    let user_agent_str =
-        mirror_config
-            .contact
-            .as_ref()
-            .map_or_else(default_user_agent, |contact| {
-                if contact != "your@email.com" {
-                    format!("Panamax/{} ({})", env!("CARGO_PKG_VERSION"), contact)
-                } else {
-                    default_user_agent()
-                }
-            });
+    let user_agent_str = user_agent(mirror_config.contact.as_ref());
 
     // Set the user agent with contact information.
     let user_agent = match HeaderValue::from_str(&user_agent_str) {
@@ -365,55 +433,56 @@ pub(crate) async fn fix_mirror(
         }
     };
 
-    let client = Client::new();
-
-    // This code is copied from `crates::sync_crates_files` and could be mutualised in a future commit.
-    // For example in a function within module crates (e.g. `crates::build_and_run_tasks`)
-    let tasks = futures::stream::iter(crates_to_fetch.into_iter())
-        .map(|c| {
-            // Duplicate variables used in the async closure.
-            let client = client.clone();
-            let path = path.clone();
-            let mirror_retries = mirror_config.retries;
-            let crates_source = crates_source.map(|s| s.to_string());
-            let user_agent = user_agent.to_owned();
-            let pb = pb.clone();
-
-            tokio::spawn(async move {
-                let out = sync_one_crate_entry(
-                    &client,
-                    &path,
-                    crates_source.as_deref(),
-                    mirror_retries,
-                    &c,
-                    &user_agent,
-                )
-                .await;
-
-                pb.inc(1);
-
-                out
-            })
-        })
-        .buffer_unordered(crates_config.download_threads)
-        .collect::<Vec<_>>()
-        .await;
+    let options = DownloadOptions {
+        events: events.cloned(),
+        credentials: Some(Arc::new(Credentials::from_config(&path, crates_config)?)),
+        ..DownloadOptions::try_from(mirror_config)?
+    };
+    let events = PhaseEvents::start(events, "repair", Some(crates_to_fetch.len()));
 
-    for t in tasks {
-        let res = t.unwrap();
+    let on_done = {
+        let pb = pb.clone();
+        move |_: &CrateEntry, _: &Result<DownloadStatus, DownloadError>| pb.inc(1)
+    };
+    let tasks = download_crate_entries(
+        crates_to_fetch,
+        store,
+        crates_config,
+        &options,
+        &user_agent,
+        on_done,
+    )
+    .await;
+
+    let mut failed = 0;
+    for (c, res) in tasks {
+        let item = format!("{}@{}", c.get_name(), c.get_vers());
+        match &res {
+            Ok(_) => events.completed(&item),
+            Err(e) => events.failed(&item, e),
+        }
         match res {
-            Ok(())
+            Ok(_)
             | Err(DownloadError::NotFound {
                 status: _,
                 url: _,
                 data: _,
-            })
-            | Err(DownloadError::MismatchedHash {
-                expected: _,
-                actual: _,
             }) => {}
 
+            // The hash still didn't match after retrying, which usually means
+            // the source is serving a bad file. Report it rather than skipping it.
+            Err(DownloadError::MismatchedHash { expected, actual }) => {
+                failed += 1;
+                eprintln!(
+                    "Crate {}@{} has a mismatched hash after {} retries - expected '{expected}', got '{actual}'",
+                    c.get_name(),
+                    c.get_vers(),
+                    options.retries
+                );
+            }
+
             Err(e) => {
+                failed += 1;
                 eprintln!("Downloading failed: {e:?}");
             }
         }
@@ -421,11 +490,115 @@ pub(crate) async fn fix_mirror(
 
     pb.finish_and_clear();
     *current_step += 1;
+    if failed > 0 {
+        return Err(MirrorError::Incomplete {
+            count: failed,
+            what: "crates",
+        });
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use super::{is_mismatched, verify_mirror};
+    use crate::crates::{get_crate_path, prune_crates_files, CrateEntry, VersionFloor};
+    use crate::crates_index::update_crates_config;
+    use crate::download::sha256_file;
+    use git2::{Repository, Signature};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    async fn missing_crates(path: &Path) -> Option<Vec<CrateEntry>> {
+        let floor = VersionFloor::default();
+        verify_mirror(
+            path.to_path_buf(),
+            &mut 0,
+            1,
+            false,
+            None,
+            None,
+            vec![],
+            &floor,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pruned_crates_are_not_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let index_path = path.join("crates.io-index");
+        std::fs::create_dir_all(index_path.join("se/rd")).unwrap();
+        std::fs::write(
+            index_path.join("se/rd/serde"),
+            "{\"name\":\"serde\",\"vers\":\"1.0.0\"}\n{\"name\":\"serde\",\"vers\":\"1.0.1\"}\n",
+        )
+        .unwrap();
+        let repo = Repository::init(&index_path).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("se/rd/serde")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+        repo.commit(
+            Some("refs/heads/master"),
+            &signature,
+            &signature,
+            "index",
+            &tree,
+            &[],
+        )
+        .unwrap();
+        repo.set_head("refs/heads/master").unwrap();
+        for version in ["1.0.0", "1.0.1"] {
+            let crate_path = get_crate_path(path, "serde", version).unwrap();
+            std::fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+            std::fs::write(crate_path, [0u8; 10]).unwrap();
+        }
+        let crates =
+            toml_edit::easy::from_str::<crate::mirror::Config>(include_str!("mirror.default.toml"))
+                .unwrap()
+                .crates
+                .unwrap();
+
+        assert_eq!(
+            prune_crates_files(path, 15, None, None, &[], String::new()).unwrap(),
+            10
+        );
+        let missing = missing_crates(path).await.unwrap();
+        assert_eq!(missing[0].get_vers(), "1.0.0");
+
+        // Syncing the config removes the pruned version from the index, so it isn't missing.
+        update_crates_config(path, &crates).unwrap();
+        assert!(missing_crates(path).await.is_none());
+    }
+
+    #[test]
+    fn refreshed_crates_are_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("serde-1.0.0.crate");
+        std::fs::write(&path, "serde").unwrap();
+        let cksum = sha256_file(&path).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let modified = |path: &Path| std::fs::metadata(path).unwrap().modified().unwrap();
+        let set_modified = |time| {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(time).unwrap();
+        };
+
+        set_modified(SystemTime::now() - 10 * day);
+        let cutoff = SystemTime::now() - day;
+        assert!(!is_mismatched(&path, &cksum, false, Some(cutoff)));
+        assert!(modified(&path) > cutoff);
+
+        // A mismatched file is left as it is, to be re-downloaded.
+        set_modified(SystemTime::now() - 10 * day);
+        assert!(is_mismatched(&path, "0000", false, Some(cutoff)));
+        assert!(modified(&path) < cutoff);
+    }
 
     mod input {
         use crate::verify::Input;